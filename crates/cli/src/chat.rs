@@ -0,0 +1,173 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{ io::{ self, Read }, path::PathBuf };
+use clap::Args;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::{ load_config, load_providers, Config, Provider };
+
+// Provider types with an OpenAI compatible chat completions API
+const CHAT_PROVIDER_TYPES: [&str; 3] = ["opla", "openai", "server"];
+
+#[derive(Args)]
+pub struct ChatArgs {
+    /// Provider name or id, defaults to the active one
+    #[arg(long)]
+    provider: Option<String>,
+    /// Model name, defaults to the active one
+    #[arg(long)]
+    model: Option<String>,
+    /// System message
+    #[arg(long)]
+    system: Option<String>,
+    /// Message to send, read from stdin if not given
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatMessage {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatChoice {
+    message: ChatMessage,
+}
+
+#[derive(Deserialize)]
+struct ChatUsage {
+    prompt_tokens: Option<i32>,
+    completion_tokens: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+    usage: Option<ChatUsage>,
+}
+
+fn find_provider(
+    providers: Vec<Provider>,
+    config: &Config,
+    provider: &Option<String>
+) -> Result<Provider, String> {
+    let active_provider = config.services.active_service
+        .as_ref()
+        .and_then(|s| s.provider_id_or_name.clone());
+    match provider.as_ref().or(active_provider.as_ref()) {
+        Some(id_or_name) =>
+            providers
+                .into_iter()
+                .find(|p| p.id == *id_or_name || p.name.eq_ignore_ascii_case(id_or_name))
+                .ok_or(format!("Provider not found: {}", id_or_name)),
+        None =>
+            providers
+                .into_iter()
+                .find(|p| !p.disabled.unwrap_or(false))
+                .ok_or("No provider configured".to_string()),
+    }
+}
+
+fn find_model(provider: &Provider, config: &Config, model: &Option<String>) -> Option<String> {
+    if model.is_some() {
+        return model.clone();
+    }
+    let active_model = config.services.active_service.as_ref().and_then(|s| s.model_id.clone());
+    if provider.r#type == "opla" {
+        let model_id = active_model.or(
+            config.server.parameters
+                .get("model_id")
+                .and_then(|m| m.as_str())
+                .map(|m| m.to_string())
+        )?;
+        return config.models.items
+            .iter()
+            .find(|m| m.is_same_id_or_name(&model_id))
+            .map(|m| m.name.clone())
+            .or(Some(model_id));
+    }
+    active_model
+}
+
+pub fn run(args: ChatArgs, config_dir: &Option<PathBuf>) -> Result<(), String> {
+    let config = load_config(config_dir)?;
+    let providers = load_providers(config_dir)?;
+    let provider = find_provider(providers, &config, &args.provider)?;
+    if !CHAT_PROVIDER_TYPES.contains(&provider.r#type.as_str()) {
+        return Err(
+            format!(
+                "Chat is not supported for provider {} of type {}, only {} are supported",
+                provider.name,
+                provider.r#type,
+                CHAT_PROVIDER_TYPES.join(", ")
+            )
+        );
+    }
+    let model = find_model(&provider, &config, &args.model).ok_or(
+        format!("No model set for provider {}, use --model", provider.name)
+    )?;
+
+    let content = match args.message {
+        Some(message) => message,
+        None => {
+            let mut message = String::new();
+            io::stdin()
+                .read_to_string(&mut message)
+                .map_err(|err| err.to_string())?;
+            message
+        }
+    };
+
+    let mut messages = Vec::new();
+    if let Some(system) = args.system {
+        messages.push(json!({ "role": "system", "content": system }));
+    }
+    messages.push(json!({ "role": "user", "content": content }));
+
+    let url = format!("{}/chat/completions", provider.api_url());
+    let client = reqwest::blocking::Client::new();
+    let mut request = client.post(&url).json(
+        &json!({
+            "model": model,
+            "messages": messages,
+            "stream": false,
+        })
+    );
+    if let Some(key) = &provider.key {
+        request = request.bearer_auth(key);
+    }
+    let response = request.send().map_err(|err| format!("Chat request error {}: {}", url, err))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().unwrap_or_default();
+        return Err(format!("Chat request error {}: {} {}", url, status, body));
+    }
+    let response: ChatResponse = response.json().map_err(|err| err.to_string())?;
+    let content = response.choices
+        .into_iter()
+        .next()
+        .and_then(|c| c.message.content)
+        .unwrap_or_default();
+    println!("{}", content);
+    if let Some(usage) = response.usage {
+        eprintln!(
+            "prompt_tokens={} completion_tokens={}",
+            usage.prompt_tokens.unwrap_or(0),
+            usage.completion_tokens.unwrap_or(0)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{ collections::HashMap, fs, path::PathBuf };
+use serde::Deserialize;
+
+// Read-only view of the configuration of the desktop app.
+// See webapp/native/src/store for the full structures.
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ModelEntry {
+    pub id: Option<String>,
+    pub name: String,
+    pub title: Option<String>,
+    pub state: Option<String>,
+    pub path: Option<String>,
+    pub file_name: Option<String>,
+    pub context_window: Option<i32>,
+    pub quantization: Option<String>,
+}
+
+impl ModelEntry {
+    pub fn is_same_id_or_name(&self, id_or_name: &str) -> bool {
+        self.id.as_deref() == Some(id_or_name) || self.name == id_or_name
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ModelsConfig {
+    #[serde(default)]
+    pub items: Vec<ModelEntry>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub parameters: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ActiveService {
+    #[serde(alias = "modelId", default)]
+    pub model_id: Option<String>,
+    #[serde(alias = "providerIdOrName", default)]
+    pub provider_id_or_name: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ServicesConfig {
+    #[serde(default)]
+    pub active_service: Option<ActiveService>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub models: ModelsConfig,
+    #[serde(default)]
+    pub services: ServicesConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Provider {
+    pub id: String,
+    pub name: String,
+    pub r#type: String,
+    pub url: String,
+    pub key: Option<String>,
+    pub disabled: Option<bool>,
+}
+
+impl Provider {
+    // Base url of the OpenAI compatible API
+    pub fn api_url(&self) -> String {
+        let url = self.url.trim_end_matches('/');
+        let url = if url.starts_with("http://") || url.starts_with("https://") {
+            url.to_string()
+        } else {
+            format!("http://{}", url)
+        };
+        if self.r#type == "opla" && !url.ends_with("/v1") {
+            return format!("{}/v1", url);
+        }
+        url
+    }
+}
+
+pub fn get_config_directory(config_dir: &Option<PathBuf>) -> Result<PathBuf, String> {
+    match config_dir {
+        Some(dir) => Ok(dir.clone()),
+        None => {
+            let config_dir = dirs::config_dir().ok_or("Failed to get conf directory")?;
+            Ok(config_dir.join("Opla"))
+        }
+    }
+}
+
+pub fn load_config(config_dir: &Option<PathBuf>) -> Result<Config, String> {
+    let config_path = get_config_directory(config_dir)?.join("config.json");
+    let data = fs::read_to_string(&config_path).map_err(|err| format!("Can't read {:?}: {}", config_path, err))?;
+    serde_json::from_str(&data).map_err(|err| format!("Can't parse {:?}: {}", config_path, err))
+}
+
+pub fn load_providers(config_dir: &Option<PathBuf>) -> Result<Vec<Provider>, String> {
+    let providers_path = get_config_directory(config_dir)?.join("providers.json");
+    let data = fs::read_to_string(&providers_path).map_err(|err| format!("Can't read {:?}: {}", providers_path, err))?;
+    serde_json::from_str(&data).map_err(|err| format!("Can't parse {:?}: {}", providers_path, err))
+}
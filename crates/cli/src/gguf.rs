@@ -0,0 +1,147 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+use clap::Subcommand;
+use opla_core::gguf::{ GGUFReadOptions, GGUF };
+
+#[derive(Subcommand)]
+pub enum GGUFCommand {
+    /// Show the header and the metadata of a GGUF file
+    Inspect {
+        file: String,
+        /// Output the whole file description as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the tensors of a GGUF file
+    Tensors {
+        file: String,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check that a GGUF file is complete and consistent
+    Validate {
+        file: String,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the metadata and tensors differences between two GGUF files
+    Diff {
+        a: String,
+        b: String,
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn read_gguf(file: &str) -> Result<GGUF, String> {
+    let mut gguf = GGUF::new(file);
+    let options = GGUFReadOptions { use_mmap: true, ..GGUFReadOptions::default() };
+    gguf.read_with_options(file, &options, &mut ()).map_err(|err| err.to_string())?;
+    Ok(gguf)
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<String, String> {
+    serde_json::to_string_pretty(value).map_err(|err| err.to_string())
+}
+
+fn inspect(file: &str, json: bool) -> Result<(), String> {
+    let gguf = read_gguf(file)?;
+    if json {
+        println!("{}", to_json(&gguf)?);
+        return Ok(());
+    }
+
+    println!("File: {}", gguf.file_name);
+    println!("Version: {} {:?}", gguf.header.version, gguf.header.byte_order);
+    println!("Tensor_count: {}", gguf.header.tensor_count);
+    println!("Metadata_kv_count: {}", gguf.header.metadata_kv_count);
+    println!("Alignment: {}", gguf.alignment);
+    println!("Data_offset: {}", gguf.data_offset);
+    println!("Data_size: {}", gguf.data_size());
+    if let Some(quantization) = gguf.quantization() {
+        println!("Quantization: {}", quantization);
+    }
+    println!();
+    for metadata in &gguf.header.metadata_kv {
+        println!("{}={}", metadata.key, metadata.value);
+    }
+    Ok(())
+}
+
+fn tensors(file: &str, json: bool) -> Result<(), String> {
+    let gguf = read_gguf(file)?;
+    if json {
+        println!("{}", to_json(&gguf.tensors)?);
+        return Ok(());
+    }
+
+    for tensor in &gguf.tensors {
+        let dimensions: Vec<String> = tensor.dimensions
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        println!(
+            "{:<48} {:<8} {:<24} offset={} size={}",
+            tensor.name,
+            tensor.tensor_type.to_string(),
+            format!("[{}]", dimensions.join(", ")),
+            tensor.offset,
+            tensor.size()
+        );
+    }
+    Ok(())
+}
+
+fn validate(file: &str, json: bool) -> Result<(), String> {
+    let gguf = read_gguf(file).map_err(|err| format!("{} is invalid: {}", file, err))?;
+    let file_size = fs::metadata(file).map_err(|err| err.to_string())?.len();
+    let issues = gguf.validate(file_size);
+    if json {
+        println!("{}", to_json(&issues)?);
+    } else {
+        for issue in &issues {
+            println!("{}", issue);
+        }
+    }
+    if !issues.is_empty() {
+        return Err(format!("{} is invalid: {} issue(s) found", file, issues.len()));
+    }
+    if !json {
+        println!("{} is valid", file);
+    }
+    Ok(())
+}
+
+fn diff(a: &str, b: &str, json: bool) -> Result<(), String> {
+    let differences = read_gguf(a)?.diff(&read_gguf(b)?);
+    if json {
+        println!("{}", to_json(&differences)?);
+        return Ok(());
+    }
+    for difference in &differences {
+        println!("{}", difference);
+    }
+    Ok(())
+}
+
+pub fn run(command: GGUFCommand) -> Result<(), String> {
+    match command {
+        GGUFCommand::Inspect { file, json } => inspect(&file, json),
+        GGUFCommand::Tensors { file, json } => tensors(&file, json),
+        GGUFCommand::Validate { file, json } => validate(&file, json),
+        GGUFCommand::Diff { a, b, json } => diff(&a, &b, json),
+    }
+}
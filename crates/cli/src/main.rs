@@ -12,16 +12,53 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::env;
+use std::{ path::PathBuf, process::ExitCode };
+use clap::{ Parser, Subcommand };
 
-use opla_core::gguf::GGUF;
+mod chat;
+mod config;
+mod gguf;
+mod models;
+mod tokenize;
 
-fn main() {
-    println!("Opla CLI WIP");
-    let args: Vec<_> = env::args().collect();
-    if args.len() > 1 {
-        println!("The first argument is {}", args[1]);
+#[derive(Parser)]
+#[command(name = "opla", version, about = "Opla command line interface")]
+struct Cli {
+    /// Opla configuration directory, defaults to the one of the desktop app
+    #[arg(long, global = true)]
+    config_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect GGUF model files
+    #[command(subcommand)]
+    Gguf(gguf::GGUFCommand),
+    /// Encode a text into tokens
+    Tokenize(tokenize::TokenizeArgs),
+    /// Manage the models of Opla
+    #[command(subcommand)]
+    Models(models::ModelsCommand),
+    /// Send a message to a provider
+    Chat(chat::ChatArgs),
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Gguf(command) => gguf::run(command),
+        Command::Tokenize(args) => tokenize::run(args),
+        Command::Models(command) => models::run(command, &cli.config_dir),
+        Command::Chat(args) => chat::run(args, &cli.config_dir),
+    };
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::FAILURE
+        }
     }
-    let mut gguf = GGUF::new(&args[1]);
-    gguf.read(&args[1]).unwrap();
 }
@@ -0,0 +1,74 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::PathBuf;
+use clap::Subcommand;
+use serde_json::json;
+
+use crate::config::load_config;
+
+#[derive(Subcommand)]
+pub enum ModelsCommand {
+    /// List the models installed in Opla
+    List {
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn list(config_dir: &Option<PathBuf>, json: bool) -> Result<(), String> {
+    let config = load_config(config_dir)?;
+    if json {
+        let models: Vec<_> = config.models.items
+            .iter()
+            .map(|m| {
+                json!({
+                    "id": m.id,
+                    "name": m.name,
+                    "title": m.title,
+                    "state": m.state,
+                    "path": m.path,
+                    "file_name": m.file_name,
+                    "context_window": m.context_window,
+                    "quantization": m.quantization,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&models).map_err(|err| err.to_string())?);
+        return Ok(());
+    }
+
+    let active_model_id = config.services.active_service.and_then(|s| s.model_id);
+    for model in &config.models.items {
+        let active = match &active_model_id {
+            Some(id) if model.is_same_id_or_name(id) => "*",
+            _ => " ",
+        };
+        println!(
+            "{} {:<38} {:<40} {:<12} {}",
+            active,
+            model.id.clone().unwrap_or_default(),
+            model.name,
+            model.state.clone().unwrap_or_default(),
+            model.file_name.clone().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+pub fn run(command: ModelsCommand, config_dir: &Option<PathBuf>) -> Result<(), String> {
+    match command {
+        ModelsCommand::List { json } => list(config_dir, json),
+    }
+}
@@ -0,0 +1,54 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use clap::Args;
+
+#[derive(Args)]
+pub struct TokenizeArgs {
+    /// Model used to select the encoding, e.g. gpt-4o, or the path of a GGUF or tokenizer.json file
+    #[arg(long, default_value = "gpt-4")]
+    model: String,
+    /// Encoding to use instead of the one of the model, e.g. o200k_base
+    #[arg(long)]
+    encoding: Option<String>,
+    /// Only print the number of tokens
+    #[arg(long)]
+    count: bool,
+    /// Print each token with its byte range and text
+    #[arg(long, conflicts_with = "count")]
+    offsets: bool,
+    text: String,
+}
+
+pub fn run(args: TokenizeArgs) -> Result<(), String> {
+    let tokenizer = tokenizer::get_tokenizer(&args.model, args.encoding.as_deref())?;
+    if args.count {
+        println!("{}", tokenizer.count(&args.text));
+        return Ok(());
+    }
+    if args.offsets {
+        for span in tokenizer.encode_with_offsets(&args.text) {
+            let text = String::from_utf8_lossy(&args.text.as_bytes()[span.start..span.end]);
+            println!("{}\t{}..{}\t{:?}", span.token, span.start, span.end, text);
+        }
+        return Ok(());
+    }
+    let tokens = tokenizer.encode(&args.text);
+    let tokens: Vec<String> = tokens
+        .iter()
+        .map(|t| t.to_string())
+        .collect();
+    println!("[{}]", tokens.join(", "));
+    Ok(())
+}
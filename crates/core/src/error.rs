@@ -0,0 +1,126 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use thiserror::Error;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Offsets are absolute positions in the file, at the start of the item being parsed.
+// Key is the metadata key being parsed, if any.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("file not found: {0}")]
+    NotFound(String),
+
+    #[error("i/o error at offset {offset}: {source}")]
+    Io {
+        offset: u64,
+        source: std::io::Error,
+    },
+
+    #[error("not a valid GGUF file, magic is {0:?}")]
+    InvalidMagic([u8; 4]),
+
+    #[error("unsupported GGUF version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("invalid gguf metadata type 0x{value_type:x} at offset {offset}{}", key_context(.key))]
+    InvalidValueType {
+        value_type: u32,
+        offset: u64,
+        key: Option<String>,
+    },
+
+    #[error("invalid ggml type 0x{tensor_type:x} at offset {offset} for tensor {tensor}")]
+    InvalidTensorType {
+        tensor_type: u32,
+        offset: u64,
+        tensor: String,
+    },
+
+    #[error("invalid UTF-8 string at offset {offset}{}", key_context(.key))]
+    InvalidUtf8 {
+        offset: u64,
+        key: Option<String>,
+    },
+
+    #[error("{name} limit exceeded at offset {offset}: {value} > {max}{}", key_context(.key))]
+    LimitExceeded {
+        name: &'static str,
+        value: u64,
+        max: u64,
+        offset: u64,
+        key: Option<String>,
+    },
+
+    #[error(
+        "file truncated at offset {offset}: {needed} bytes needed but only {remaining} remaining{}",
+        key_context(.key)
+    )]
+    Truncated {
+        needed: u64,
+        remaining: u64,
+        offset: u64,
+        key: Option<String>,
+    },
+}
+
+fn key_context(key: &Option<String>) -> String {
+    match key {
+        Some(key) => format!(" (key {})", key),
+        None => String::new(),
+    }
+}
+
+impl Error {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Error::NotFound(_))
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            Error::Io { offset, .. } |
+            Error::InvalidValueType { offset, .. } |
+            Error::InvalidTensorType { offset, .. } |
+            Error::InvalidUtf8 { offset, .. } |
+            Error::LimitExceeded { offset, .. } |
+            Error::Truncated { offset, .. } => Some(*offset),
+            Error::InvalidMagic(_) | Error::UnsupportedVersion(_) => Some(0),
+            Error::NotFound(_) => None,
+        }
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Error::InvalidValueType { key, .. } |
+            Error::InvalidUtf8 { key, .. } |
+            Error::LimitExceeded { key, .. } |
+            Error::Truncated { key, .. } => key.as_deref(),
+            _ => None,
+        }
+    }
+
+    // Attaches the metadata key being parsed, keeping the innermost one if already set.
+    pub(crate) fn with_key(mut self, name: &str) -> Self {
+        if let
+            Error::InvalidValueType { key: key @ None, .. } |
+            Error::InvalidUtf8 { key: key @ None, .. } |
+            Error::LimitExceeded { key: key @ None, .. } |
+            Error::Truncated { key: key @ None, .. } = &mut self
+        {
+            *key = Some(name.to_string());
+        }
+        self
+    }
+}
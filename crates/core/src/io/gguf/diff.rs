@@ -0,0 +1,147 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::{ self, Display, Formatter };
+use serde::{ Deserialize, Serialize };
+
+use super::{ GGUFMetadataValue, GGUFTensorInfo, GGUF };
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "difference", rename_all = "snake_case")]
+pub enum GGUFDifference {
+    Header {
+        field: String,
+        a: String,
+        b: String,
+    },
+    MetadataAdded {
+        key: String,
+        value: GGUFMetadataValue,
+    },
+    MetadataRemoved {
+        key: String,
+        value: GGUFMetadataValue,
+    },
+    MetadataChanged {
+        key: String,
+        a: GGUFMetadataValue,
+        b: GGUFMetadataValue,
+    },
+    TensorAdded {
+        tensor: GGUFTensorInfo,
+    },
+    TensorRemoved {
+        tensor: GGUFTensorInfo,
+    },
+    TensorChanged {
+        a: GGUFTensorInfo,
+        b: GGUFTensorInfo,
+    },
+}
+
+fn tensor_description(tensor: &GGUFTensorInfo) -> String {
+    format!("{} {:?}", tensor.tensor_type, tensor.dimensions)
+}
+
+impl Display for GGUFDifference {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self {
+            GGUFDifference::Header { field, a, b } => write!(fmt, "~ {}: {} -> {}", field, a, b),
+            GGUFDifference::MetadataAdded { key, value } => write!(fmt, "+ {}={}", key, value),
+            GGUFDifference::MetadataRemoved { key, value } => write!(fmt, "- {}={}", key, value),
+            GGUFDifference::MetadataChanged { key, a, b } =>
+                write!(fmt, "~ {}: {} -> {}", key, a, b),
+            GGUFDifference::TensorAdded { tensor } =>
+                write!(fmt, "+ tensor {} {}", tensor.name, tensor_description(tensor)),
+            GGUFDifference::TensorRemoved { tensor } =>
+                write!(fmt, "- tensor {} {}", tensor.name, tensor_description(tensor)),
+            GGUFDifference::TensorChanged { a, b } =>
+                write!(
+                    fmt,
+                    "~ tensor {}: {} -> {}",
+                    a.name,
+                    tensor_description(a),
+                    tensor_description(b)
+                ),
+        }
+    }
+}
+
+impl GGUF {
+    // Differences from self (a) to other (b). Tensors are compared by type and
+    // dimensions, their offsets depend on the other tensors so they are ignored.
+    pub fn diff(&self, other: &GGUF) -> Vec<GGUFDifference> {
+        let mut differences = Vec::new();
+
+        let headers = [
+            ("version", self.header.version.to_string(), other.header.version.to_string()),
+            (
+                "byte_order",
+                format!("{:?}", self.header.byte_order),
+                format!("{:?}", other.header.byte_order),
+            ),
+            ("alignment", self.alignment.to_string(), other.alignment.to_string()),
+            ("tensor_count", self.tensors.len().to_string(), other.tensors.len().to_string()),
+        ];
+        for (field, a, b) in headers {
+            if a != b {
+                differences.push(GGUFDifference::Header { field: field.to_string(), a, b });
+            }
+        }
+
+        for metadata in &self.header.metadata_kv {
+            match other.get_metadata(&metadata.key) {
+                None =>
+                    differences.push(GGUFDifference::MetadataRemoved {
+                        key: metadata.key.clone(),
+                        value: metadata.value.clone(),
+                    }),
+                Some(value) if *value != metadata.value =>
+                    differences.push(GGUFDifference::MetadataChanged {
+                        key: metadata.key.clone(),
+                        a: metadata.value.clone(),
+                        b: value.clone(),
+                    }),
+                _ => {}
+            }
+        }
+        for metadata in &other.header.metadata_kv {
+            if self.get_metadata(&metadata.key).is_none() {
+                differences.push(GGUFDifference::MetadataAdded {
+                    key: metadata.key.clone(),
+                    value: metadata.value.clone(),
+                });
+            }
+        }
+
+        for tensor in &self.tensors {
+            match other.tensors.iter().find(|t| t.name == tensor.name) {
+                None => differences.push(GGUFDifference::TensorRemoved { tensor: tensor.clone() }),
+                Some(t) if t.tensor_type != tensor.tensor_type || t.dimensions != tensor.dimensions =>
+                    differences.push(GGUFDifference::TensorChanged {
+                        a: tensor.clone(),
+                        b: t.clone(),
+                    }),
+                _ => {}
+            }
+        }
+        for tensor in &other.tensors {
+            if !self.tensors.iter().any(|t| t.name == tensor.name) {
+                differences.push(GGUFDifference::TensorAdded { tensor: tensor.clone() });
+            }
+        }
+
+        differences
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use serde::{ Deserialize, Serialize };
+
+use super::{ GGMLType, GGUF };
+
+// Default logical batch size of llama.cpp.
+pub const DEFAULT_BATCH_SIZE: u64 = 512;
+
+// Memory needed to run a model, in bytes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GGUFMemoryEstimate {
+    pub weights: u64,
+    pub kv_cache: u64,
+    pub compute_buffer: u64,
+}
+
+impl GGUFMemoryEstimate {
+    pub fn total(&self) -> u64 {
+        self.weights.saturating_add(self.kv_cache).saturating_add(self.compute_buffer)
+    }
+}
+
+impl GGUF {
+    // Size of the weights of the repeating layer `index`, i.e. the tensors named blk.{index}.*
+    pub fn layer_size(&self, index: u64) -> u64 {
+        let prefix = format!("blk.{}.", index);
+        self.tensors
+            .iter()
+            .filter(|t| t.name.starts_with(&prefix))
+            .fold(0u64, |size, t| size.saturating_add(t.size()))
+    }
+
+    // Number of distinct repeating layers having tensors in the file.
+    pub fn layer_tensor_count(&self) -> u64 {
+        self.tensors
+            .iter()
+            .filter_map(|t| t.name.strip_prefix("blk."))
+            .filter_map(|name| name.split('.').next()?.parse::<u64>().ok())
+            .collect::<HashSet<_>>()
+            .len() as u64
+    }
+
+    // Size of the K and V caches of one layer for `context_size` tokens.
+    pub fn kv_cache_layer_size(&self, context_size: u64, kv_type: GGMLType) -> u64 {
+        let (embedding_length, head_count) = match (self.embedding_length(), self.head_count()) {
+            (Some(e), Some(h)) if h > 0 => (e, h),
+            _ => {
+                return 0;
+            }
+        };
+        let head_count_kv = self.head_count_kv().unwrap_or(head_count);
+        let key_length = self
+            .get_architecture_metadata("attention.key_length")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(embedding_length / head_count);
+        let value_length = self
+            .get_architecture_metadata("attention.value_length")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(embedding_length / head_count);
+        let elements = context_size
+            .saturating_mul(head_count_kv)
+            .saturating_mul(key_length.saturating_add(value_length));
+        elements.div_ceil(kv_type.block_size()).saturating_mul(kv_type.type_size())
+    }
+
+    pub fn kv_cache_size(&self, context_size: u64, kv_type: GGMLType) -> u64 {
+        self.block_count()
+            .unwrap_or(0)
+            .saturating_mul(self.kv_cache_layer_size(context_size, kv_type))
+    }
+
+    // Approximation of the llama.cpp compute graph without flash attention:
+    // f32 logits and activations for one batch, plus the attention scores.
+    pub fn compute_buffer_size(&self, context_size: u64, batch_size: u64) -> u64 {
+        let batch_size = batch_size.min(context_size);
+        let embedding_length = self.embedding_length().unwrap_or(0);
+        let head_count = self.head_count().unwrap_or(0);
+        let vocab_size = self.vocab_size().unwrap_or(0);
+        let activations = vocab_size
+            .saturating_add(embedding_length.saturating_mul(4))
+            .saturating_add(context_size.saturating_mul(head_count));
+        batch_size.saturating_mul(4).saturating_mul(activations)
+    }
+
+    pub fn estimate_memory(&self, context_size: u64, kv_type: GGMLType) -> GGUFMemoryEstimate {
+        GGUFMemoryEstimate {
+            weights: self.data_size(),
+            kv_cache: self.kv_cache_size(context_size, kv_type),
+            compute_buffer: self.compute_buffer_size(context_size, DEFAULT_BATCH_SIZE),
+        }
+    }
+
+    // Number of repeating layers, with their KV cache, fitting in `budget` bytes
+    // once the compute buffer is allocated. Used to choose the layers to offload to a GPU.
+    pub fn layers_fitting(&self, budget: u64, context_size: u64, kv_type: GGMLType) -> u64 {
+        let mut available = budget.saturating_sub(
+            self.compute_buffer_size(context_size, DEFAULT_BATCH_SIZE)
+        );
+        let kv_cache_layer_size = self.kv_cache_layer_size(context_size, kv_type);
+        // block_count comes from the file, only the layers with tensors can be counted
+        let layer_count = self.block_count().unwrap_or(0).min(self.layer_tensor_count());
+        let mut layers = 0;
+        for index in 0..layer_count {
+            let size = self.layer_size(index).saturating_add(kv_cache_layer_size);
+            if size > available {
+                break;
+            }
+            available -= size;
+            layers += 1;
+        }
+        layers
+    }
+}
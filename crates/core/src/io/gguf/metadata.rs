@@ -0,0 +1,170 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{ GGUFMetadataValue, GGUF };
+
+// See https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#standardized-key-value-pairs
+pub const GENERAL_ARCHITECTURE: &str = "general.architecture";
+pub const GENERAL_NAME: &str = "general.name";
+pub const GENERAL_FILE_TYPE: &str = "general.file_type";
+pub const TOKENIZER_MODEL: &str = "tokenizer.ggml.model";
+pub const TOKENIZER_TOKENS: &str = "tokenizer.ggml.tokens";
+pub const TOKENIZER_CHAT_TEMPLATE: &str = "tokenizer.chat_template";
+
+impl GGUFMetadataValue {
+    // Integers of any size and sign, negative values are ignored.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            GGUFMetadataValue::Uint8(v) => Some(*v as u64),
+            GGUFMetadataValue::Uint16(v) => Some(*v as u64),
+            GGUFMetadataValue::Uint32(v) => Some(*v as u64),
+            GGUFMetadataValue::Uint64(v) => Some(*v),
+            GGUFMetadataValue::Int8(v) => u64::try_from(*v).ok(),
+            GGUFMetadataValue::Int16(v) => u64::try_from(*v).ok(),
+            GGUFMetadataValue::Int32(v) => u64::try_from(*v).ok(),
+            GGUFMetadataValue::Int64(v) => u64::try_from(*v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            GGUFMetadataValue::Float32(v) => Some(*v as f64),
+            GGUFMetadataValue::Float64(v) => Some(*v),
+            _ => self.as_u64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GGUFMetadataValue::String(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+// Name of a llama_ftype, see https://github.com/ggerganov/llama.cpp/blob/master/include/llama.h
+pub fn file_type_name(file_type: u32) -> Option<&'static str> {
+    Some(match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        33 => "Q4_0_4_4",
+        34 => "Q4_0_4_8",
+        35 => "Q4_0_8_8",
+        36 => "TQ1_0",
+        37 => "TQ2_0",
+        _ => {
+            return None;
+        }
+    })
+}
+
+// Typed view over the well-known metadata keys.
+// Keys specific to an architecture are prefixed with it, e.g. llama.context_length.
+impl GGUF {
+    pub fn architecture(&self) -> Option<&str> {
+        self.get_metadata(GENERAL_ARCHITECTURE)?.as_str()
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.get_metadata(GENERAL_NAME)?.as_str()
+    }
+
+    pub fn get_architecture_metadata(&self, key: &str) -> Option<&GGUFMetadataValue> {
+        let architecture = self.architecture()?;
+        self.get_metadata(&format!("{}.{}", architecture, key))
+    }
+
+    pub fn context_length(&self) -> Option<u64> {
+        self.get_architecture_metadata("context_length")?.as_u64()
+    }
+
+    pub fn embedding_length(&self) -> Option<u64> {
+        self.get_architecture_metadata("embedding_length")?.as_u64()
+    }
+
+    pub fn block_count(&self) -> Option<u64> {
+        self.get_architecture_metadata("block_count")?.as_u64()
+    }
+
+    pub fn head_count(&self) -> Option<u64> {
+        self.get_architecture_metadata("attention.head_count")?.as_u64()
+    }
+
+    // Defaults to head_count for models without grouped-query attention.
+    pub fn head_count_kv(&self) -> Option<u64> {
+        self.get_architecture_metadata("attention.head_count_kv")
+            .and_then(|v| v.as_u64())
+            .or_else(|| self.head_count())
+    }
+
+    // Defaults to the number of tokens of the tokenizer.
+    pub fn vocab_size(&self) -> Option<u64> {
+        self.get_architecture_metadata("vocab_size")
+            .and_then(|v| v.as_u64())
+            .or_else(|| {
+                match self.get_metadata(TOKENIZER_TOKENS)? {
+                    GGUFMetadataValue::Array(tokens) => Some(tokens.len),
+                    _ => None,
+                }
+            })
+    }
+
+    pub fn file_type(&self) -> Option<u32> {
+        self.get_metadata(GENERAL_FILE_TYPE)?
+            .as_u64()
+            .map(|v| v as u32)
+    }
+
+    // Quantization name from the file type, e.g. Q4_K_M.
+    pub fn quantization(&self) -> Option<&'static str> {
+        file_type_name(self.file_type()?)
+    }
+
+    pub fn chat_template(&self) -> Option<&str> {
+        self.get_metadata(TOKENIZER_CHAT_TEMPLATE)?.as_str()
+    }
+
+    pub fn tokenizer_model(&self) -> Option<&str> {
+        self.get_metadata(TOKENIZER_MODEL)?.as_str()
+    }
+}
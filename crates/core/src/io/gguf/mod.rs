@@ -12,10 +12,48 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{ fmt::{ self, Display, Formatter }, fs::File, io::{ BufReader, Read } };
+use std::{
+    fmt::{ self, Display, Formatter },
+    fs::{ self, File },
+    io::{ BufReader, BufWriter, Cursor, ErrorKind, Read, Seek, SeekFrom, Write },
+    path::Path,
+};
+use memmap2::Mmap;
 use serde::{ Deserialize, Serialize };
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+use crate::{ Error, Result };
+
+mod diff;
+mod memory;
+mod metadata;
+mod observer;
+mod parser;
+mod tensor;
+mod validate;
+mod writer;
+pub use diff::GGUFDifference;
+pub use memory::{ GGUFMemoryEstimate, DEFAULT_BATCH_SIZE };
+pub use metadata::{
+    file_type_name,
+    GENERAL_ARCHITECTURE,
+    GENERAL_FILE_TYPE,
+    GENERAL_NAME,
+    TOKENIZER_CHAT_TEMPLATE,
+    TOKENIZER_MODEL,
+    TOKENIZER_TOKENS,
+};
+#[cfg(feature = "tracing")]
+pub use observer::GGUFTracingObserver;
+pub use observer::GGUFObserver;
+pub use parser::{ GGUFByteOrder, GGUFLimits, GGUFParser };
+pub use tensor::{ GGMLType, GGUFTensorInfo };
+pub use validate::{ GGUFValidationIssue, REQUIRED_KEYS };
+pub use writer::GGUFWriter;
+
+// Default alignment of the tensor data, used when general.alignment is not set.
+pub const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum GGUFMetadataValueType {
     // The value is a 8-bit unsigned integer.
     UInt8 = 0,
@@ -38,7 +76,7 @@ pub enum GGUFMetadataValueType {
     // The value is a UTF-8 non-null-terminated string, with length prepended.
     String = 8,
     // The value is an array of other values, with the length and type prepended.
-    ///
+    //
     // Arrays can be nested, and the length of the array is the number of elements in the array, not the number of bytes.
     Array = 9,
     // The value is a 64-bit unsigned little-endian integer.
@@ -49,11 +87,9 @@ pub enum GGUFMetadataValueType {
     Float64 = 12,
 }
 
-impl TryFrom<u32> for GGUFMetadataValueType {
-    type Error = String;
-
-    fn try_from(item: u32) -> Result<Self, Self::Error> {
-        Ok(match item {
+impl GGUFMetadataValueType {
+    pub fn from_u32(item: u32) -> Option<Self> {
+        Some(match item {
             0 => GGUFMetadataValueType::UInt8,
             1 => GGUFMetadataValueType::Int8,
             2 => GGUFMetadataValueType::UInt16,
@@ -68,13 +104,31 @@ impl TryFrom<u32> for GGUFMetadataValueType {
             11 => GGUFMetadataValueType::Int64,
             12 => GGUFMetadataValueType::Float64,
             _ => {
-                return Err(format!("invalid gguf metadata type 0x{:x}", item));
+                return None;
             }
         })
     }
+
+    // Smallest number of bytes a value of this type can take in a file.
+    pub(crate) fn min_size(&self, length_size: u64) -> u64 {
+        match self {
+            GGUFMetadataValueType::UInt8 |
+            GGUFMetadataValueType::Int8 |
+            GGUFMetadataValueType::Bool => 1,
+            GGUFMetadataValueType::UInt16 | GGUFMetadataValueType::Int16 => 2,
+            GGUFMetadataValueType::UInt32 |
+            GGUFMetadataValueType::Int32 |
+            GGUFMetadataValueType::Float32 => 4,
+            GGUFMetadataValueType::UInt64 |
+            GGUFMetadataValueType::Int64 |
+            GGUFMetadataValueType::Float64 => 8,
+            GGUFMetadataValueType::String => length_size,
+            GGUFMetadataValueType::Array => 4 + length_size,
+        }
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum GGUFMetadataValue {
     Uint8(u8),
@@ -92,7 +146,27 @@ pub enum GGUFMetadataValue {
     Array(GGUFMetadataArrayValue),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl GGUFMetadataValue {
+    pub fn value_type(&self) -> GGUFMetadataValueType {
+        match self {
+            GGUFMetadataValue::Uint8(_) => GGUFMetadataValueType::UInt8,
+            GGUFMetadataValue::Int8(_) => GGUFMetadataValueType::Int8,
+            GGUFMetadataValue::Uint16(_) => GGUFMetadataValueType::UInt16,
+            GGUFMetadataValue::Int16(_) => GGUFMetadataValueType::Int16,
+            GGUFMetadataValue::Uint32(_) => GGUFMetadataValueType::UInt32,
+            GGUFMetadataValue::Int32(_) => GGUFMetadataValueType::Int32,
+            GGUFMetadataValue::Float32(_) => GGUFMetadataValueType::Float32,
+            GGUFMetadataValue::Uint64(_) => GGUFMetadataValueType::UInt64,
+            GGUFMetadataValue::Int64(_) => GGUFMetadataValueType::Int64,
+            GGUFMetadataValue::Float64(_) => GGUFMetadataValueType::Float64,
+            GGUFMetadataValue::Bool(_) => GGUFMetadataValueType::Bool,
+            GGUFMetadataValue::String(_) => GGUFMetadataValueType::String,
+            GGUFMetadataValue::Array(_) => GGUFMetadataValueType::Array,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct GGUFMetadataArrayValue {
     pub value_type: GGUFMetadataValueType,
     pub len: u64,
@@ -132,15 +206,28 @@ pub struct GGUFMetadata {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GGUFHeader {
     pub version: u32,
+    #[serde(default)]
+    pub byte_order: GGUFByteOrder,
     pub tensor_count: u64,
     pub metadata_kv_count: u64,
     pub metadata_kv: Vec<GGUFMetadata>,
 }
 
+#[derive(Clone, Debug, Default)]
+pub struct GGUFReadOptions {
+    // Map the file in memory instead of reading it through a buffer.
+    pub use_mmap: bool,
+    pub limits: GGUFLimits,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GGUF {
     pub file_name: String,
     pub header: GGUFHeader,
+    pub tensors: Vec<GGUFTensorInfo>,
+    pub alignment: u64,
+    // Absolute offset in the file of the tensor data section.
+    pub data_offset: u64,
 }
 
 impl GGUF {
@@ -149,172 +236,819 @@ impl GGUF {
             file_name: file_name.to_string(),
             header: GGUFHeader {
                 version: 0,
+                byte_order: GGUFByteOrder::LittleEndian,
                 tensor_count: 0,
                 metadata_kv_count: 0,
                 metadata_kv: Vec::new(),
             },
+            tensors: Vec::new(),
+            alignment: GGUF_DEFAULT_ALIGNMENT,
+            data_offset: 0,
         }
     }
 
-    fn parse_metadata_value(
+    fn parse_metadata_value<R: Read>(
         &mut self,
-        reader: &mut BufReader<File>,
-        value_type: &GGUFMetadataValueType
-    ) -> Result<GGUFMetadataValue, anyhow::Error> {
+        reader: &mut GGUFParser<R>,
+        value_type: &GGUFMetadataValueType,
+        depth: u32
+    ) -> Result<GGUFMetadataValue> {
         match value_type {
-            GGUFMetadataValueType::UInt8 => {
-                let mut value = [0; 1];
-                reader.read_exact(&mut value)?;
-                Ok(GGUFMetadataValue::Uint8(value[0]))
-            }
-            GGUFMetadataValueType::Int8 => {
-                let mut value = [0; 1];
-                reader.read_exact(&mut value)?;
-                Ok(GGUFMetadataValue::Int8(value[0] as i8))
-            }
-            GGUFMetadataValueType::UInt16 => {
-                let mut value = [0; 2];
-                reader.read_exact(&mut value)?;
-                Ok(GGUFMetadataValue::Uint16(u16::from_le_bytes(value)))
-            }
-            GGUFMetadataValueType::Int16 => {
-                let mut value = [0; 2];
-                reader.read_exact(&mut value)?;
-                Ok(GGUFMetadataValue::Int16(i16::from_le_bytes(value)))
-            }
-            GGUFMetadataValueType::UInt32 => {
-                let mut value = [0; 4];
-                reader.read_exact(&mut value)?;
-                Ok(GGUFMetadataValue::Uint32(u32::from_le_bytes(value)))
-            }
-            GGUFMetadataValueType::Int32 => {
-                let mut value = [0; 4];
-                reader.read_exact(&mut value)?;
-                Ok(GGUFMetadataValue::Int32(i32::from_le_bytes(value)))
-            }
-            GGUFMetadataValueType::Float32 => {
-                let mut value = [0; 4];
-                reader.read_exact(&mut value)?;
-                Ok(GGUFMetadataValue::Float32(f32::from_le_bytes(value)))
-            }
-            GGUFMetadataValueType::Bool => {
-                let mut value = [0; 1];
-                reader.read_exact(&mut value)?;
-                Ok(GGUFMetadataValue::Bool(value[0] != 0))
-            }
+            GGUFMetadataValueType::UInt8 => Ok(GGUFMetadataValue::Uint8(reader.read_u8()?)),
+            GGUFMetadataValueType::Int8 => Ok(GGUFMetadataValue::Int8(reader.read_i8()?)),
+            GGUFMetadataValueType::UInt16 => Ok(GGUFMetadataValue::Uint16(reader.read_u16()?)),
+            GGUFMetadataValueType::Int16 => Ok(GGUFMetadataValue::Int16(reader.read_i16()?)),
+            GGUFMetadataValueType::UInt32 => Ok(GGUFMetadataValue::Uint32(reader.read_u32()?)),
+            GGUFMetadataValueType::Int32 => Ok(GGUFMetadataValue::Int32(reader.read_i32()?)),
+            GGUFMetadataValueType::Float32 => Ok(GGUFMetadataValue::Float32(reader.read_f32()?)),
+            GGUFMetadataValueType::Bool => Ok(GGUFMetadataValue::Bool(reader.read_bool()?)),
             GGUFMetadataValueType::String => {
-                let mut value_length = [0; 8];
-                reader.read_exact(&mut value_length)?;
-                let length = u64::from_le_bytes(value_length) as usize;
-
-                let mut value = vec![0; length];
-                reader.read_exact(&mut value)?;
-                let value = String::from_utf8(value)?;
-                // println!("String length: {} value: {}", length, value);
+                let value = reader.read_string()?;
+                // println!("String value: {}", value);
                 Ok(GGUFMetadataValue::String(value))
             }
             GGUFMetadataValueType::Array => {
-                let mut value_type = [0; 4];
-                reader.read_exact(&mut value_type)?;
-                let value_type = u32::from_le_bytes(value_type);
-                let value_type = GGUFMetadataValueType::try_from(value_type).map_err(|err|
-                    anyhow::Error::msg(err.to_string())
-                )?;
+                let offset = reader.position();
+                reader.check_limit("array depth", offset, depth + 1, reader.limits.max_depth)?;
+                let value_type = reader.read_value_type()?;
 
-                let mut value_length = [0; 8];
-                reader.read_exact(&mut value_length)?;
-                let length = u64::from_le_bytes(value_length) as usize;
+                let offset = reader.position();
+                let length = reader.read_length()?;
+                reader.check_limit("array length", offset, length, reader.limits.max_array_length)?;
+                reader.ensure_available(
+                    length.saturating_mul(value_type.min_size(reader.length_size()))
+                )?;
                 // println!("Array length: {} type: {:?}", length, value_type);
                 let mut value = Vec::new();
                 for _ in 0..length {
-                    value.push(self.parse_metadata_value(reader, &value_type)?);
+                    value.push(self.parse_metadata_value(reader, &value_type, depth + 1)?);
                 }
 
                 Ok(
                     GGUFMetadataValue::Array(GGUFMetadataArrayValue {
                         value_type,
-                        len: length as u64,
+                        len: length,
                         value,
                     })
                 )
             }
-            GGUFMetadataValueType::UInt64 => {
-                let mut value = [0; 8];
-                reader.read_exact(&mut value)?;
-                Ok(GGUFMetadataValue::Uint64(u64::from_le_bytes(value)))
-            }
-            GGUFMetadataValueType::Int64 => {
-                let mut value = [0; 8];
-                reader.read_exact(&mut value)?;
-                Ok(GGUFMetadataValue::Int64(i64::from_le_bytes(value)))
-            }
-            GGUFMetadataValueType::Float64 => {
-                let mut value = [0; 8];
-                reader.read_exact(&mut value)?;
-                Ok(GGUFMetadataValue::Float64(f64::from_le_bytes(value)))
-            }
+            GGUFMetadataValueType::UInt64 => Ok(GGUFMetadataValue::Uint64(reader.read_u64()?)),
+            GGUFMetadataValueType::Int64 => Ok(GGUFMetadataValue::Int64(reader.read_i64()?)),
+            GGUFMetadataValueType::Float64 => Ok(GGUFMetadataValue::Float64(reader.read_f64()?)),
         }
     }
 
-    fn parse_metadata_kv(&mut self, reader: &mut BufReader<File>) -> Result<(), anyhow::Error> {
-        let mut key_length = [0; 8];
-        let mut value_type = [0; 4];
+    fn parse_metadata_kv<R: Read>(
+        &mut self,
+        reader: &mut GGUFParser<R>,
+        observer: &mut dyn GGUFObserver
+    ) -> Result<()> {
         for _ in 0..self.header.metadata_kv_count {
-            reader.read_exact(&mut key_length)?;
-            let length = u64::from_le_bytes(key_length) as usize;
-
-            let mut key = vec![0; length];
-            reader.read_exact(&mut key)?;
-            let key = String::from_utf8(key)?;
+            let key = reader.read_string()?;
 
-            reader.read_exact(&mut value_type)?;
-            let value_type = u32::from_le_bytes(value_type);
-            let value_type = GGUFMetadataValueType::try_from(value_type).map_err(|err|
-                anyhow::Error::msg(err.to_string())
-            )?;
-
-            let value = self.parse_metadata_value(reader, &value_type)?;
+            let value_type = reader.read_value_type().map_err(|err| err.with_key(&key))?;
 
-            println!("{}={}", key, value);
+            let value = self
+                .parse_metadata_value(reader, &value_type, 0)
+                .map_err(|err| err.with_key(&key))?;
 
-            self.header.metadata_kv.push(GGUFMetadata {
+            let metadata = GGUFMetadata {
                 key,
                 value,
                 value_type,
-            });
+            };
+            observer.on_metadata(&metadata);
+            self.header.metadata_kv.push(metadata);
         }
         Ok(())
     }
 
-    pub fn read(&mut self, path: &str) -> Result<(), String> {
-        println!("Reading GGUF file: {}", path);
+    fn parse_tensors_info<R: Read>(
+        &mut self,
+        reader: &mut GGUFParser<R>,
+        observer: &mut dyn GGUFObserver
+    ) -> Result<()> {
+        for _ in 0..self.header.tensor_count {
+            let name = reader.read_string()?;
+
+            let offset = reader.position();
+            let n_dimensions = reader.read_u32()?;
+            reader.check_limit(
+                "tensor dimensions",
+                offset,
+                n_dimensions,
+                reader.limits.max_tensor_dimensions
+            )?;
+            let mut dimensions = Vec::new();
+            for _ in 0..n_dimensions {
+                dimensions.push(reader.read_dimension()?);
+            }
+
+            let offset = reader.position();
+            let tensor_type = reader.read_u32()?;
+            let tensor_type = GGMLType::from_u32(tensor_type).ok_or_else(|| {
+                Error::InvalidTensorType { tensor_type, offset, tensor: name.clone() }
+            })?;
 
-        let input = File::open(path).map_err(|err| err.to_string())?;
+            let offset = reader.read_u64()?;
+
+            let tensor = GGUFTensorInfo {
+                name,
+                n_dimensions,
+                dimensions,
+                tensor_type,
+                offset,
+            };
+            observer.on_tensor_info(&tensor);
+            self.tensors.push(tensor);
+        }
+        Ok(())
+    }
+
+    fn parse_alignment(&mut self) {
+        let alignment = self.header.metadata_kv
+            .iter()
+            .find(|kv| kv.key == "general.alignment")
+            .map(|kv| {
+                match kv.value {
+                    GGUFMetadataValue::Uint32(v) => v as u64,
+                    _ => 0,
+                }
+            })
+            .unwrap_or(GGUF_DEFAULT_ALIGNMENT);
+        // The alignment must be a non-zero power of two, fallback to default otherwise
+        self.alignment = if alignment.is_power_of_two() {
+            alignment
+        } else {
+            GGUF_DEFAULT_ALIGNMENT
+        };
+    }
+
+    // Size of the tensor data section, i.e. the end of the last tensor,
+    // saturated to u64::MAX when a tensor end overflows.
+    pub fn data_size(&self) -> u64 {
+        self.tensors
+            .iter()
+            .map(|t| t.checked_end().unwrap_or(u64::MAX))
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn get_metadata(&self, key: &str) -> Option<&GGUFMetadataValue> {
+        self.header.metadata_kv
+            .iter()
+            .find(|kv| kv.key == key)
+            .map(|kv| &kv.value)
+    }
+
+    // Replaces the value of an existing key, or appends it.
+    pub fn set_metadata(&mut self, key: &str, value: GGUFMetadataValue) {
+        let value_type = value.value_type();
+        match self.header.metadata_kv.iter_mut().find(|kv| kv.key == key) {
+            Some(kv) => {
+                kv.value_type = value_type;
+                kv.value = value;
+            }
+            None => {
+                self.header.metadata_kv.push(GGUFMetadata {
+                    key: key.to_string(),
+                    value_type,
+                    value,
+                });
+            }
+        }
+        self.header.metadata_kv_count = self.header.metadata_kv.len() as u64;
+        self.parse_alignment();
+    }
+
+    pub fn remove_metadata(&mut self, key: &str) -> Option<GGUFMetadataValue> {
+        let index = self.header.metadata_kv.iter().position(|kv| kv.key == key)?;
+        let metadata = self.header.metadata_kv.remove(index);
+        self.header.metadata_kv_count = self.header.metadata_kv.len() as u64;
+        self.parse_alignment();
+        Some(metadata.value)
+    }
+
+    // Writes the header, metadata and tensors info to `path`, then copies the tensor data
+    // from the file that was read. Writing to the file that was read is done through a
+    // temporary file.
+    pub fn write(&self, path: &str) -> Result<()> {
+        let io_error = |err| Error::Io { offset: 0, source: err };
+        let mut input = File::open(&self.file_name).map_err(|err| {
+            if err.kind() == ErrorKind::NotFound {
+                Error::NotFound(self.file_name.clone())
+            } else {
+                io_error(err)
+            }
+        })?;
+        let size = input.metadata().map_err(io_error)?.len();
+        input.seek(SeekFrom::Start(self.data_offset)).map_err(|err| Error::Io {
+            offset: self.data_offset,
+            source: err,
+        })?;
+
+        let in_place = Path::new(path) == Path::new(&self.file_name);
+        let output_path = if in_place { format!("{}.tmp", path) } else { path.to_string() };
+        let result = self
+            .write_file(input, size, &output_path)
+            .and_then(|_| {
+                if in_place { fs::rename(&output_path, path).map_err(io_error) } else { Ok(()) }
+            });
+        // Don't leave the temporary file behind
+        if result.is_err() && in_place {
+            fs::remove_file(&output_path).ok();
+        }
+        result
+    }
+
+    fn write_file(&self, input: File, size: u64, output_path: &str) -> Result<()> {
+        let output = File::create(output_path).map_err(|err| Error::Io { offset: 0, source: err })?;
+        let mut writer = GGUFWriter::new(
+            BufWriter::new(output),
+            self.header.byte_order,
+            self.header.version
+        );
+        writer.write_header(self)?;
+        // The input is closed when returning, before it is replaced
         let mut reader = BufReader::new(input);
+        writer.write_tensor_data(&mut reader, size.saturating_sub(self.data_offset))?;
+        let offset = writer.position();
+        writer
+            .into_inner()
+            .flush()
+            .map_err(|err| Error::Io { offset, source: err })
+    }
 
-        let mut header = [0; 8];
-        reader.read_exact(&mut header).map_err(|err| err.to_string())?;
+    pub fn read(&mut self, path: &str) -> Result<()> {
+        self.read_with_options(path, &GGUFReadOptions::default(), &mut ())
+    }
 
-        if header[0] != 0x47 && header[1] != 0x47 && header[2] != 0x55 && header[3] != 0x46 {
-            return Err("Not valid GGUF".to_string());
+    pub fn read_with_options(
+        &mut self,
+        path: &str,
+        options: &GGUFReadOptions,
+        observer: &mut dyn GGUFObserver
+    ) -> Result<()> {
+        let input = File::open(path).map_err(|err| {
+            if err.kind() == ErrorKind::NotFound {
+                Error::NotFound(path.to_string())
+            } else {
+                Error::Io { offset: 0, source: err }
+            }
+        })?;
+        // The tensor data is copied from this file when writing
+        self.file_name = path.to_string();
+        let size = input
+            .metadata()
+            .map_err(|err| Error::Io { offset: 0, source: err })?
+            .len();
+        if options.use_mmap {
+            // Safety: the file is only read, and must not be modified while mapped
+            let mmap = unsafe { Mmap::map(&input) }.map_err(|err| Error::Io {
+                offset: 0,
+                source: err,
+            })?;
+            self.read_from(Cursor::new(&mmap[..]), Some(size), options.limits.clone(), observer)
+        } else {
+            self.read_from(BufReader::new(input), Some(size), options.limits.clone(), observer)
         }
+    }
+
+    pub fn read_from<R: Read>(
+        &mut self,
+        reader: R,
+        size: Option<u64>,
+        limits: GGUFLimits,
+        observer: &mut dyn GGUFObserver
+    ) -> Result<()> {
+        let mut reader = GGUFParser::new(reader, size, limits);
+        // A GGUF can be read again, the previous metadata and tensors are replaced
+        self.header.metadata_kv.clear();
+        self.tensors.clear();
+
+        self.header.version = reader.read_magic_and_version()?;
+        self.header.byte_order = reader.byte_order;
 
-        // Models are little-endian by default.
-        self.header.version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
-        println!("Version: {}", self.header.version);
+        let offset = reader.position();
+        self.header.tensor_count = reader.read_length()?;
+        reader.check_limit(
+            "tensor count",
+            offset,
+            self.header.tensor_count,
+            reader.limits.max_tensor_count
+        )?;
 
-        reader.read_exact(&mut header).map_err(|err| err.to_string())?;
+        let offset = reader.position();
+        self.header.metadata_kv_count = reader.read_length()?;
+        reader.check_limit(
+            "metadata kv count",
+            offset,
+            self.header.metadata_kv_count,
+            reader.limits.max_metadata_kv_count
+        )?;
 
-        self.header.tensor_count = u64::from_le_bytes(header);
-        println!("Tensor_count: {}", self.header.tensor_count);
+        observer.on_header(&self.file_name, &self.header);
 
-        reader.read_exact(&mut header).map_err(|err| err.to_string())?;
+        self.parse_metadata_kv(&mut reader, observer)?;
+        self.parse_alignment();
 
-        self.header.metadata_kv_count = u64::from_le_bytes(header);
-        println!("Metadata_kv_count: {}", self.header.metadata_kv_count);
+        self.parse_tensors_info(&mut reader, observer)?;
 
-        self.parse_metadata_kv(&mut reader).map_err(|err| err.to_string())?;
+        let position = reader.position();
+        self.data_offset = position.div_ceil(self.alignment) * self.alignment;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{ fs, path::PathBuf };
+
+    use crate::Error;
+    use super::{
+        GGMLType,
+        GGUFByteOrder,
+        GGUFDifference,
+        GGUFHeader,
+        GGUFLimits,
+        GGUFMetadata,
+        GGUFMetadataArrayValue,
+        GGUFMetadataValue,
+        GGUFMetadataValueType,
+        GGUFObserver,
+        GGUFReadOptions,
+        GGUFTensorInfo,
+        GGUFValidationIssue,
+        GGUF,
+        GGUF_DEFAULT_ALIGNMENT,
+    };
+
+    struct TestFile {
+        version: u32,
+        byte_order: GGUFByteOrder,
+        buffer: Vec<u8>,
+    }
+
+    impl TestFile {
+        fn new(version: u32, byte_order: GGUFByteOrder) -> Self {
+            TestFile { version, byte_order, buffer: b"GGUF".to_vec() }
+        }
+
+        fn u32(&mut self, value: u32) {
+            match self.byte_order {
+                GGUFByteOrder::LittleEndian => self.buffer.extend(value.to_le_bytes()),
+                GGUFByteOrder::BigEndian => self.buffer.extend(value.to_be_bytes()),
+            }
+        }
+
+        fn u64(&mut self, value: u64) {
+            match self.byte_order {
+                GGUFByteOrder::LittleEndian => self.buffer.extend(value.to_le_bytes()),
+                GGUFByteOrder::BigEndian => self.buffer.extend(value.to_be_bytes()),
+            }
+        }
+
+        fn length(&mut self, value: u64) {
+            if self.version == 1 {
+                self.u32(value as u32);
+            } else {
+                self.u64(value);
+            }
+        }
+
+        fn string(&mut self, value: &str) {
+            self.length(value.len() as u64);
+            self.buffer.extend(value.as_bytes());
+        }
+
+        fn build(mut self) -> Vec<u8> {
+            let version = self.version;
+            self.u32(version);
+            self.length(2);
+            self.length(2);
+
+            self.string("general.architecture");
+            self.u32(8);
+            self.string("llama");
+            self.string("general.alignment");
+            self.u32(4);
+            self.u32(64);
+
+            self.string("token_embd.weight");
+            self.u32(2);
+            self.length(64);
+            self.length(2);
+            self.u32(8);
+            self.u64(0);
+
+            self.string("output_norm.weight");
+            self.u32(1);
+            self.length(64);
+            self.u32(0);
+            self.u64(192);
+
+            let mut buffer = self.buffer;
+            buffer.resize(buffer.len().div_ceil(64) * 64, 0);
+            buffer.resize(buffer.len() + 192 + 256, 0);
+            buffer
+        }
+    }
+
+    fn write_test_file(name: &str, buffer: Vec<u8>) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, buffer).unwrap();
+        path
+    }
+
+    fn read_test_file(name: &str, buffer: Vec<u8>) -> Result<GGUF, Error> {
+        let path = write_test_file(name, buffer);
+        let file_name = path.to_str().unwrap();
+        let mut gguf = GGUF::new(file_name);
+        let result = gguf.read(file_name);
+        fs::remove_file(&path).unwrap();
+        result.map(|_| gguf)
+    }
+
+    #[test]
+    fn test_read_tensors_info() {
+        let buffer = TestFile::new(3, GGUFByteOrder::LittleEndian).build();
+        let gguf = read_test_file("opla_core_test_read_tensors_info.gguf", buffer).unwrap();
+
+        assert_eq!(gguf.alignment, 64);
+        assert_eq!(gguf.data_offset % 64, 0);
+        assert_eq!(gguf.tensors.len(), 2);
+        assert_eq!(gguf.tensors[0].name, "token_embd.weight");
+        assert_eq!(gguf.tensors[0].tensor_type, GGMLType::Q8_0);
+        assert_eq!(gguf.tensors[0].size(), 2 * 34 * 2);
+        assert_eq!(gguf.tensors[1].tensor_type, GGMLType::F32);
+        assert_eq!(gguf.data_size(), 192 + 256);
+    }
+
+    #[test]
+    fn test_metadata_accessors() {
+        let buffer = TestFile::new(3, GGUFByteOrder::LittleEndian).build();
+        let mut gguf = read_test_file("opla_core_test_metadata_accessors.gguf", buffer).unwrap();
+        assert_eq!(gguf.architecture(), Some("llama"));
+        assert_eq!(gguf.context_length(), None);
+
+        gguf.set_metadata("llama.context_length", GGUFMetadataValue::Uint32(4096));
+        gguf.set_metadata("llama.attention.head_count", GGUFMetadataValue::Uint32(32));
+        gguf.set_metadata("general.file_type", GGUFMetadataValue::Uint32(15));
+        gguf.set_metadata(
+            "tokenizer.chat_template",
+            GGUFMetadataValue::String("{{ messages }}".to_string())
+        );
+        assert_eq!(gguf.context_length(), Some(4096));
+        assert_eq!(gguf.head_count_kv(), Some(32));
+        assert_eq!(gguf.quantization(), Some("Q4_K_M"));
+        assert_eq!(gguf.chat_template(), Some("{{ messages }}"));
+        assert_eq!(gguf.tokenizer_model(), None);
+    }
+
+    #[test]
+    fn test_read_v1_and_big_endian() {
+        let buffer = TestFile::new(1, GGUFByteOrder::LittleEndian).build();
+        let gguf = read_test_file("opla_core_test_read_v1.gguf", buffer).unwrap();
+        assert_eq!(gguf.header.version, 1);
+        assert_eq!(gguf.tensors[0].dimensions, vec![64, 2]);
+
+        let buffer = TestFile::new(3, GGUFByteOrder::BigEndian).build();
+        let gguf = read_test_file("opla_core_test_read_be.gguf", buffer).unwrap();
+        assert_eq!(gguf.header.byte_order, GGUFByteOrder::BigEndian);
+        assert_eq!(gguf.header.metadata_kv[0].value.to_string(), "llama");
+        assert_eq!(gguf.tensors[1].offset, 192);
+    }
+
+    #[test]
+    fn test_read_invalid_magic() {
+        let mut buffer = TestFile::new(3, GGUFByteOrder::LittleEndian).build();
+        buffer[0] = b'X';
+        let result = read_test_file("opla_core_test_read_invalid_magic.gguf", buffer);
+        assert!(matches!(result, Err(Error::InvalidMagic(_))));
+
+        let mut gguf = GGUF::new("not_found.gguf");
+        let result = gguf.read("not_found.gguf");
+        assert!(result.unwrap_err().is_not_found());
+    }
+
+    #[test]
+    fn test_read_error_context() {
+        let mut file = TestFile::new(3, GGUFByteOrder::LittleEndian);
+        file.u32(3);
+        file.length(0);
+        file.length(1);
+        file.string("general.name");
+        file.u32(42);
+        let size = file.buffer.len() as u64;
+        let mut gguf = GGUF::new("context");
+        let err = gguf
+            .read_from(&file.buffer[..], Some(size), GGUFLimits::default(), &mut ())
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidValueType { value_type: 42, .. }));
+        assert_eq!(err.offset(), Some(size - 4));
+        assert_eq!(err.key(), Some("general.name"));
+    }
+
+    #[test]
+    fn test_read_with_limits() {
+        let buffer = TestFile::new(3, GGUFByteOrder::LittleEndian).build();
+        let path = write_test_file("opla_core_test_read_with_limits.gguf", buffer);
+        let file_name = path.to_str().unwrap();
+
+        let mut gguf = GGUF::new(file_name);
+        let options = GGUFReadOptions { use_mmap: true, limits: GGUFLimits::default() };
+        gguf.read_with_options(file_name, &options, &mut ()).unwrap();
+        assert_eq!(gguf.tensors.len(), 2);
+
+        let mut gguf = GGUF::new(file_name);
+        let options = GGUFReadOptions {
+            use_mmap: false,
+            limits: GGUFLimits { max_string_length: 8, ..GGUFLimits::default() },
+        };
+        let result = gguf.read_with_options(file_name, &options, &mut ());
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(Error::LimitExceeded { .. })));
+    }
+
+    #[test]
+    fn test_read_huge_length() {
+        let mut file = TestFile::new(3, GGUFByteOrder::LittleEndian);
+        file.u32(3);
+        file.length(0);
+        file.length(1);
+        file.string("tokenizer.ggml.tokens");
+        file.u32(9);
+        file.u32(8);
+        file.length(u64::MAX / 2);
+        let mut gguf = GGUF::new("huge");
+        let size = file.buffer.len() as u64;
+        let result = gguf.read_from(&file.buffer[..], Some(size), GGUFLimits::default(), &mut ());
+        assert!(matches!(result, Err(Error::LimitExceeded { .. })));
+
+        let limits = GGUFLimits { max_array_length: u64::MAX, ..GGUFLimits::default() };
+        let result = gguf.read_from(&file.buffer[..], Some(size), limits, &mut ());
+        assert!(matches!(result, Err(Error::Truncated { .. })));
+    }
+
+    #[derive(Default)]
+    struct TestObserver {
+        events: Vec<String>,
+    }
+
+    impl GGUFObserver for TestObserver {
+        fn on_header(&mut self, _path: &str, header: &GGUFHeader) {
+            self.events.push(format!("v{}", header.version));
+        }
+
+        fn on_metadata(&mut self, metadata: &GGUFMetadata) {
+            self.events.push(format!("{}={}", metadata.key, metadata.value));
+        }
+
+        fn on_tensor_info(&mut self, tensor: &GGUFTensorInfo) {
+            self.events.push(tensor.name.clone());
+        }
+    }
+
+    #[test]
+    fn test_read_with_observer() {
+        let buffer = TestFile::new(3, GGUFByteOrder::LittleEndian).build();
+        let size = buffer.len() as u64;
+        let mut observer = TestObserver::default();
+        let mut gguf = GGUF::new("observer");
+        gguf.read_from(&buffer[..], Some(size), GGUFLimits::default(), &mut observer).unwrap();
+        assert_eq!(observer.events, vec![
+            "v3",
+            "general.architecture=llama",
+            "general.alignment=Uint32(64)",
+            "token_embd.weight",
+            "output_norm.weight"
+        ]);
+    }
+
+    #[test]
+    fn test_estimate_memory() {
+        let mut gguf = GGUF::new("estimate");
+        gguf.set_metadata("general.architecture", GGUFMetadataValue::String("llama".to_string()));
+        gguf.set_metadata("llama.block_count", GGUFMetadataValue::Uint32(2));
+        gguf.set_metadata("llama.embedding_length", GGUFMetadataValue::Uint32(256));
+        gguf.set_metadata("llama.attention.head_count", GGUFMetadataValue::Uint32(8));
+        gguf.set_metadata("llama.attention.head_count_kv", GGUFMetadataValue::Uint32(2));
+        gguf.set_metadata("llama.vocab_size", GGUFMetadataValue::Uint32(1000));
+        let mut offset = 0;
+        for name in ["token_embd.weight", "blk.0.attn_q.weight", "blk.1.attn_q.weight"] {
+            let tensor = GGUFTensorInfo {
+                name: name.to_string(),
+                n_dimensions: 2,
+                dimensions: vec![256, 256],
+                tensor_type: GGMLType::F16,
+                offset,
+            };
+            offset += tensor.size();
+            gguf.tensors.push(tensor);
+        }
+
+        let estimate = gguf.estimate_memory(1024, GGMLType::F16);
+        assert_eq!(estimate.weights, 3 * 256 * 256 * 2);
+        // 2 layers * 1024 tokens * 2 kv heads * (32 + 32) * 2 bytes
+        assert_eq!(estimate.kv_cache, 2 * 1024 * 2 * 64 * 2);
+        assert_eq!(gguf.kv_cache_size(1024, GGMLType::Q8_0), 2 * ((1024 * 2 * 64) / 32) * 34);
+        assert_eq!(estimate.compute_buffer, 4 * 512 * (1000 + 4 * 256 + 1024 * 8));
+        assert_eq!(estimate.total(), estimate.weights + estimate.kv_cache + estimate.compute_buffer);
+
+        let layer = gguf.layer_size(0) + gguf.kv_cache_layer_size(1024, GGMLType::F16);
+        let budget = estimate.compute_buffer + layer + layer / 2;
+        assert_eq!(gguf.layers_fitting(budget, 1024, GGMLType::F16), 1);
+        assert_eq!(gguf.layers_fitting(u64::MAX, 1024, GGMLType::F16), 2);
+        assert_eq!(gguf.layers_fitting(0, 1024, GGMLType::F16), 0);
+        assert_eq!(gguf.layer_tensor_count(), 2);
+
+        // A huge block_count without layer tensors nor metadata must not spin
+        let mut gguf = GGUF::new("empty");
+        gguf.set_metadata("general.architecture", GGUFMetadataValue::String("llama".to_string()));
+        gguf.set_metadata("llama.block_count", GGUFMetadataValue::Uint64(u64::MAX));
+        assert_eq!(gguf.layers_fitting(u64::MAX, 1024, GGMLType::F16), 0);
+        gguf.set_metadata("llama.embedding_length", GGUFMetadataValue::Uint64(u64::MAX));
+        gguf.set_metadata("llama.attention.head_count", GGUFMetadataValue::Uint64(1));
+        assert_eq!(gguf.kv_cache_size(u64::MAX, GGMLType::F16), u64::MAX);
+        assert_eq!(gguf.estimate_memory(u64::MAX, GGMLType::F16).total(), u64::MAX);
+    }
+
+    #[test]
+    fn test_validate() {
+        let buffer = TestFile::new(3, GGUFByteOrder::LittleEndian).build();
+        let size = buffer.len() as u64;
+        let mut gguf = GGUF::new("validate");
+        gguf.read_from(&buffer[..], Some(size), GGUFLimits::default(), &mut ()).unwrap();
+        assert_eq!(gguf.validate(size), vec![
+            GGUFValidationIssue::MissingKey { key: "llama.context_length".to_string() },
+            GGUFValidationIssue::MissingKey { key: "llama.embedding_length".to_string() },
+            GGUFValidationIssue::MissingKey { key: "llama.block_count".to_string() }
+        ]);
+
+        gguf.set_metadata("llama.context_length", GGUFMetadataValue::Uint32(4096));
+        gguf.set_metadata("llama.embedding_length", GGUFMetadataValue::Uint32(64));
+        gguf.set_metadata("llama.block_count", GGUFMetadataValue::Uint32(1));
+        assert_eq!(gguf.validate(size), vec![]);
+
+        // Reading again replaces the metadata and tensors instead of appending them
+        gguf.read_from(&buffer[..], Some(size), GGUFLimits::default(), &mut ()).unwrap();
+        assert_eq!(gguf.header.metadata_kv.len(), 2);
+        assert_eq!(gguf.tensors.len(), 2);
+        assert_eq!(gguf.validate(size).len(), 3);
+        gguf.set_metadata("llama.context_length", GGUFMetadataValue::Uint32(4096));
+        gguf.set_metadata("llama.embedding_length", GGUFMetadataValue::Uint32(64));
+        gguf.set_metadata("llama.block_count", GGUFMetadataValue::Uint32(1));
+
+        gguf.header.metadata_kv.push(gguf.header.metadata_kv[0].clone());
+        gguf.tensors[1].offset = 100;
+        let issues = gguf.validate(size - 200);
+        assert_eq!(issues, vec![
+            GGUFValidationIssue::DuplicateKey { key: "general.architecture".to_string() },
+            GGUFValidationIssue::MisalignedTensor {
+                name: "output_norm.weight".to_string(),
+                offset: 100,
+                alignment: 64,
+            },
+            GGUFValidationIssue::TensorOutOfBounds {
+                name: "output_norm.weight".to_string(),
+                end: gguf.data_offset + 356,
+                file_size: size - 200,
+            },
+            GGUFValidationIssue::OverlappingTensors {
+                name: "token_embd.weight".to_string(),
+                other: "output_norm.weight".to_string(),
+            },
+            GGUFValidationIssue::SizeMismatch {
+                expected: gguf.data_offset + 356,
+                file_size: size - 200,
+            }
+        ]);
+
+        // Sizes overflowing a u64 are reported instead of wrapping around
+        gguf.header.metadata_kv.pop();
+        gguf.tensors[1].offset = 0;
+        gguf.tensors[0].dimensions = vec![u64::MAX, 2];
+        assert_eq!(gguf.tensors[0].checked_size(), None);
+        assert_eq!(gguf.data_size(), u64::MAX);
+        let issues = gguf.validate(size);
+        assert!(
+            issues.contains(
+                &(GGUFValidationIssue::TensorOutOfBounds {
+                    name: "token_embd.weight".to_string(),
+                    end: u64::MAX,
+                    file_size: size,
+                })
+            )
+        );
+        gguf.tensors[0].dimensions = vec![1 << 32, 1 << 31];
+        gguf.tensors[0].offset = u64::MAX - 64;
+        assert!(
+            gguf
+                .validate(size)
+                .iter()
+                .any(|issue| matches!(issue, GGUFValidationIssue::TensorOutOfBounds { .. }))
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let buffer = TestFile::new(3, GGUFByteOrder::LittleEndian).build();
+        let size = buffer.len() as u64;
+        let mut a = GGUF::new("a");
+        a.read_from(&buffer[..], Some(size), GGUFLimits::default(), &mut ()).unwrap();
+        let mut b = a.clone();
+        assert_eq!(a.diff(&b), vec![]);
+
+        b.set_metadata("general.name", GGUFMetadataValue::String("Opla".to_string()));
+        b.set_metadata("general.architecture", GGUFMetadataValue::String("phi3".to_string()));
+        b.tensors[0].tensor_type = GGMLType::Q4_0;
+        b.tensors.remove(1);
+        let differences: Vec<String> = a
+            .diff(&b)
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(differences, vec![
+            "~ tensor_count: 2 -> 1",
+            "~ general.architecture: llama -> phi3",
+            "+ general.name=Opla",
+            "~ tensor token_embd.weight: Q8_0 [64, 2] -> Q4_0 [64, 2]",
+            "- tensor output_norm.weight F32 [64]"
+        ]);
+        assert!(matches!(a.diff(&b)[2], GGUFDifference::MetadataAdded { .. }));
+    }
+
+    #[test]
+    fn test_write() {
+        let buffer = TestFile::new(3, GGUFByteOrder::LittleEndian).build();
+        let mut data = buffer.clone();
+        let size = data.len();
+        data[size - 1] = 42;
+        let path = write_test_file("opla_core_test_write.gguf", data.clone());
+        let file_name = path.to_str().unwrap();
+        // The data is copied from the file that was read, not from the name given to new
+        let mut gguf = GGUF::new("opla_core_test_write");
+        gguf.read(file_name).unwrap();
+        assert_eq!(gguf.file_name, file_name);
+
+        // Without changes the file is written back unchanged
+        let copy = std::env::temp_dir().join("opla_core_test_write_copy.gguf");
+        let copy_name = copy.to_str().unwrap();
+        gguf.write(copy_name).unwrap();
+        assert_eq!(fs::read(&copy).unwrap(), data);
+
+        gguf.set_metadata("general.name", GGUFMetadataValue::String("Opla".to_string()));
+        gguf.set_metadata("general.architecture", GGUFMetadataValue::String("phi3".to_string()));
+        gguf.set_metadata("opla.tags", GGUFMetadataValue::Array(GGUFMetadataArrayValue {
+            value_type: GGUFMetadataValueType::String,
+            len: 2,
+            value: vec![
+                GGUFMetadataValue::String("chat".to_string()),
+                GGUFMetadataValue::String("test".to_string())
+            ],
+        }));
+        gguf.write(file_name).unwrap();
+        assert!(!std::path::Path::new(&format!("{}.tmp", file_name)).exists());
+
+        let mut edited = GGUF::new(file_name);
+        edited.read(file_name).unwrap();
+        let written = fs::read(&path).unwrap();
+
+        assert_eq!(edited.header.metadata_kv_count, 4);
+        assert_eq!(edited.get_metadata("general.name").unwrap().to_string(), "Opla");
+        assert_eq!(edited.get_metadata("general.architecture").unwrap().to_string(), "phi3");
+        assert_eq!(edited.get_metadata("opla.tags").unwrap().to_string(), "[:2]");
+        assert_eq!(edited.alignment, 64);
+        assert_eq!(edited.tensors[1].offset, 192);
+        assert_eq!(
+            &written[edited.data_offset as usize..],
+            &data[gguf.data_offset as usize..]
+        );
+
+        // The padding follows the alignment set in the metadata
+        edited.set_metadata("general.alignment", GGUFMetadataValue::Uint32(32));
+        assert_eq!(edited.alignment, 32);
+        edited.write(copy_name).unwrap();
+        let mut realigned = GGUF::new(copy_name);
+        realigned.read(copy_name).unwrap();
+        let realigned_data = fs::read(&copy).unwrap();
+        assert_eq!(realigned.alignment, 32);
+        assert!(
+            realigned
+                .validate(realigned_data.len() as u64)
+                .iter()
+                .all(|issue| matches!(issue, GGUFValidationIssue::MissingKey { .. }))
+        );
+        assert_eq!(
+            &realigned_data[realigned.data_offset as usize..],
+            &data[gguf.data_offset as usize..]
+        );
+        edited.set_metadata("general.alignment", GGUFMetadataValue::Uint32(64));
+        edited.remove_metadata("general.alignment");
+        assert_eq!(edited.alignment, GGUF_DEFAULT_ALIGNMENT);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&copy).unwrap();
+    }
+}
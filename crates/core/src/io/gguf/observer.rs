@@ -0,0 +1,62 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{ GGUFHeader, GGUFMetadata, GGUFTensorInfo };
+
+// Notified while a GGUF file is parsed, parsing itself never prints anything.
+// All methods do nothing by default.
+pub trait GGUFObserver {
+    // Called once the version and the counts are read, before the metadata.
+    fn on_header(&mut self, _path: &str, _header: &GGUFHeader) {}
+
+    fn on_metadata(&mut self, _metadata: &GGUFMetadata) {}
+
+    fn on_tensor_info(&mut self, _tensor: &GGUFTensorInfo) {}
+}
+
+// No-op observer, used by GGUF::read.
+impl GGUFObserver for () {}
+
+// Forwards parsing events to `tracing` at debug level.
+#[cfg(feature = "tracing")]
+#[derive(Clone, Debug, Default)]
+pub struct GGUFTracingObserver;
+
+#[cfg(feature = "tracing")]
+impl GGUFObserver for GGUFTracingObserver {
+    fn on_header(&mut self, path: &str, header: &GGUFHeader) {
+        tracing::debug!(
+            path,
+            version = header.version,
+            byte_order = ?header.byte_order,
+            tensor_count = header.tensor_count,
+            metadata_kv_count = header.metadata_kv_count,
+            "Reading GGUF file"
+        );
+    }
+
+    fn on_metadata(&mut self, metadata: &GGUFMetadata) {
+        tracing::debug!(key = metadata.key, value = %metadata.value, "GGUF metadata");
+    }
+
+    fn on_tensor_info(&mut self, tensor: &GGUFTensorInfo) {
+        tracing::trace!(
+            name = tensor.name,
+            dimensions = ?tensor.dimensions,
+            tensor_type = %tensor.tensor_type,
+            offset = tensor.offset,
+            "GGUF tensor"
+        );
+    }
+}
@@ -11,3 +11,208 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+
+use std::io::{ ErrorKind, Read };
+use serde::{ Deserialize, Serialize };
+
+use crate::{ Error, Result };
+use super::GGUFMetadataValueType;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GGUFByteOrder {
+    #[default]
+    LittleEndian,
+    BigEndian,
+}
+
+// Limits applied while parsing, to avoid a corrupted or malicious file exhausting memory.
+#[derive(Clone, Debug)]
+pub struct GGUFLimits {
+    pub max_string_length: u64,
+    pub max_array_length: u64,
+    pub max_depth: u32,
+    pub max_metadata_kv_count: u64,
+    pub max_tensor_count: u64,
+    pub max_tensor_dimensions: u32,
+}
+
+impl Default for GGUFLimits {
+    fn default() -> Self {
+        GGUFLimits {
+            // Chat templates are the largest strings, a few KB in practice
+            max_string_length: 16 * 1024 * 1024,
+            // Vocabularies and merges are the largest arrays, a few hundred thousands in practice
+            max_array_length: 16 * 1024 * 1024,
+            max_depth: 8,
+            max_metadata_kv_count: 64 * 1024,
+            max_tensor_count: 1024 * 1024,
+            // GGML_MAX_DIMS
+            max_tensor_dimensions: 4,
+        }
+    }
+}
+
+macro_rules! read_number {
+    ($name:ident, $type:ty) => {
+        pub fn $name(&mut self) -> Result<$type> {
+            let mut value = [0; std::mem::size_of::<$type>()];
+            self.read_exact(&mut value)?;
+            Ok(match self.byte_order {
+                GGUFByteOrder::LittleEndian => <$type>::from_le_bytes(value),
+                GGUFByteOrder::BigEndian => <$type>::from_be_bytes(value),
+            })
+        }
+    };
+}
+
+// Reads GGUF primitives taking into account the byte order and the version of the file.
+// GGUF v1 uses u32 for lengths and counts, later versions use u64.
+pub struct GGUFParser<R: Read> {
+    reader: R,
+    position: u64,
+    // Total size of the input if known, used to reject lengths bigger than the remaining bytes.
+    size: Option<u64>,
+    pub limits: GGUFLimits,
+    pub byte_order: GGUFByteOrder,
+    pub version: u32,
+}
+
+impl<R: Read> GGUFParser<R> {
+    pub fn new(reader: R, size: Option<u64>, limits: GGUFLimits) -> Self {
+        GGUFParser {
+            reader,
+            position: 0,
+            size,
+            limits,
+            byte_order: GGUFByteOrder::LittleEndian,
+            version: 0,
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn remaining(&self) -> Option<u64> {
+        self.size.map(|size| size.saturating_sub(self.position))
+    }
+
+    // Checks that at least `needed` bytes are still available before allocating for them.
+    pub fn ensure_available(&self, needed: u64) -> Result<()> {
+        match self.remaining() {
+            Some(remaining) if needed > remaining => {
+                Err(Error::Truncated { needed, remaining, offset: self.position, key: None })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Checks a length or a count read at `offset` against one of the limits.
+    pub fn check_limit(
+        &self,
+        name: &'static str,
+        offset: u64,
+        value: impl Into<u64>,
+        max: impl Into<u64>
+    ) -> Result<()> {
+        let (value, max) = (value.into(), max.into());
+        if value > max {
+            return Err(Error::LimitExceeded { name, value, max, offset, key: None });
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let offset = self.position;
+        self.reader.read_exact(buffer).map_err(|err| {
+            if err.kind() == ErrorKind::UnexpectedEof {
+                Error::Truncated {
+                    needed: buffer.len() as u64,
+                    remaining: self.remaining().unwrap_or(0),
+                    offset,
+                    key: None,
+                }
+            } else {
+                Error::Io { offset, source: err }
+            }
+        })?;
+        self.position += buffer.len() as u64;
+        Ok(())
+    }
+
+    read_number!(read_u8, u8);
+    read_number!(read_i8, i8);
+    read_number!(read_u16, u16);
+    read_number!(read_i16, i16);
+    read_number!(read_u32, u32);
+    read_number!(read_i32, i32);
+    read_number!(read_f32, f32);
+    read_number!(read_u64, u64);
+    read_number!(read_i64, i64);
+    read_number!(read_f64, f64);
+
+    pub fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    // Lengths of strings and arrays, and header counts.
+    pub fn read_length(&mut self) -> Result<u64> {
+        if self.version == 1 {
+            return Ok(self.read_u32()? as u64);
+        }
+        self.read_u64()
+    }
+
+    // Size in bytes of a length, the smallest possible size of a string.
+    pub fn length_size(&self) -> u64 {
+        if self.version == 1 { 4 } else { 8 }
+    }
+
+    // Tensor dimensions were stored as u32 in GGUF v1.
+    pub fn read_dimension(&mut self) -> Result<u64> {
+        self.read_length()
+    }
+
+    pub fn read_string(&mut self) -> Result<String> {
+        let offset = self.position;
+        let length = self.read_length()?;
+        self.check_limit("string length", offset, length, self.limits.max_string_length)?;
+        self.ensure_available(length)?;
+        let mut value = vec![0; length as usize];
+        self.read_exact(&mut value)?;
+        String::from_utf8(value).map_err(|_| Error::InvalidUtf8 { offset, key: None })
+    }
+
+    pub fn read_value_type(&mut self) -> Result<GGUFMetadataValueType> {
+        let offset = self.position;
+        let value_type = self.read_u32()?;
+        GGUFMetadataValueType::from_u32(value_type).ok_or(Error::InvalidValueType {
+            value_type,
+            offset,
+            key: None,
+        })
+    }
+
+    // Reads the magic and the version, detecting the byte order of the file.
+    pub fn read_magic_and_version(&mut self) -> Result<u32> {
+        let mut magic = [0; 4];
+        self.read_exact(&mut magic)?;
+        if &magic != b"GGUF" {
+            return Err(Error::InvalidMagic(magic));
+        }
+
+        let mut version = [0; 4];
+        self.read_exact(&mut version)?;
+        let mut value = u32::from_le_bytes(version);
+        // A big-endian file has its version bytes swapped, so the low bytes are empty.
+        if value & 0xffff == 0 {
+            self.byte_order = GGUFByteOrder::BigEndian;
+            value = u32::from_be_bytes(version);
+        }
+        if !(1..=3).contains(&value) {
+            return Err(Error::UnsupportedVersion(value));
+        }
+        self.version = value;
+        Ok(value)
+    }
+}
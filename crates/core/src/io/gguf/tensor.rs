@@ -0,0 +1,210 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::{ self, Display, Formatter };
+use serde::{ Deserialize, Serialize };
+
+// See https://github.com/ggerganov/ggml/blob/master/include/ggml.h enum ggml_type
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GGMLType {
+    F32 = 0,
+    F16 = 1,
+    Q4_0 = 2,
+    Q4_1 = 3,
+    // 4 and 5 were Q4_2 and Q4_3, removed from ggml
+    Q5_0 = 6,
+    Q5_1 = 7,
+    Q8_0 = 8,
+    Q8_1 = 9,
+    Q2_K = 10,
+    Q3_K = 11,
+    Q4_K = 12,
+    Q5_K = 13,
+    Q6_K = 14,
+    Q8_K = 15,
+    IQ2_XXS = 16,
+    IQ2_XS = 17,
+    IQ3_XXS = 18,
+    IQ1_S = 19,
+    IQ4_NL = 20,
+    IQ3_S = 21,
+    IQ2_S = 22,
+    IQ4_XS = 23,
+    I8 = 24,
+    I16 = 25,
+    I32 = 26,
+    I64 = 27,
+    F64 = 28,
+    IQ1_M = 29,
+    BF16 = 30,
+    Q4_0_4_4 = 31,
+    Q4_0_4_8 = 32,
+    Q4_0_8_8 = 33,
+    TQ1_0 = 34,
+    TQ2_0 = 35,
+}
+
+impl GGMLType {
+    // Number of elements stored in one block.
+    pub fn block_size(&self) -> u64 {
+        match self {
+            GGMLType::F32 |
+            GGMLType::F16 |
+            GGMLType::BF16 |
+            GGMLType::F64 |
+            GGMLType::I8 |
+            GGMLType::I16 |
+            GGMLType::I32 |
+            GGMLType::I64 => 1,
+            GGMLType::Q4_0 |
+            GGMLType::Q4_1 |
+            GGMLType::Q5_0 |
+            GGMLType::Q5_1 |
+            GGMLType::Q8_0 |
+            GGMLType::Q8_1 |
+            GGMLType::IQ4_NL |
+            GGMLType::Q4_0_4_4 |
+            GGMLType::Q4_0_4_8 |
+            GGMLType::Q4_0_8_8 => 32,
+            _ => 256,
+        }
+    }
+
+    // Number of bytes used to store one block.
+    pub fn type_size(&self) -> u64 {
+        match self {
+            GGMLType::F32 => 4,
+            GGMLType::F16 => 2,
+            GGMLType::Q4_0 => 18,
+            GGMLType::Q4_1 => 20,
+            GGMLType::Q5_0 => 22,
+            GGMLType::Q5_1 => 24,
+            GGMLType::Q8_0 => 34,
+            GGMLType::Q8_1 => 36,
+            GGMLType::Q2_K => 84,
+            GGMLType::Q3_K => 110,
+            GGMLType::Q4_K => 144,
+            GGMLType::Q5_K => 176,
+            GGMLType::Q6_K => 210,
+            GGMLType::Q8_K => 292,
+            GGMLType::IQ2_XXS => 66,
+            GGMLType::IQ2_XS => 74,
+            GGMLType::IQ3_XXS => 98,
+            GGMLType::IQ1_S => 50,
+            GGMLType::IQ4_NL => 18,
+            GGMLType::IQ3_S => 110,
+            GGMLType::IQ2_S => 82,
+            GGMLType::IQ4_XS => 136,
+            GGMLType::I8 => 1,
+            GGMLType::I16 => 2,
+            GGMLType::I32 => 4,
+            GGMLType::I64 => 8,
+            GGMLType::F64 => 8,
+            GGMLType::IQ1_M => 56,
+            GGMLType::BF16 => 2,
+            GGMLType::Q4_0_4_4 | GGMLType::Q4_0_4_8 | GGMLType::Q4_0_8_8 => 18,
+            GGMLType::TQ1_0 => 54,
+            GGMLType::TQ2_0 => 66,
+        }
+    }
+}
+
+impl GGMLType {
+    pub fn from_u32(item: u32) -> Option<Self> {
+        Some(match item {
+            0 => GGMLType::F32,
+            1 => GGMLType::F16,
+            2 => GGMLType::Q4_0,
+            3 => GGMLType::Q4_1,
+            6 => GGMLType::Q5_0,
+            7 => GGMLType::Q5_1,
+            8 => GGMLType::Q8_0,
+            9 => GGMLType::Q8_1,
+            10 => GGMLType::Q2_K,
+            11 => GGMLType::Q3_K,
+            12 => GGMLType::Q4_K,
+            13 => GGMLType::Q5_K,
+            14 => GGMLType::Q6_K,
+            15 => GGMLType::Q8_K,
+            16 => GGMLType::IQ2_XXS,
+            17 => GGMLType::IQ2_XS,
+            18 => GGMLType::IQ3_XXS,
+            19 => GGMLType::IQ1_S,
+            20 => GGMLType::IQ4_NL,
+            21 => GGMLType::IQ3_S,
+            22 => GGMLType::IQ2_S,
+            23 => GGMLType::IQ4_XS,
+            24 => GGMLType::I8,
+            25 => GGMLType::I16,
+            26 => GGMLType::I32,
+            27 => GGMLType::I64,
+            28 => GGMLType::F64,
+            29 => GGMLType::IQ1_M,
+            30 => GGMLType::BF16,
+            31 => GGMLType::Q4_0_4_4,
+            32 => GGMLType::Q4_0_4_8,
+            33 => GGMLType::Q4_0_8_8,
+            34 => GGMLType::TQ1_0,
+            35 => GGMLType::TQ2_0,
+            _ => {
+                return None;
+            }
+        })
+    }
+}
+
+impl Display for GGMLType {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        fmt.write_str(&format!("{:?}", self))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GGUFTensorInfo {
+    pub name: String,
+    pub n_dimensions: u32,
+    pub dimensions: Vec<u64>,
+    pub tensor_type: GGMLType,
+    // Offset relative to the start of the tensor data section, a multiple of the alignment.
+    pub offset: u64,
+}
+
+impl GGUFTensorInfo {
+    // None when the dimensions read from the file overflow a u64.
+    pub fn checked_element_count(&self) -> Option<u64> {
+        self.dimensions.iter().try_fold(1u64, |count, &dimension| count.checked_mul(dimension))
+    }
+
+    pub fn element_count(&self) -> u64 {
+        self.checked_element_count().unwrap_or(u64::MAX)
+    }
+
+    // Size in bytes of the tensor data, None when it overflows a u64.
+    pub fn checked_size(&self) -> Option<u64> {
+        (self.checked_element_count()? / self.tensor_type.block_size()).checked_mul(
+            self.tensor_type.type_size()
+        )
+    }
+
+    // Size in bytes of the tensor data, saturated to u64::MAX on overflow.
+    pub fn size(&self) -> u64 {
+        self.checked_size().unwrap_or(u64::MAX)
+    }
+
+    // End of the tensor data relative to the start of the data section.
+    pub fn checked_end(&self) -> Option<u64> {
+        self.offset.checked_add(self.checked_size()?)
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{ collections::HashSet, fmt::{ self, Display, Formatter } };
+use serde::{ Deserialize, Serialize };
+
+use super::GGUF;
+
+// Keys every model file must have, {arch} is replaced by general.architecture.
+pub const REQUIRED_KEYS: [&str; 4] = [
+    "general.architecture",
+    "{arch}.context_length",
+    "{arch}.embedding_length",
+    "{arch}.block_count",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "issue", rename_all = "snake_case")]
+pub enum GGUFValidationIssue {
+    MisalignedTensor {
+        name: String,
+        offset: u64,
+        alignment: u64,
+    },
+    TensorOutOfBounds {
+        name: String,
+        end: u64,
+        file_size: u64,
+    },
+    OverlappingTensors {
+        name: String,
+        other: String,
+    },
+    SizeMismatch {
+        expected: u64,
+        file_size: u64,
+    },
+    DuplicateKey {
+        key: String,
+    },
+    DuplicateTensor {
+        name: String,
+    },
+    MissingKey {
+        key: String,
+    },
+}
+
+impl Display for GGUFValidationIssue {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self {
+            GGUFValidationIssue::MisalignedTensor { name, offset, alignment } =>
+                write!(fmt, "tensor {} offset {} is not aligned on {}", name, offset, alignment),
+            GGUFValidationIssue::TensorOutOfBounds { name, end, file_size } =>
+                write!(fmt, "tensor {} ends at {} after the end of file {}", name, end, file_size),
+            GGUFValidationIssue::OverlappingTensors { name, other } =>
+                write!(fmt, "tensor {} overlaps tensor {}", name, other),
+            GGUFValidationIssue::SizeMismatch { expected, file_size } =>
+                write!(fmt, "file size is {} but tensors end at {}", file_size, expected),
+            GGUFValidationIssue::DuplicateKey { key } => write!(fmt, "duplicate key {}", key),
+            GGUFValidationIssue::DuplicateTensor { name } =>
+                write!(fmt, "duplicate tensor {}", name),
+            GGUFValidationIssue::MissingKey { key } => write!(fmt, "missing key {}", key),
+        }
+    }
+}
+
+impl GGUF {
+    // Checks the consistency of a file that was read, the magic and the version
+    // are already checked by the reader. `file_size` is the size of the file on disk.
+    pub fn validate(&self, file_size: u64) -> Vec<GGUFValidationIssue> {
+        let mut issues = Vec::new();
+
+        let mut keys = HashSet::new();
+        for metadata in &self.header.metadata_kv {
+            if !keys.insert(metadata.key.as_str()) {
+                issues.push(GGUFValidationIssue::DuplicateKey { key: metadata.key.clone() });
+            }
+        }
+        let architecture = self.architecture().unwrap_or("");
+        for key in REQUIRED_KEYS {
+            let key = key.replace("{arch}", architecture);
+            if !keys.contains(key.as_str()) {
+                issues.push(GGUFValidationIssue::MissingKey { key });
+            }
+        }
+
+        let mut names = HashSet::new();
+        for tensor in &self.tensors {
+            if !names.insert(tensor.name.as_str()) {
+                issues.push(GGUFValidationIssue::DuplicateTensor { name: tensor.name.clone() });
+            }
+            if tensor.offset % self.alignment != 0 {
+                issues.push(GGUFValidationIssue::MisalignedTensor {
+                    name: tensor.name.clone(),
+                    offset: tensor.offset,
+                    alignment: self.alignment,
+                });
+            }
+            // An end overflowing a u64 is past any file
+            let end = tensor.checked_end().and_then(|end| end.checked_add(self.data_offset));
+            match end {
+                Some(end) if end <= file_size => {}
+                _ => {
+                    issues.push(GGUFValidationIssue::TensorOutOfBounds {
+                        name: tensor.name.clone(),
+                        end: end.unwrap_or(u64::MAX),
+                        file_size,
+                    });
+                }
+            }
+        }
+
+        let mut tensors: Vec<_> = self.tensors.iter().collect();
+        tensors.sort_by_key(|t| t.offset);
+        for pair in tensors.windows(2) {
+            if pair[0].checked_end().is_none_or(|end| end > pair[1].offset) {
+                issues.push(GGUFValidationIssue::OverlappingTensors {
+                    name: pair[0].name.clone(),
+                    other: pair[1].name.clone(),
+                });
+            }
+        }
+
+        // The last tensor may be followed by padding up to the alignment
+        let expected = self.data_offset.saturating_add(self.data_size());
+        if file_size < expected || file_size >= expected.saturating_add(self.alignment) {
+            issues.push(GGUFValidationIssue::SizeMismatch { expected, file_size });
+        }
+
+        issues
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{ self, Read, Write };
+
+use crate::{ Error, Result };
+use super::{ GGUFByteOrder, GGUFMetadataValue, GGUFTensorInfo, GGUF };
+
+macro_rules! write_number {
+    ($name:ident, $type:ty) => {
+        pub fn $name(&mut self, value: $type) -> Result<()> {
+            let bytes = match self.byte_order {
+                GGUFByteOrder::LittleEndian => value.to_le_bytes(),
+                GGUFByteOrder::BigEndian => value.to_be_bytes(),
+            };
+            self.write_all(&bytes)
+        }
+    };
+}
+
+// Writes GGUF primitives with the byte order and the version of the file,
+// the counterpart of GGUFParser.
+pub struct GGUFWriter<W: Write> {
+    writer: W,
+    position: u64,
+    pub byte_order: GGUFByteOrder,
+    pub version: u32,
+}
+
+impl<W: Write> GGUFWriter<W> {
+    pub fn new(writer: W, byte_order: GGUFByteOrder, version: u32) -> Self {
+        GGUFWriter {
+            writer,
+            position: 0,
+            byte_order,
+            version,
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_all(&mut self, buffer: &[u8]) -> Result<()> {
+        self.writer
+            .write_all(buffer)
+            .map_err(|err| Error::Io { offset: self.position, source: err })?;
+        self.position += buffer.len() as u64;
+        Ok(())
+    }
+
+    write_number!(write_u8, u8);
+    write_number!(write_i8, i8);
+    write_number!(write_u16, u16);
+    write_number!(write_i16, i16);
+    write_number!(write_u32, u32);
+    write_number!(write_i32, i32);
+    write_number!(write_f32, f32);
+    write_number!(write_u64, u64);
+    write_number!(write_i64, i64);
+    write_number!(write_f64, f64);
+
+    pub fn write_bool(&mut self, value: bool) -> Result<()> {
+        self.write_u8(value as u8)
+    }
+
+    // GGUF v1 uses u32 for lengths and counts.
+    pub fn write_length(&mut self, value: u64) -> Result<()> {
+        if self.version == 1 {
+            return self.write_u32(value as u32);
+        }
+        self.write_u64(value)
+    }
+
+    pub fn write_string(&mut self, value: &str) -> Result<()> {
+        self.write_length(value.len() as u64)?;
+        self.write_all(value.as_bytes())
+    }
+
+    pub fn write_value(&mut self, value: &GGUFMetadataValue) -> Result<()> {
+        match value {
+            GGUFMetadataValue::Uint8(v) => self.write_u8(*v),
+            GGUFMetadataValue::Int8(v) => self.write_i8(*v),
+            GGUFMetadataValue::Uint16(v) => self.write_u16(*v),
+            GGUFMetadataValue::Int16(v) => self.write_i16(*v),
+            GGUFMetadataValue::Uint32(v) => self.write_u32(*v),
+            GGUFMetadataValue::Int32(v) => self.write_i32(*v),
+            GGUFMetadataValue::Float32(v) => self.write_f32(*v),
+            GGUFMetadataValue::Uint64(v) => self.write_u64(*v),
+            GGUFMetadataValue::Int64(v) => self.write_i64(*v),
+            GGUFMetadataValue::Float64(v) => self.write_f64(*v),
+            GGUFMetadataValue::Bool(v) => self.write_bool(*v),
+            GGUFMetadataValue::String(v) => self.write_string(v),
+            GGUFMetadataValue::Array(array) => {
+                self.write_u32(array.value_type.clone() as u32)?;
+                self.write_length(array.value.len() as u64)?;
+                for value in &array.value {
+                    self.write_value(value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn write_tensor_info(&mut self, tensor: &GGUFTensorInfo) -> Result<()> {
+        self.write_string(&tensor.name)?;
+        self.write_u32(tensor.dimensions.len() as u32)?;
+        for dimension in &tensor.dimensions {
+            self.write_length(*dimension)?;
+        }
+        self.write_u32(tensor.tensor_type as u32)?;
+        self.write_u64(tensor.offset)
+    }
+
+    // Writes zeros up to the next multiple of `alignment`.
+    pub fn write_padding(&mut self, alignment: u64) -> Result<()> {
+        let padding = self.position.div_ceil(alignment) * alignment - self.position;
+        self.write_all(&vec![0; padding as usize])
+    }
+
+    // Writes everything up to the tensor data: magic, version, counts, metadata and tensors info.
+    // The counts are taken from the metadata and tensors, not from the header.
+    pub fn write_header(&mut self, gguf: &GGUF) -> Result<()> {
+        self.write_all(b"GGUF")?;
+        self.write_u32(self.version)?;
+        self.write_length(gguf.tensors.len() as u64)?;
+        self.write_length(gguf.header.metadata_kv.len() as u64)?;
+
+        for metadata in &gguf.header.metadata_kv {
+            self.write_string(&metadata.key)?;
+            self.write_u32(metadata.value_type.clone() as u32)?;
+            self.write_value(&metadata.value)?;
+        }
+
+        for tensor in &gguf.tensors {
+            self.write_tensor_info(tensor)?;
+        }
+
+        self.write_padding(gguf.alignment)
+    }
+
+    // Streams the tensor data through unchanged, the reader must be at the start of the data.
+    pub fn write_tensor_data<R: Read>(&mut self, reader: &mut R, size: u64) -> Result<()> {
+        let offset = self.position;
+        let copied = io::copy(&mut reader.take(size), &mut self.writer).map_err(|err| Error::Io { offset, source: err })?;
+        self.position += copied;
+        if copied < size {
+            return Err(Error::Truncated {
+                needed: size,
+                remaining: copied,
+                offset,
+                key: None,
+            });
+        }
+        Ok(())
+    }
+}
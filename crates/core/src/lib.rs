@@ -12,5 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod error;
 mod io;
+pub use error::{ Error, Result };
 pub use io::gguf;
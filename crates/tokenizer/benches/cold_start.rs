@@ -0,0 +1,46 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Latency of the first cl100k_base tokenization, with the ranks parsed from the .tiktoken file
+// as before, and with the ranks precompiled at build time.
+
+use criterion::{ black_box, criterion_group, criterion_main, Criterion };
+use tokenizer::{ cl100k_base, load_bpe, CL100K_PATTERN };
+
+const TEXT: &str = "This is a test with a lot of spaces and an emoji 🌍";
+
+fn cold_start(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cl100k_base cold start");
+    group.sample_size(10);
+    group.bench_function("parse .tiktoken", |b| {
+        b.iter(|| {
+            let bpe = load_bpe(
+                include_str!("../encodings/cl100k_base.tiktoken"),
+                &[("<|endoftext|>", 100257)],
+                CL100K_PATTERN
+            ).unwrap();
+            black_box(bpe.encode_ordinary(TEXT))
+        })
+    });
+    group.bench_function("precompiled", |b| {
+        b.iter(|| {
+            let bpe = cl100k_base().unwrap();
+            black_box(bpe.encode_ordinary(TEXT))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, cold_start);
+criterion_main!(benches);
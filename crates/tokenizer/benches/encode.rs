@@ -0,0 +1,48 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Steady state cl100k_base tokenization, every BPE merge looks up a rank: with the ranks
+// parsed from the .tiktoken file in a HashMap, and with the precompiled perfect hash table.
+
+use criterion::{ black_box, criterion_group, criterion_main, Criterion, Throughput };
+use tokenizer::{ cl100k_base, load_bpe, CL100K_PATTERN };
+
+const PROSE: &str =
+    "The quick brown fox jumps over the lazy dog. Tokenizers split the text in pieces with a \
+regular expression, then merge the bytes of each piece by rank until no pair is left. \
+Unusual words like antidisestablishmentarianism or floccinaucinihilipilification need \
+many merges, while 🌍🚀 emojis and numbers such as 3.14159 or 2024-10-16 are split early. ";
+const CODE: &str = include_str!("../src/vendors/tiktoken.rs");
+
+fn encode(c: &mut Criterion) {
+    let map = load_bpe(
+        include_str!("../encodings/cl100k_base.tiktoken"),
+        &[("<|endoftext|>", 100257)],
+        CL100K_PATTERN
+    ).unwrap();
+    let precompiled = cl100k_base().unwrap();
+    let prose = PROSE.repeat(20);
+    for (name, text) in [("prose", prose.as_str()), ("code", CODE)] {
+        let mut group = c.benchmark_group(format!("cl100k_base encode_ordinary {}", name));
+        group.throughput(Throughput::Bytes(text.len() as u64));
+        group.bench_function("HashMap", |b| b.iter(|| black_box(map.encode_ordinary(text))));
+        group.bench_function("precompiled", |b| {
+            b.iter(|| black_box(precompiled.encode_ordinary(text)))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
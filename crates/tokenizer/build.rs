@@ -0,0 +1,193 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Precompiles the ranks of the tiktoken encodings, so they are not parsed at startup.
+// Each encoding is stored as its token bytes in rank order, and a perfect hash table
+// from the token bytes to their rank, see src/ranks.rs.
+
+use std::{ cmp::Reverse, env, fs, path::Path };
+use base64::{ engine::general_purpose, Engine as _ };
+
+#[path = "src/ranks_hash.rs"]
+mod ranks_hash;
+
+// Average number of keys per displacement bucket
+const LAMBDA: usize = 5;
+
+const ENCODINGS: &[(&str, &str)] = &[
+    ("R50K_BASE_RANKS", "r50k_base"),
+    ("P50K_BASE_RANKS", "p50k_base"),
+    ("CL100K_BASE_RANKS", "cl100k_base"),
+    ("O200K_BASE_RANKS", "o200k_base"),
+];
+
+fn to_le_bytes(values: impl Iterator<Item = u32>) -> Vec<u8> {
+    values.flat_map(|value| value.to_le_bytes()).collect()
+}
+
+struct HashState {
+    key: u64,
+    disps: Vec<(u32, u32)>,
+    // Index of the key of each slot
+    map: Vec<usize>,
+}
+
+// CHD perfect hash, like phf_generator but with the hash of src/ranks_hash.rs.
+// The keys are placed by bucket, the largest buckets first, each bucket is given
+// the first displacements putting all its keys in free slots.
+fn try_generate_hash(keys: &[&[u8]], key: u64) -> Option<HashState> {
+    let hashes: Vec<_> = keys
+        .iter()
+        .map(|k| ranks_hash::hash(k, key))
+        .collect();
+    let buckets_len = keys.len().div_ceil(LAMBDA);
+    let mut buckets: Vec<(usize, Vec<usize>)> = (0..buckets_len).map(|i| (i, vec![])).collect();
+    for (index, (g, _, _)) in hashes.iter().enumerate() {
+        buckets[ranks_hash::reduce(*g, buckets_len)].1.push(index);
+    }
+    // Keys with the same displacement hashes can't be placed, another key is tried
+    for (_, bucket_keys) in &buckets {
+        let mut displacements: Vec<_> = bucket_keys
+            .iter()
+            .map(|index| (hashes[*index].1, hashes[*index].2))
+            .collect();
+        displacements.sort_unstable();
+        if displacements.windows(2).any(|pair| pair[0] == pair[1]) {
+            return None;
+        }
+    }
+    buckets.sort_by_key(|(_, bucket_keys)| Reverse(bucket_keys.len()));
+
+    let table_len = keys.len();
+    let mut map: Vec<Option<usize>> = vec![None; table_len];
+    let mut disps = vec![(0u32, 0u32); buckets_len];
+    // Slots taken by the current try
+    let mut try_map = vec![0u64; table_len];
+    let mut generation = 0;
+    let mut values_to_add = vec![];
+    'buckets: for (bucket, bucket_keys) in &buckets {
+        for d1 in 0..table_len as u32 {
+            'disps: for d2 in 0..table_len as u32 {
+                values_to_add.clear();
+                generation += 1;
+                for &index in bucket_keys {
+                    let (_, f1, f2) = hashes[index];
+                    let slot = ranks_hash::reduce(ranks_hash::displace(f1, f2, d1, d2), table_len);
+                    if map[slot].is_some() || try_map[slot] == generation {
+                        continue 'disps;
+                    }
+                    try_map[slot] = generation;
+                    values_to_add.push((slot, index));
+                }
+                disps[*bucket] = (d1, d2);
+                for &(slot, index) in &values_to_add {
+                    map[slot] = Some(index);
+                }
+                continue 'buckets;
+            }
+        }
+        return None;
+    }
+    Some(HashState {
+        key,
+        disps,
+        map: map.into_iter().map(|index| index.unwrap()).collect(),
+    })
+}
+
+// The keys are tried in order so the tables are the same on every build.
+fn generate_hash(keys: &[&[u8]]) -> HashState {
+    (0..)
+        .find_map(|key| try_generate_hash(keys, key))
+        .unwrap()
+}
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let out_dir = Path::new(&out_dir);
+    let mut code = String::new();
+    for (name, file) in ENCODINGS {
+        let path = format!("encodings/{}.tiktoken", file);
+        println!("cargo:rerun-if-changed={}", path);
+        let data = fs::read_to_string(&path).unwrap();
+
+        let mut ranks: Vec<(Vec<u8>, u32)> = vec![];
+        for line in data.lines() {
+            let mut parts = line.split(' ');
+            let token = general_purpose::STANDARD.decode(parts.next().unwrap()).unwrap();
+            let rank: u32 = parts.next().unwrap().parse().unwrap();
+            ranks.push((token, rank));
+        }
+        ranks.sort_by_key(|(_, rank)| *rank);
+
+        // Tokens are stored by rank, a missing rank is an empty token
+        let mut tokens: Vec<u8> = vec![];
+        let mut offsets: Vec<u32> = vec![0];
+        for (token, rank) in &ranks {
+            while offsets.len() <= (*rank as usize) {
+                offsets.push(tokens.len() as u32);
+            }
+            tokens.extend(token);
+            offsets.push(tokens.len() as u32);
+        }
+        let keys: Vec<&[u8]> = ranks
+            .iter()
+            .map(|(token, _)| token.as_slice())
+            .collect();
+        let state = generate_hash(&keys);
+        // Each slot holds the rank and the location of its token, the start on 24 bits and
+        // the length on 8 bits, then the bytes of the tokens of at most 8 bytes, to compare
+        // the piece without reading the offsets, and most of the time without the tokens
+        let slots = state.map.iter().flat_map(|index| {
+            let (token, rank) = &ranks[*index];
+            let start = offsets[*rank as usize];
+            assert!(start < 1 << 24 && token.len() < 1 << 8);
+            let word = if token.len() <= 8 { ranks_hash::short_word(token) } else { 0 };
+            [*rank, start | ((token.len() as u32) << 24), word as u32, (word >> 32) as u32]
+        });
+        let map = to_le_bytes(slots);
+
+        fs::write(out_dir.join(format!("{}.tokens", file)), tokens).unwrap();
+        fs::write(
+            out_dir.join(format!("{}.offsets", file)),
+            to_le_bytes(offsets.into_iter())
+        ).unwrap();
+        fs::write(
+            out_dir.join(format!("{}.disps", file)),
+            to_le_bytes(state.disps.iter().flat_map(|(d1, d2)| [*d1, *d2]))
+        ).unwrap();
+        fs::write(
+            out_dir.join(format!("{}.map", file)),
+            map
+        ).unwrap();
+
+        code.push_str(
+            &format!(
+                "pub static {name}: StaticRanks = StaticRanks {{\n\
+                 \x20   key: {key},\n\
+                 \x20   tokens: include_bytes!(concat!(env!(\"OUT_DIR\"), \"/{file}.tokens\")),\n\
+                 \x20   offsets: include_bytes!(concat!(env!(\"OUT_DIR\"), \"/{file}.offsets\")),\n\
+                 \x20   disps: include_bytes!(concat!(env!(\"OUT_DIR\"), \"/{file}.disps\")),\n\
+                 \x20   map: include_bytes!(concat!(env!(\"OUT_DIR\"), \"/{file}.map\")),\n\
+                 }};\n",
+                name = name,
+                key = state.key,
+                file = file
+            )
+        );
+    }
+    fs::write(out_dir.join("ranks.rs"), code).unwrap();
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/ranks_hash.rs");
+}
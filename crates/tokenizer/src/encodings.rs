@@ -19,7 +19,10 @@ use std::collections::HashMap;
 use std::sync::OnceLock;
 use base64::{ engine::general_purpose, Engine as _ };
 
-use crate::vendors::tiktoken::{ CoreBPE, Rank };
+use crate::{
+    ranks::{ StaticRanks, CL100K_BASE_RANKS, O200K_BASE_RANKS, P50K_BASE_RANKS, R50K_BASE_RANKS },
+    vendors::tiktoken::{ CoreBPE, Rank },
+};
 
 pub const ENDOFTEXT: &str = "<|endoftext|>";
 pub const FIM_PREFIX: &str = "<|fim_prefix|>";
@@ -27,11 +30,99 @@ pub const FIM_MIDDLE: &str = "<|fim_middle|>";
 pub const FIM_SUFFIX: &str = "<|fim_suffix|>";
 pub const ENDOFPROMPT: &str = "<|endofprompt|>";
 
-pub fn cl100k_base() -> Result<CoreBPE, anyhow::Error> {
-    let cl100k_base = include_str!("../encodings/cl100k_base.tiktoken");
+pub const R50K_PATTERN: &str =
+    "'s|'t|'re|'ve|'m|'ll|'d| ?\\p{L}+| ?\\p{N}+| ?[^\\s\\p{L}\\p{N}]+|\\s+(?!\\S)|\\s+";
+pub const CL100K_PATTERN: &str =
+    "(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\\r\\n\\p{L}\\p{N}]?\\p{L}+|\\p{N}{1,3}| ?[^\\s\\p{L}\\p{N}]+[\\r\\n]*|\\s*[\\r\\n]+|\\s+(?!\\S)|\\s+";
+pub const O200K_PATTERN: &str = concat!(
+    "[^\\r\\n\\p{L}\\p{N}]?[\\p{Lu}\\p{Lt}\\p{Lm}\\p{Lo}\\p{M}]*[\\p{Ll}\\p{Lm}\\p{Lo}\\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?|",
+    "[^\\r\\n\\p{L}\\p{N}]?[\\p{Lu}\\p{Lt}\\p{Lm}\\p{Lo}\\p{M}]+[\\p{Ll}\\p{Lm}\\p{Lo}\\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?|",
+    "\\p{N}{1,3}| ?[^\\s\\p{L}\\p{N}]+[\\r\\n/]*|\\s*[\\r\\n]+|\\s+(?!\\S)|\\s+"
+);
+
+pub const O200K_BASE: &str = "o200k_base";
+pub const CL100K_BASE: &str = "cl100k_base";
+pub const P50K_BASE: &str = "p50k_base";
+pub const P50K_EDIT: &str = "p50k_edit";
+pub const R50K_BASE: &str = "r50k_base";
+pub const GPT2: &str = "gpt2";
+
+// Encoding of the models, see https://github.com/openai/tiktoken/blob/main/tiktoken/model.py
+const MODEL_TO_ENCODING: &[(&str, &str)] = &[
+    ("o1", O200K_BASE),
+    ("o3", O200K_BASE),
+    ("o4-mini", O200K_BASE),
+    ("gpt-4.1", O200K_BASE),
+    ("gpt-4o", O200K_BASE),
+    ("chatgpt-4o-latest", O200K_BASE),
+    ("gpt-4", CL100K_BASE),
+    ("gpt-3.5-turbo", CL100K_BASE),
+    ("gpt-3.5", CL100K_BASE),
+    ("gpt-35-turbo", CL100K_BASE),
+    ("davinci-002", CL100K_BASE),
+    ("babbage-002", CL100K_BASE),
+    ("text-embedding-ada-002", CL100K_BASE),
+    ("text-embedding-3-small", CL100K_BASE),
+    ("text-embedding-3-large", CL100K_BASE),
+    ("text-davinci-003", P50K_BASE),
+    ("text-davinci-002", P50K_BASE),
+    ("code-davinci-002", P50K_BASE),
+    ("code-davinci-001", P50K_BASE),
+    ("code-cushman-002", P50K_BASE),
+    ("code-cushman-001", P50K_BASE),
+    ("davinci-codex", P50K_BASE),
+    ("cushman-codex", P50K_BASE),
+    ("text-davinci-edit-001", P50K_EDIT),
+    ("code-davinci-edit-001", P50K_EDIT),
+    ("text-davinci-001", R50K_BASE),
+    ("text-curie-001", R50K_BASE),
+    ("text-babbage-001", R50K_BASE),
+    ("text-ada-001", R50K_BASE),
+    ("davinci", R50K_BASE),
+    ("curie", R50K_BASE),
+    ("babbage", R50K_BASE),
+    ("ada", R50K_BASE),
+    ("gpt2", GPT2),
+    ("gpt-2", GPT2),
+];
+
+// Prefixes of the versioned and fine-tuned models, the most specific first.
+const MODEL_PREFIX_TO_ENCODING: &[(&str, &str)] = &[
+    ("o1-", O200K_BASE),
+    ("o3-", O200K_BASE),
+    ("o4-mini-", O200K_BASE),
+    ("gpt-4.1-", O200K_BASE),
+    ("gpt-4.5-", O200K_BASE),
+    ("gpt-4o-", O200K_BASE),
+    ("chatgpt-4o-", O200K_BASE),
+    ("gpt-4-", CL100K_BASE),
+    ("gpt-3.5-turbo-", CL100K_BASE),
+    ("gpt-35-turbo-", CL100K_BASE),
+    ("ft:gpt-4o", O200K_BASE),
+    ("ft:gpt-4", CL100K_BASE),
+    ("ft:gpt-3.5-turbo", CL100K_BASE),
+    ("ft:davinci-002", CL100K_BASE),
+    ("ft:babbage-002", CL100K_BASE),
+];
 
+pub fn encoding_for_model(model: &str) -> Option<&'static str> {
+    let model = model.to_lowercase();
+    if let Some((_, encoding)) = MODEL_TO_ENCODING.iter().find(|(name, _)| *name == model) {
+        return Some(encoding);
+    }
+    MODEL_PREFIX_TO_ENCODING.iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, encoding)| *encoding)
+}
+
+// Parses the ranks of a .tiktoken file, the bundled encodings are precompiled by build.rs.
+pub fn load_bpe(
+    data: &str,
+    special_tokens: &[(&str, Rank)],
+    pattern: &str
+) -> Result<CoreBPE, anyhow::Error> {
     let mut encoder = HashMap::default();
-    for line in cl100k_base.lines() {
+    for line in data.lines() {
         let mut parts = line.split(' ');
         let raw = parts.next().unwrap();
         let token = &general_purpose::STANDARD.decode(raw)?;
@@ -39,22 +130,114 @@ pub fn cl100k_base() -> Result<CoreBPE, anyhow::Error> {
         encoder.insert(token.clone(), rank);
     }
 
-    let mut special_tokens = HashMap::default();
-    special_tokens.insert(String::from(ENDOFTEXT), 100257);
-    special_tokens.insert(String::from(FIM_PREFIX), 100258);
-    special_tokens.insert(String::from(FIM_MIDDLE), 100259);
-    special_tokens.insert(String::from(FIM_SUFFIX), 100260);
-    special_tokens.insert(String::from(ENDOFPROMPT), 100276);
+    let special_tokens = special_tokens
+        .iter()
+        .map(|(token, rank)| (token.to_string(), *rank))
+        .collect();
+
+    CoreBPE::new(encoder, special_tokens, pattern)
+}
+
+fn load_static_bpe(
+    ranks: &'static StaticRanks,
+    special_tokens: &[(&str, Rank)],
+    pattern: &str
+) -> Result<CoreBPE, anyhow::Error> {
+    let special_tokens = special_tokens
+        .iter()
+        .map(|(token, rank)| (token.to_string(), *rank))
+        .collect();
+
+    CoreBPE::from_static(ranks, special_tokens, pattern)
+}
+
+pub fn r50k_base() -> Result<CoreBPE, anyhow::Error> {
+    load_static_bpe(
+        &R50K_BASE_RANKS,
+        &[(ENDOFTEXT, 50256)],
+        R50K_PATTERN
+    )
+}
+
+pub fn p50k_base() -> Result<CoreBPE, anyhow::Error> {
+    load_static_bpe(
+        &P50K_BASE_RANKS,
+        &[(ENDOFTEXT, 50256)],
+        R50K_PATTERN
+    )
+}
+
+pub fn p50k_edit() -> Result<CoreBPE, anyhow::Error> {
+    load_static_bpe(
+        &P50K_BASE_RANKS,
+        &[
+            (ENDOFTEXT, 50256),
+            (FIM_PREFIX, 50281),
+            (FIM_MIDDLE, 50282),
+            (FIM_SUFFIX, 50283),
+        ],
+        R50K_PATTERN
+    )
+}
+
+pub fn cl100k_base() -> Result<CoreBPE, anyhow::Error> {
+    load_static_bpe(
+        &CL100K_BASE_RANKS,
+        &[
+            (ENDOFTEXT, 100257),
+            (FIM_PREFIX, 100258),
+            (FIM_MIDDLE, 100259),
+            (FIM_SUFFIX, 100260),
+            (ENDOFPROMPT, 100276),
+        ],
+        CL100K_PATTERN
+    )
+}
+
+pub fn o200k_base() -> Result<CoreBPE, anyhow::Error> {
+    load_static_bpe(
+        &O200K_BASE_RANKS,
+        &[
+            (ENDOFTEXT, 199999),
+            (ENDOFPROMPT, 200018),
+        ],
+        O200K_PATTERN
+    )
+}
+
+pub fn r50k_base_singleton() -> &'static CoreBPE {
+    static R50K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+    R50K_BASE.get_or_init(|| r50k_base().unwrap())
+}
+
+pub fn p50k_base_singleton() -> &'static CoreBPE {
+    static P50K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+    P50K_BASE.get_or_init(|| p50k_base().unwrap())
+}
 
-    let bpe = CoreBPE::new(
-        encoder,
-        special_tokens,
-        "(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\\r\\n\\p{L}\\p{N}]?\\p{L}+|\\p{N}{1,3}| ?[^\\s\\p{L}\\p{N}]+[\\r\\n]*|\\s*[\\r\\n]+|\\s+(?!\\S)|\\s+"
-    )?;
-    Ok(bpe)
+pub fn p50k_edit_singleton() -> &'static CoreBPE {
+    static P50K_EDIT: OnceLock<CoreBPE> = OnceLock::new();
+    P50K_EDIT.get_or_init(|| p50k_edit().unwrap())
 }
 
 pub fn cl100k_base_singleton() -> &'static CoreBPE {
     static CL100K_BASE: OnceLock<CoreBPE> = OnceLock::new();
     CL100K_BASE.get_or_init(|| cl100k_base().unwrap())
 }
+
+pub fn o200k_base_singleton() -> &'static CoreBPE {
+    static O200K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+    O200K_BASE.get_or_init(|| o200k_base().unwrap())
+}
+
+// gpt2 shares the ranks of r50k_base.
+pub fn get_encoding(name: &str) -> Option<&'static CoreBPE> {
+    match name {
+        O200K_BASE => Some(o200k_base_singleton()),
+        CL100K_BASE => Some(cl100k_base_singleton()),
+        P50K_BASE => Some(p50k_base_singleton()),
+        P50K_EDIT => Some(p50k_edit_singleton()),
+        R50K_BASE | GPT2 => Some(r50k_base_singleton()),
+        _ => None,
+    }
+}
@@ -0,0 +1,791 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Tokenizer built from the vocabulary stored in GGUF metadata, following llama.cpp llama-vocab.cpp
+// https://github.com/ggerganov/llama.cpp/blob/master/src/llama-vocab.cpp
+
+use std::{ cmp::Ordering, collections::{ BinaryHeap, HashMap } };
+use fancy_regex::Regex;
+use opla_core::gguf::{ GGUFMetadataValue, GGUF };
+
+use crate::tokenizer::{ last_word_boundary, Tokenizer };
+use crate::vendors::tiktoken::Rank;
+
+pub const TOKENIZER_SCORES: &str = "tokenizer.ggml.scores";
+pub const TOKENIZER_TOKEN_TYPE: &str = "tokenizer.ggml.token_type";
+pub const TOKENIZER_MERGES: &str = "tokenizer.ggml.merges";
+pub const TOKENIZER_PRE: &str = "tokenizer.ggml.pre";
+pub const TOKENIZER_BOS_TOKEN_ID: &str = "tokenizer.ggml.bos_token_id";
+pub const TOKENIZER_EOS_TOKEN_ID: &str = "tokenizer.ggml.eos_token_id";
+pub const TOKENIZER_UNKNOWN_TOKEN_ID: &str = "tokenizer.ggml.unknown_token_id";
+pub const TOKENIZER_ADD_BOS_TOKEN: &str = "tokenizer.ggml.add_bos_token";
+pub const TOKENIZER_ADD_SPACE_PREFIX: &str = "tokenizer.ggml.add_space_prefix";
+
+// See llama_token_type in llama.cpp
+pub const TOKEN_TYPE_NORMAL: i32 = 1;
+pub const TOKEN_TYPE_UNKNOWN: i32 = 2;
+pub const TOKEN_TYPE_CONTROL: i32 = 3;
+pub const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+pub const TOKEN_TYPE_UNUSED: i32 = 5;
+pub const TOKEN_TYPE_BYTE: i32 = 6;
+
+pub(crate) const GPT2_PATTERN: &str =
+    "'s|'t|'re|'ve|'m|'ll|'d| ?\\p{L}+| ?\\p{N}+| ?[^\\s\\p{L}\\p{N}]+|\\s+(?!\\S)|\\s+";
+const LLAMA3_PATTERN: &str =
+    "(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\\r\\n\\p{L}\\p{N}]?\\p{L}+|\\p{N}{1,3}| ?[^\\s\\p{L}\\p{N}]+[\\r\\n]*|\\s*[\\r\\n]+|\\s+(?!\\S)|\\s+";
+const QWEN2_PATTERN: &str =
+    "(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\\r\\n\\p{L}\\p{N}]?\\p{L}+|\\p{N}| ?[^\\s\\p{L}\\p{N}]+[\\r\\n]*|\\s*[\\r\\n]+|\\s+(?!\\S)|\\s+";
+
+const SPM_SPACE: char = '\u{2581}';
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GGUFTokenizerModel {
+    // SentencePiece, tokenizer.ggml.model = "llama"
+    Llama,
+    // Byte-level BPE, tokenizer.ggml.model = "gpt2"
+    Gpt2,
+}
+
+#[derive(Clone, Debug)]
+pub struct GGUFTokenizer {
+    pub model: GGUFTokenizerModel,
+    tokens: Vec<String>,
+    scores: Vec<f32>,
+    token_types: Vec<i32>,
+    token_ids: HashMap<String, Rank>,
+    // Rank and merged token of each pair of tokens
+    merges: HashMap<(Rank, Rank), (usize, Rank)>,
+    regex: Option<Regex>,
+    // Control and user defined tokens, found in the text by encode_with_special
+    special_regex: Option<Regex>,
+    byte_encoder: Vec<char>,
+    byte_decoder: HashMap<char, u8>,
+    bos_token_id: Option<Rank>,
+    eos_token_id: Option<Rank>,
+    unknown_token_id: Option<Rank>,
+    add_bos_token: bool,
+    add_space_prefix: bool,
+    // Jinja template of the conversations, from tokenizer.chat_template
+    chat_template: Option<String>,
+}
+
+#[derive(Debug)]
+struct SpmSymbol {
+    start: usize,
+    len: usize,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+#[derive(Debug)]
+struct SpmBigram {
+    left: usize,
+    right: usize,
+    score: f32,
+    len: usize,
+}
+
+impl PartialEq for SpmBigram {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SpmBigram {}
+
+impl PartialOrd for SpmBigram {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SpmBigram {
+    // Highest score first, then leftmost first
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .partial_cmp(&other.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.left.cmp(&self.left))
+    }
+}
+
+#[derive(Debug)]
+struct BpeSymbol {
+    // None for the bytes without a token
+    id: Option<Rank>,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct BpeMerge {
+    rank: usize,
+    left: usize,
+    right: usize,
+}
+
+impl PartialOrd for BpeMerge {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BpeMerge {
+    // Lowest rank first, then leftmost first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.rank.cmp(&self.rank).then_with(|| other.left.cmp(&self.left))
+    }
+}
+
+fn metadata_array<'a>(gguf: &'a GGUF, key: &str) -> Option<&'a Vec<GGUFMetadataValue>> {
+    match gguf.get_metadata(key)? {
+        GGUFMetadataValue::Array(array) => Some(&array.value),
+        _ => None,
+    }
+}
+
+fn metadata_bool(gguf: &GGUF, key: &str) -> Option<bool> {
+    match gguf.get_metadata(key)? {
+        GGUFMetadataValue::Bool(value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn metadata_token_id(gguf: &GGUF, key: &str, n_tokens: usize) -> Option<Rank> {
+    let id = gguf.get_metadata(key)?.as_u64()?;
+    if (id as usize) < n_tokens {
+        Some(id as Rank)
+    } else {
+        None
+    }
+}
+
+// GPT-2 mapping of bytes to printable unicode characters
+pub(crate) fn bytes_to_unicode() -> Vec<char> {
+    let mut chars = vec!['\0'; 256];
+    let mut n = 0;
+    for byte in 0..256u32 {
+        let printable =
+            (0x21..=0x7e).contains(&byte) ||
+            (0xa1..=0xac).contains(&byte) ||
+            (0xae..=0xff).contains(&byte);
+        let code = if printable {
+            byte
+        } else {
+            n += 1;
+            255 + n
+        };
+        chars[byte as usize] = char::from_u32(code).unwrap_or('\0');
+    }
+    chars
+}
+
+impl GGUFTokenizer {
+    pub fn from_gguf(gguf: &GGUF) -> Result<Self, String> {
+        let model = match gguf.tokenizer_model() {
+            Some("llama") => GGUFTokenizerModel::Llama,
+            Some("gpt2") => GGUFTokenizerModel::Gpt2,
+            Some(model) => {
+                return Err(format!("Tokenizer model not supported {}", model));
+            }
+            None => {
+                return Err("Tokenizer model not found".to_string());
+            }
+        };
+
+        let tokens: Vec<String> = match metadata_array(gguf, opla_core::gguf::TOKENIZER_TOKENS) {
+            Some(values) =>
+                values
+                    .iter()
+                    .map(|v| v.as_str().unwrap_or_default().to_string())
+                    .collect(),
+            None => {
+                return Err("Tokenizer tokens not found".to_string());
+            }
+        };
+        let n_tokens = tokens.len();
+
+        let scores: Vec<f32> = match metadata_array(gguf, TOKENIZER_SCORES) {
+            Some(values) =>
+                values
+                    .iter()
+                    .map(|v| v.as_f64().unwrap_or_default() as f32)
+                    .collect(),
+            None => vec![0.0; n_tokens],
+        };
+        let token_types: Vec<i32> = match metadata_array(gguf, TOKENIZER_TOKEN_TYPE) {
+            Some(values) =>
+                values
+                    .iter()
+                    .map(|v| {
+                        match v {
+                            GGUFMetadataValue::Int32(v) => *v,
+                            _ => v.as_u64().unwrap_or(TOKEN_TYPE_NORMAL as u64) as i32,
+                        }
+                    })
+                    .collect(),
+            None => vec![TOKEN_TYPE_NORMAL; n_tokens],
+        };
+        if scores.len() != n_tokens || token_types.len() != n_tokens {
+            return Err("Tokenizer scores and token types must match the tokens".to_string());
+        }
+
+        let mut token_ids = HashMap::with_capacity(n_tokens);
+        for (id, token) in tokens.iter().enumerate() {
+            token_ids.entry(token.clone()).or_insert(id as Rank);
+        }
+
+        let mut merges = HashMap::new();
+        let mut regex = None;
+        if model == GGUFTokenizerModel::Gpt2 {
+            let values = match metadata_array(gguf, TOKENIZER_MERGES) {
+                Some(values) => values,
+                None => {
+                    return Err("Tokenizer merges not found".to_string());
+                }
+            };
+            for (rank, merge) in values.iter().enumerate() {
+                let (left, right) = match merge.as_str().and_then(|m| m.split_once(' ')) {
+                    Some(pair) => pair,
+                    None => {
+                        continue;
+                    }
+                };
+                // A merge into a token missing from the vocabulary can't be encoded
+                let ids = (
+                    token_ids.get(left),
+                    token_ids.get(right),
+                    token_ids.get(&format!("{}{}", left, right)),
+                );
+                if let (Some(left), Some(right), Some(merged)) = ids {
+                    merges.entry((*left, *right)).or_insert((rank, *merged));
+                }
+            }
+            let pattern = match gguf.get_metadata(TOKENIZER_PRE).and_then(|v| v.as_str()) {
+                Some("llama3" | "llama-bpe" | "llama-v3" | "dbrx" | "smaug-bpe") => LLAMA3_PATTERN,
+                Some("qwen2") => QWEN2_PATTERN,
+                _ => GPT2_PATTERN,
+            };
+            regex = Some(Regex::new(pattern).map_err(|err| err.to_string())?);
+        }
+
+        let mut special: Vec<&str> = tokens
+            .iter()
+            .zip(&token_types)
+            .filter(|(token, token_type)| {
+                !token.is_empty() &&
+                    matches!(**token_type, TOKEN_TYPE_CONTROL | TOKEN_TYPE_USER_DEFINED)
+            })
+            .map(|(token, _)| token.as_str())
+            .collect();
+        special.sort_by_key(|t| std::cmp::Reverse(t.len()));
+        let special_regex = if special.is_empty() {
+            None
+        } else {
+            let parts: Vec<String> = special
+                .iter()
+                .map(|t| fancy_regex::escape(t).into_owned())
+                .collect();
+            Some(Regex::new(&parts.join("|")).map_err(|err| err.to_string())?)
+        };
+
+        let byte_encoder = bytes_to_unicode();
+        let byte_decoder = byte_encoder
+            .iter()
+            .enumerate()
+            .map(|(byte, c)| (*c, byte as u8))
+            .collect();
+
+        Ok(GGUFTokenizer {
+            model,
+            tokens,
+            scores,
+            token_types,
+            token_ids,
+            merges,
+            regex,
+            special_regex,
+            byte_encoder,
+            byte_decoder,
+            bos_token_id: metadata_token_id(gguf, TOKENIZER_BOS_TOKEN_ID, n_tokens),
+            eos_token_id: metadata_token_id(gguf, TOKENIZER_EOS_TOKEN_ID, n_tokens),
+            unknown_token_id: metadata_token_id(gguf, TOKENIZER_UNKNOWN_TOKEN_ID, n_tokens),
+            add_bos_token: metadata_bool(gguf, TOKENIZER_ADD_BOS_TOKEN).unwrap_or(
+                model == GGUFTokenizerModel::Llama
+            ),
+            add_space_prefix: metadata_bool(gguf, TOKENIZER_ADD_SPACE_PREFIX).unwrap_or(
+                model == GGUFTokenizerModel::Llama
+            ),
+            chat_template: gguf.chat_template().map(|template| template.to_string()),
+        })
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let mut gguf = GGUF::new(path);
+        let options = opla_core::gguf::GGUFReadOptions { use_mmap: true, ..Default::default() };
+        gguf.read_with_options(path, &options, &mut ()).map_err(|err| err.to_string())?;
+        Self::from_gguf(&gguf)
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn bos_token_id(&self) -> Option<Rank> {
+        self.bos_token_id
+    }
+
+    pub fn eos_token_id(&self) -> Option<Rank> {
+        self.eos_token_id
+    }
+
+    pub fn chat_template(&self) -> Option<&str> {
+        self.chat_template.as_deref()
+    }
+
+    pub fn token_to_id(&self, token: &str) -> Option<Rank> {
+        self.token_ids.get(token).copied()
+    }
+
+    pub fn id_to_token(&self, id: Rank) -> Option<&str> {
+        self.tokens.get(id as usize).map(|t| t.as_str())
+    }
+
+    // Tokens as the model receives them, starting with the BOS token if the model expects it.
+    pub fn encode_with_bos(&self, text: &str) -> Vec<Rank> {
+        let mut tokens = Vec::new();
+        if let (true, Some(bos)) = (self.add_bos_token, self.bos_token_id) {
+            tokens.push(bos);
+        }
+        tokens.extend(self.encode(text));
+        tokens
+    }
+
+    fn encode_spm(&self, text: &str) -> Vec<Rank> {
+        let mut text = text.replace(' ', &SPM_SPACE.to_string());
+        if self.add_space_prefix {
+            text.insert(0, SPM_SPACE);
+        }
+
+        let mut symbols: Vec<SpmSymbol> = text
+            .char_indices()
+            .enumerate()
+            .map(|(index, (start, c))| SpmSymbol {
+                start,
+                len: c.len_utf8(),
+                prev: index.checked_sub(1),
+                next: Some(index + 1),
+            })
+            .collect();
+        if let Some(last) = symbols.last_mut() {
+            last.next = None;
+        }
+
+        let mut queue = BinaryHeap::new();
+        for left in 1..symbols.len() {
+            self.try_add_bigram(&text, &symbols, &mut queue, left - 1, left);
+        }
+
+        while let Some(bigram) = queue.pop() {
+            let (left, right) = (bigram.left, bigram.right);
+            // Skip the bigrams whose symbols have been merged since
+            if
+                symbols[left].len == 0 ||
+                symbols[right].len == 0 ||
+                symbols[left].len + symbols[right].len != bigram.len
+            {
+                continue;
+            }
+            symbols[left].len += symbols[right].len;
+            symbols[right].len = 0;
+            symbols[left].next = symbols[right].next;
+            if let Some(next) = symbols[right].next {
+                symbols[next].prev = Some(left);
+            }
+            if let Some(prev) = symbols[left].prev {
+                self.try_add_bigram(&text, &symbols, &mut queue, prev, left);
+            }
+            if let Some(next) = symbols[left].next {
+                self.try_add_bigram(&text, &symbols, &mut queue, left, next);
+            }
+        }
+
+        let mut tokens = Vec::new();
+        let mut index = if symbols.is_empty() { None } else { Some(0) };
+        while let Some(i) = index {
+            let symbol = &symbols[i];
+            let piece = &text[symbol.start..symbol.start + symbol.len];
+            match self.token_ids.get(piece) {
+                Some(id) => tokens.push(*id),
+                None => {
+                    // Byte fallback, then unknown token
+                    for byte in piece.bytes() {
+                        match self.token_ids.get(&format!("<0x{:02X}>", byte)) {
+                            Some(id) => tokens.push(*id),
+                            None => {
+                                if let Some(unknown) = self.unknown_token_id {
+                                    tokens.push(unknown);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            index = symbol.next;
+        }
+        tokens
+    }
+
+    fn try_add_bigram(
+        &self,
+        text: &str,
+        symbols: &[SpmSymbol],
+        queue: &mut BinaryHeap<SpmBigram>,
+        left: usize,
+        right: usize
+    ) {
+        let start = symbols[left].start;
+        let len = symbols[left].len + symbols[right].len;
+        if let Some(id) = self.token_ids.get(&text[start..start + len]) {
+            queue.push(SpmBigram {
+                left,
+                right,
+                score: self.scores[*id as usize],
+                len,
+            });
+        }
+    }
+
+    fn encode_bpe(&self, text: &str) -> Vec<Rank> {
+        let regex = match &self.regex {
+            Some(regex) => regex,
+            None => {
+                return vec![];
+            }
+        };
+        let mut tokens = Vec::new();
+        for piece in regex.find_iter(text) {
+            let piece = match piece {
+                Ok(piece) => piece.as_str(),
+                Err(_) => {
+                    continue;
+                }
+            };
+            self.merge_bpe(piece, &mut tokens);
+        }
+        tokens
+    }
+
+    // Merges the bytes of the piece by rank, with a queue of the candidate merges over a linked
+    // list of symbols, like encode_spm.
+    fn merge_bpe(&self, piece: &str, tokens: &mut Vec<Rank>) {
+        let len = piece.len();
+        let mut symbols: Vec<BpeSymbol> = piece
+            .bytes()
+            .enumerate()
+            .map(|(index, byte)| {
+                let mut c = [0; 4];
+                let c = self.byte_encoder[byte as usize].encode_utf8(&mut c);
+                BpeSymbol {
+                    id: self.token_ids.get(&*c).copied(),
+                    prev: index.checked_sub(1),
+                    next: if index + 1 < len { Some(index + 1) } else { None },
+                }
+            })
+            .collect();
+
+        let mut queue = BinaryHeap::new();
+        for left in 1..symbols.len() {
+            self.try_add_merge(&symbols, &mut queue, left - 1, left);
+        }
+        while let Some(merge) = queue.pop() {
+            let (left, right) = (merge.left, merge.right);
+            // Skip the merges whose symbols have changed since, the rank of a pair is unique
+            if
+                symbols[left].next != Some(right) ||
+                self.merge_of(&symbols, left, right).map(|(rank, _)| rank) != Some(merge.rank)
+            {
+                continue;
+            }
+            symbols[left].id = self.merge_of(&symbols, left, right).map(|(_, id)| id);
+            symbols[left].next = symbols[right].next;
+            symbols[right].prev = None;
+            symbols[right].next = None;
+            if let Some(next) = symbols[left].next {
+                symbols[next].prev = Some(left);
+                self.try_add_merge(&symbols, &mut queue, left, next);
+            }
+            if let Some(prev) = symbols[left].prev {
+                self.try_add_merge(&symbols, &mut queue, prev, left);
+            }
+        }
+
+        let mut index = if symbols.is_empty() { None } else { Some(0) };
+        while let Some(i) = index {
+            if let Some(id) = symbols[i].id.or(self.unknown_token_id) {
+                tokens.push(id);
+            }
+            index = symbols[i].next;
+        }
+    }
+
+    fn merge_of(&self, symbols: &[BpeSymbol], left: usize, right: usize) -> Option<(usize, Rank)> {
+        let pair = (symbols[left].id?, symbols[right].id?);
+        self.merges.get(&pair).copied()
+    }
+
+    fn try_add_merge(
+        &self,
+        symbols: &[BpeSymbol],
+        queue: &mut BinaryHeap<BpeMerge>,
+        left: usize,
+        right: usize
+    ) {
+        if let Some((rank, _)) = self.merge_of(symbols, left, right) {
+            queue.push(BpeMerge { rank, left, right });
+        }
+    }
+}
+
+impl Tokenizer for GGUFTokenizer {
+    // Tokens of the text, without the BOS token.
+    fn encode(&self, text: &str) -> Vec<Rank> {
+        if text.is_empty() {
+            return vec![];
+        }
+        match self.model {
+            GGUFTokenizerModel::Llama => self.encode_spm(text),
+            GGUFTokenizerModel::Gpt2 => self.encode_bpe(text),
+        }
+    }
+
+    fn encode_with_special(&self, text: &str) -> Vec<Rank> {
+        let regex = match &self.special_regex {
+            Some(regex) => regex,
+            None => {
+                return self.encode(text);
+            }
+        };
+        let mut tokens = Vec::new();
+        let mut last = 0;
+        for m in regex.find_iter(text).filter_map(|m| m.ok()) {
+            tokens.extend(self.encode(&text[last..m.start()]));
+            if let Some(id) = self.token_ids.get(m.as_str()) {
+                tokens.push(*id);
+            }
+            last = m.end();
+        }
+        tokens.extend(self.encode(&text[last..]));
+        tokens
+    }
+
+    fn bos_token(&self) -> Option<Rank> {
+        self.bos_token_id
+    }
+
+    fn add_bos_token(&self) -> bool {
+        self.add_bos_token
+    }
+
+    // The text of Llama models is encoded as a whole, with a space prefix.
+    fn stable_len(&self, text: &str) -> usize {
+        match self.model {
+            GGUFTokenizerModel::Gpt2 => last_word_boundary(text),
+            GGUFTokenizerModel::Llama => 0,
+        }
+    }
+
+    fn decode(&self, tokens: &[Rank]) -> Result<String, String> {
+        let mut bytes = Vec::new();
+        for token in tokens {
+            match self.token_bytes(*token) {
+                Some(token_bytes) => bytes.extend(token_bytes),
+                None => {
+                    return Err(format!("Invalid token for decoding: {}", token));
+                }
+            }
+        }
+        if self.add_space_prefix && bytes.first() == Some(&b' ') {
+            bytes.remove(0);
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    // Control, unknown and unused tokens have no bytes.
+    fn token_bytes(&self, token: Rank) -> Option<Vec<u8>> {
+        let text = self.tokens.get(token as usize)?;
+        let bytes = match self.token_types[token as usize] {
+            TOKEN_TYPE_CONTROL | TOKEN_TYPE_UNKNOWN | TOKEN_TYPE_UNUSED => vec![],
+            TOKEN_TYPE_BYTE if self.model == GGUFTokenizerModel::Llama => {
+                text.strip_prefix("<0x")
+                    .and_then(|t| t.strip_suffix('>'))
+                    .and_then(|t| u8::from_str_radix(t, 16).ok())
+                    .map_or(vec![], |byte| vec![byte])
+            }
+            _ =>
+                match self.model {
+                    GGUFTokenizerModel::Llama => text.replace(SPM_SPACE, " ").into_bytes(),
+                    GGUFTokenizerModel::Gpt2 => {
+                        let mut bytes = Vec::with_capacity(text.len());
+                        for c in text.chars() {
+                            match self.byte_decoder.get(&c) {
+                                Some(byte) => bytes.push(*byte),
+                                None => {
+                                    let mut buffer = [0; 4];
+                                    bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+                                }
+                            }
+                        }
+                        bytes
+                    }
+                }
+        };
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use opla_core::gguf::{
+        GGUFMetadataArrayValue,
+        GGUFMetadataValue,
+        GGUFMetadataValueType,
+        GGUF,
+        TOKENIZER_MODEL,
+        TOKENIZER_TOKENS,
+    };
+    use crate::tokenizer::{ TokenSpan, Tokenizer };
+    use super::*;
+
+    fn array(value_type: GGUFMetadataValueType, value: Vec<GGUFMetadataValue>) -> GGUFMetadataValue {
+        GGUFMetadataValue::Array(GGUFMetadataArrayValue {
+            value_type,
+            len: value.len() as u64,
+            value,
+        })
+    }
+
+    fn strings(values: &[&str]) -> GGUFMetadataValue {
+        array(
+            GGUFMetadataValueType::String,
+            values
+                .iter()
+                .map(|v| GGUFMetadataValue::String(v.to_string()))
+                .collect()
+        )
+    }
+
+    pub(crate) fn llama_tokenizer() -> GGUFTokenizer {
+        let tokens = [
+            ("<unk>", 0.0, TOKEN_TYPE_UNKNOWN),
+            ("<s>", 0.0, TOKEN_TYPE_CONTROL),
+            ("</s>", 0.0, TOKEN_TYPE_CONTROL),
+            ("<0x21>", 0.0, TOKEN_TYPE_BYTE),
+            ("\u{2581}", -10.0, TOKEN_TYPE_NORMAL),
+            ("h", -10.0, TOKEN_TYPE_NORMAL),
+            ("e", -10.0, TOKEN_TYPE_NORMAL),
+            ("l", -10.0, TOKEN_TYPE_NORMAL),
+            ("o", -10.0, TOKEN_TYPE_NORMAL),
+            ("\u{2581}h", -1.0, TOKEN_TYPE_NORMAL),
+            ("ll", -2.0, TOKEN_TYPE_NORMAL),
+            ("\u{2581}he", -3.0, TOKEN_TYPE_NORMAL),
+            ("llo", -4.0, TOKEN_TYPE_NORMAL),
+            ("\u{2581}hello", -5.0, TOKEN_TYPE_NORMAL),
+        ];
+        let mut gguf = GGUF::new("llama");
+        gguf.set_metadata(TOKENIZER_MODEL, GGUFMetadataValue::String("llama".to_string()));
+        gguf.set_metadata(
+            TOKENIZER_TOKENS,
+            strings(&tokens.iter().map(|t| t.0).collect::<Vec<_>>())
+        );
+        gguf.set_metadata(
+            TOKENIZER_SCORES,
+            array(
+                GGUFMetadataValueType::Float32,
+                tokens
+                    .iter()
+                    .map(|t| GGUFMetadataValue::Float32(t.1))
+                    .collect()
+            )
+        );
+        gguf.set_metadata(
+            TOKENIZER_TOKEN_TYPE,
+            array(
+                GGUFMetadataValueType::Int32,
+                tokens
+                    .iter()
+                    .map(|t| GGUFMetadataValue::Int32(t.2))
+                    .collect()
+            )
+        );
+        gguf.set_metadata(TOKENIZER_BOS_TOKEN_ID, GGUFMetadataValue::Uint32(1));
+        gguf.set_metadata(TOKENIZER_EOS_TOKEN_ID, GGUFMetadataValue::Uint32(2));
+        gguf.set_metadata(TOKENIZER_UNKNOWN_TOKEN_ID, GGUFMetadataValue::Uint32(0));
+        GGUFTokenizer::from_gguf(&gguf).unwrap()
+    }
+
+    #[test]
+    fn test_encode_llama() {
+        let tokenizer = llama_tokenizer();
+        assert_eq!(tokenizer.model, GGUFTokenizerModel::Llama);
+        assert_eq!(tokenizer.encode("hello!"), vec![13, 3]);
+        assert_eq!(tokenizer.encode_with_bos("hello!"), vec![1, 13, 3]);
+        assert_eq!(tokenizer.encode("hello?"), vec![13, 0]);
+        assert_eq!(tokenizer.decode(&[1, 13, 3]), Ok("hello!".to_string()));
+        assert_eq!(tokenizer.encode_with_special("<s>hello</s>"), vec![1, 13, 2]);
+        assert_eq!(tokenizer.encode_with_offsets("hello!"), vec![
+            TokenSpan { token: 13, start: 0, end: 5, char_start: 0, char_end: 5 },
+            TokenSpan { token: 3, start: 5, end: 6, char_start: 5, char_end: 6 },
+        ]);
+        assert_eq!(tokenizer.eos_token_id(), Some(2));
+        assert_eq!(tokenizer.bos_token(), Some(1));
+        assert!(tokenizer.add_bos_token());
+        assert_eq!(tokenizer.chat_template(), None);
+    }
+
+    #[test]
+    fn test_encode_gpt2() {
+        let tokens = [
+            "h", "e", "l", "o", "\u{120}", "w", "r", "d",
+            "he", "ll", "llo", "hello", "\u{120}w", "or", "\u{120}wor", "ld", "\u{120}world",
+        ];
+        let merges = [
+            "h e", "l l", "ll o", "he llo", "\u{120} w", "o r", "\u{120}w or", "l d", "\u{120}wor ld",
+        ];
+        let mut gguf = GGUF::new("gpt2");
+        gguf.set_metadata(TOKENIZER_MODEL, GGUFMetadataValue::String("gpt2".to_string()));
+        gguf.set_metadata(TOKENIZER_TOKENS, strings(&tokens));
+        gguf.set_metadata(TOKENIZER_MERGES, strings(&merges));
+
+        let tokenizer = GGUFTokenizer::from_gguf(&gguf).unwrap();
+        assert!(!tokenizer.add_bos_token());
+        assert_eq!(tokenizer.bos_token(), None);
+        // The bos token of a model is only added before the prompt when add_bos_token is set
+        gguf.set_metadata(TOKENIZER_BOS_TOKEN_ID, GGUFMetadataValue::Uint32(0));
+        let tokenizer = GGUFTokenizer::from_gguf(&gguf).unwrap();
+        assert!(!tokenizer.add_bos_token());
+        assert_eq!(tokenizer.bos_token(), Some(0));
+        assert_eq!(tokenizer.encode_with_bos("hello"), vec![11]);
+        assert_eq!(tokenizer.encode("hello world"), vec![11, 16]);
+        assert_eq!(tokenizer.encode("hell"), vec![8, 9]);
+        assert_eq!(tokenizer.decode(&[11, 16]), Ok("hello world".to_string()));
+        assert_eq!(tokenizer.count("hello world"), 2);
+        // A long run of letters is a single piece
+        assert_eq!(tokenizer.encode(&"hello".repeat(20_000)), vec![11; 20_000]);
+        assert_eq!(tokenizer.encode("lll"), vec![9, 2]);
+
+        gguf.set_metadata(TOKENIZER_MODEL, GGUFMetadataValue::String("bert".to_string()));
+        assert!(GGUFTokenizer::from_gguf(&gguf).is_err());
+    }
+}
@@ -0,0 +1,1099 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Tokenizer loaded from a HuggingFace tokenizer.json file
+// https://huggingface.co/docs/tokenizers/api/pipeline
+
+use std::{ collections::{ HashMap, HashSet }, fs, sync::OnceLock };
+use anyhow::anyhow;
+use fancy_regex::Regex;
+use serde::{ Deserialize, Deserializer };
+use unicode_normalization::UnicodeNormalization;
+
+use crate::gguf::{ bytes_to_unicode, GPT2_PATTERN };
+use crate::tokenizer::Tokenizer;
+use crate::vendors::tiktoken::Rank;
+
+// Score penalty of the unknown characters in Unigram models
+const UNIGRAM_UNK_PENALTY: f64 = 10.0;
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_isolated() -> SplitBehavior {
+    SplitBehavior::Isolated
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+fn static_regex(regex: &'static OnceLock<Regex>, pattern: &str) -> &'static Regex {
+    regex.get_or_init(|| Regex::new(pattern).unwrap())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+enum Pattern {
+    String(String),
+    Regex(#[serde(deserialize_with = "deserialize_regex")] Regex),
+}
+
+impl Pattern {
+    // Byte ranges of the non-empty matches in the text
+    fn find_matches(&self, text: &str) -> Vec<(usize, usize)> {
+        match self {
+            Pattern::String(pattern) if pattern.is_empty() => vec![],
+            Pattern::String(pattern) =>
+                text
+                    .match_indices(pattern.as_str())
+                    .map(|(start, m)| (start, start + m.len()))
+                    .collect(),
+            Pattern::Regex(regex) => regex_matches(regex, text),
+        }
+    }
+
+    fn replace(&self, text: &str, content: &str) -> String {
+        let mut result = String::with_capacity(text.len());
+        let mut last = 0;
+        for (start, end) in self.find_matches(text) {
+            result.push_str(&text[last..start]);
+            result.push_str(content);
+            last = end;
+        }
+        result.push_str(&text[last..]);
+        result
+    }
+}
+
+fn regex_matches(regex: &Regex, text: &str) -> Vec<(usize, usize)> {
+    regex
+        .find_iter(text)
+        .filter_map(|m| m.ok())
+        .filter(|m| m.start() < m.end())
+        .map(|m| (m.start(), m.end()))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+enum SplitBehavior {
+    Removed,
+    Isolated,
+    MergedWithPrevious,
+    MergedWithNext,
+    Contiguous,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PrependScheme {
+    First,
+    Never,
+    Always,
+}
+
+fn prepend_scheme(scheme: Option<PrependScheme>, add_prefix_space: Option<bool>) -> PrependScheme {
+    match (scheme, add_prefix_space) {
+        (Some(scheme), _) => scheme,
+        (None, Some(false)) => PrependScheme::Never,
+        (None, _) => PrependScheme::Always,
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type")]
+enum Normalizer {
+    Sequence {
+        normalizers: Vec<Normalizer>,
+    },
+    #[serde(rename = "NFC")]
+    Nfc,
+    #[serde(rename = "NFD")]
+    Nfd,
+    #[serde(rename = "NFKC")]
+    Nfkc,
+    #[serde(rename = "NFKD")]
+    Nfkd,
+    Lowercase,
+    Prepend {
+        prepend: String,
+    },
+    Replace {
+        pattern: Pattern,
+        content: String,
+    },
+    Strip {
+        #[serde(default)]
+        strip_left: bool,
+        #[serde(default)]
+        strip_right: bool,
+    },
+    // SentencePiece normalization rules, approximated with NFKC
+    Precompiled {},
+}
+
+impl Normalizer {
+    fn normalize(&self, text: String) -> String {
+        match self {
+            Normalizer::Sequence { normalizers } =>
+                normalizers.iter().fold(text, |text, normalizer| normalizer.normalize(text)),
+            Normalizer::Nfc => text.nfc().collect(),
+            Normalizer::Nfd => text.nfd().collect(),
+            Normalizer::Nfkc | Normalizer::Precompiled {} => text.nfkc().collect(),
+            Normalizer::Nfkd => text.nfkd().collect(),
+            Normalizer::Lowercase => text.to_lowercase(),
+            Normalizer::Prepend { prepend } if !text.is_empty() => format!("{}{}", prepend, text),
+            Normalizer::Prepend { .. } => text,
+            Normalizer::Replace { pattern, content } => pattern.replace(&text, content),
+            Normalizer::Strip { strip_left, strip_right } => {
+                let mut text = text.as_str();
+                if *strip_left {
+                    text = text.trim_start();
+                }
+                if *strip_right {
+                    text = text.trim_end();
+                }
+                text.to_string()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type")]
+enum PreTokenizer {
+    Sequence {
+        pretokenizers: Vec<PreTokenizer>,
+    },
+    ByteLevel {
+        #[serde(default = "default_true")]
+        add_prefix_space: bool,
+        #[serde(default = "default_true")]
+        use_regex: bool,
+    },
+    Split {
+        pattern: Pattern,
+        behavior: SplitBehavior,
+        #[serde(default)]
+        invert: bool,
+    },
+    Metaspace {
+        replacement: char,
+        #[serde(default)]
+        prepend_scheme: Option<PrependScheme>,
+        #[serde(default)]
+        add_prefix_space: Option<bool>,
+        #[serde(default = "default_true")]
+        split: bool,
+    },
+    Whitespace {},
+    WhitespaceSplit {},
+    Digits {
+        #[serde(default)]
+        individual_digits: bool,
+    },
+    Punctuation {
+        #[serde(default = "default_isolated")]
+        behavior: SplitBehavior,
+    },
+}
+
+// Splits the text around the matches, keeping them according to the behavior.
+fn split_pieces(
+    text: &str,
+    matches: Vec<(usize, usize)>,
+    behavior: SplitBehavior,
+    invert: bool
+) -> Vec<String> {
+    let mut segments = Vec::new();
+    let mut last = 0;
+    for (start, end) in matches {
+        if last < start {
+            segments.push((&text[last..start], invert));
+        }
+        segments.push((&text[start..end], !invert));
+        last = end;
+    }
+    if last < text.len() {
+        segments.push((&text[last..], invert));
+    }
+
+    let mut pieces: Vec<String> = Vec::new();
+    let mut last_was_match = false;
+    let mut pending: Option<String> = None;
+    for (segment, is_match) in segments {
+        match behavior {
+            SplitBehavior::Removed if is_match => {}
+            SplitBehavior::MergedWithPrevious if is_match && !last_was_match => {
+                match pieces.last_mut() {
+                    Some(previous) => previous.push_str(segment),
+                    None => pieces.push(segment.to_string()),
+                }
+            }
+            SplitBehavior::Contiguous if is_match && last_was_match => {
+                if let Some(previous) = pieces.last_mut() {
+                    previous.push_str(segment);
+                }
+            }
+            SplitBehavior::MergedWithNext if is_match => {
+                if let Some(pending) = pending.replace(segment.to_string()) {
+                    pieces.push(pending);
+                }
+            }
+            SplitBehavior::MergedWithNext => {
+                let mut piece = pending.take().unwrap_or_default();
+                piece.push_str(segment);
+                pieces.push(piece);
+            }
+            _ => pieces.push(segment.to_string()),
+        }
+        last_was_match = is_match;
+    }
+    pieces.extend(pending);
+    pieces
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type")]
+enum Decoder {
+    Sequence {
+        decoders: Vec<Decoder>,
+    },
+    ByteLevel {},
+    ByteFallback {},
+    Fuse {},
+    Strip {
+        content: char,
+        start: usize,
+        stop: usize,
+    },
+    Replace {
+        pattern: Pattern,
+        content: String,
+    },
+    Metaspace {
+        replacement: char,
+        #[serde(default)]
+        prepend_scheme: Option<PrependScheme>,
+        #[serde(default)]
+        add_prefix_space: Option<bool>,
+    },
+}
+
+impl Decoder {
+    fn is_byte_level(&self) -> bool {
+        match self {
+            Decoder::Sequence { decoders } => decoders.iter().any(|d| d.is_byte_level()),
+            Decoder::ByteLevel {} => true,
+            _ => false,
+        }
+    }
+
+    fn metaspace_replacement(&self) -> Option<char> {
+        match self {
+            Decoder::Sequence { decoders } => decoders.iter().find_map(|d| d.metaspace_replacement()),
+            Decoder::Metaspace { replacement, .. } => Some(*replacement),
+            // SentencePiece BPE models replace the metaspace with a Replace decoder
+            Decoder::Replace { pattern: Pattern::String(pattern), content } if content == " " =>
+                pattern.chars().next(),
+            _ => None,
+        }
+    }
+}
+
+// Byte of the <0xXX> tokens
+fn byte_token(piece: &str) -> Option<u8> {
+    piece
+        .strip_prefix("<0x")
+        .and_then(|p| p.strip_suffix('>'))
+        .filter(|p| p.len() == 2)
+        .and_then(|p| u8::from_str_radix(p, 16).ok())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum Merge {
+    Pair(String, String),
+    Joined(String),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type")]
+enum ModelConfig {
+    #[serde(rename = "BPE")]
+    Bpe {
+        vocab: HashMap<String, Rank>,
+        merges: Vec<Merge>,
+        #[serde(default)]
+        unk_token: Option<String>,
+        #[serde(default)]
+        continuing_subword_prefix: Option<String>,
+        #[serde(default)]
+        end_of_word_suffix: Option<String>,
+        #[serde(default)]
+        fuse_unk: bool,
+        #[serde(default)]
+        byte_fallback: bool,
+        #[serde(default)]
+        ignore_merges: bool,
+    },
+    Unigram {
+        vocab: Vec<(String, f64)>,
+        #[serde(default)]
+        unk_id: Option<Rank>,
+        #[serde(default)]
+        byte_fallback: bool,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct AddedToken {
+    id: Rank,
+    content: String,
+    #[serde(default)]
+    special: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct TokenizerConfig {
+    #[serde(default)]
+    added_tokens: Vec<AddedToken>,
+    #[serde(default)]
+    normalizer: Option<Normalizer>,
+    #[serde(default)]
+    pre_tokenizer: Option<PreTokenizer>,
+    #[serde(default)]
+    decoder: Option<Decoder>,
+    model: ModelConfig,
+}
+
+#[derive(Clone, Debug)]
+enum Model {
+    Bpe {
+        // Ranks and merged token of the pairs of tokens
+        merges: HashMap<(Rank, Rank), (usize, Rank)>,
+        continuing_subword_prefix: Option<String>,
+        end_of_word_suffix: Option<String>,
+        fuse_unk: bool,
+        ignore_merges: bool,
+    },
+    Unigram {
+        scores: Vec<f64>,
+        unk_score: f64,
+        // Length in chars of the longest piece
+        max_piece_length: usize,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct HFTokenizer {
+    vocab: HashMap<String, Rank>,
+    decoder_vocab: HashMap<Rank, String>,
+    model: Model,
+    unk_id: Option<Rank>,
+    byte_fallback: bool,
+    added_tokens: HashSet<String>,
+    special_tokens: HashSet<String>,
+    added_regex: Option<Regex>,
+    normalizer: Option<Normalizer>,
+    pre_tokenizer: Option<PreTokenizer>,
+    decoder: Option<Decoder>,
+    byte_encoder: Vec<char>,
+    byte_decoder: HashMap<char, u8>,
+}
+
+impl HFTokenizer {
+    pub fn from_file(path: &str) -> Result<Self, anyhow::Error> {
+        let json = fs::read_to_string(path)?;
+        Self::from_json(&json)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, anyhow::Error> {
+        let config: TokenizerConfig = serde_json::from_str(json)?;
+
+        let (mut vocab, model, unk_id, byte_fallback) = match config.model {
+            ModelConfig::Bpe {
+                vocab,
+                merges,
+                unk_token,
+                continuing_subword_prefix,
+                end_of_word_suffix,
+                fuse_unk,
+                byte_fallback,
+                ignore_merges,
+            } => {
+                let prefix = continuing_subword_prefix.clone().unwrap_or_default();
+                let mut ranks = HashMap::with_capacity(merges.len());
+                for (rank, merge) in merges.into_iter().enumerate() {
+                    let (left, right) = match merge {
+                        Merge::Pair(left, right) => (left, right),
+                        Merge::Joined(merge) =>
+                            match merge.split_once(' ') {
+                                Some((left, right)) => (left.to_string(), right.to_string()),
+                                None => {
+                                    return Err(anyhow!("Invalid merge {}", merge));
+                                }
+                            }
+                    };
+                    let merged = format!("{}{}", left, right.strip_prefix(&prefix).unwrap_or(&right));
+                    let ids = (vocab.get(&left), vocab.get(&right), vocab.get(&merged));
+                    let (left, right, merged) = match ids {
+                        (Some(left), Some(right), Some(merged)) => (*left, *right, *merged),
+                        _ => {
+                            return Err(anyhow!("Merge {} {} not in vocabulary", left, right));
+                        }
+                    };
+                    ranks.entry((left, right)).or_insert((rank, merged));
+                }
+                let unk_id = unk_token.and_then(|token| vocab.get(&token).copied());
+                let model = Model::Bpe {
+                    merges: ranks,
+                    continuing_subword_prefix: continuing_subword_prefix.filter(|p| !p.is_empty()),
+                    end_of_word_suffix: end_of_word_suffix.filter(|s| !s.is_empty()),
+                    fuse_unk,
+                    ignore_merges,
+                };
+                (vocab, model, unk_id, byte_fallback)
+            }
+            ModelConfig::Unigram { vocab: pieces, unk_id, byte_fallback } => {
+                let mut vocab = HashMap::with_capacity(pieces.len());
+                let mut scores = Vec::with_capacity(pieces.len());
+                let mut max_piece_length = 1;
+                for (id, (piece, score)) in pieces.into_iter().enumerate() {
+                    max_piece_length = max_piece_length.max(piece.chars().count());
+                    vocab.entry(piece).or_insert(id as Rank);
+                    scores.push(score);
+                }
+                let min_score = scores.iter().copied().fold(0.0, f64::min);
+                let model = Model::Unigram {
+                    scores,
+                    unk_score: min_score - UNIGRAM_UNK_PENALTY,
+                    max_piece_length,
+                };
+                (vocab, model, unk_id, byte_fallback)
+            }
+        };
+
+        let mut added_tokens = HashSet::new();
+        let mut special_tokens = HashSet::new();
+        for token in &config.added_tokens {
+            vocab.insert(token.content.clone(), token.id);
+            added_tokens.insert(token.content.clone());
+            if token.special {
+                special_tokens.insert(token.content.clone());
+            }
+        }
+        // Longest added tokens first so that they win over their prefixes
+        let mut added: Vec<&str> = config.added_tokens
+            .iter()
+            .map(|t| t.content.as_str())
+            .collect();
+        added.sort_by_key(|t| std::cmp::Reverse(t.len()));
+        let added_regex = if added.is_empty() {
+            None
+        } else {
+            let parts: Vec<String> = added
+                .iter()
+                .map(|t| fancy_regex::escape(t).into_owned())
+                .collect();
+            Some(Regex::new(&parts.join("|"))?)
+        };
+
+        let decoder_vocab = vocab
+            .iter()
+            .map(|(token, id)| (*id, token.clone()))
+            .collect();
+        let byte_encoder = bytes_to_unicode();
+        let byte_decoder = byte_encoder
+            .iter()
+            .enumerate()
+            .map(|(byte, c)| (*c, byte as u8))
+            .collect();
+
+        Ok(HFTokenizer {
+            vocab,
+            decoder_vocab,
+            model,
+            unk_id,
+            byte_fallback,
+            added_tokens,
+            special_tokens,
+            added_regex,
+            normalizer: config.normalizer,
+            pre_tokenizer: config.pre_tokenizer,
+            decoder: config.decoder,
+            byte_encoder,
+            byte_decoder,
+        })
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.decoder_vocab.len()
+    }
+
+    pub fn token_to_id(&self, token: &str) -> Option<Rank> {
+        self.vocab.get(token).copied()
+    }
+
+    pub fn id_to_token(&self, id: Rank) -> Option<&str> {
+        self.decoder_vocab.get(&id).map(|t| t.as_str())
+    }
+
+    pub fn special_tokens(&self) -> HashSet<&str> {
+        self.special_tokens
+            .iter()
+            .map(|t| t.as_str())
+            .collect()
+    }
+
+    // ====================
+    // Encoding
+    // ====================
+
+    // Special tokens are encoded as ordinary text, added tokens that are not special are kept.
+    pub fn encode_ordinary(&self, text: &str) -> Vec<Rank> {
+        self.encode(text, HashSet::new())
+    }
+
+    pub fn encode(&self, text: &str, allowed_special: HashSet<&str>) -> Vec<Rank> {
+        let mut tokens = Vec::new();
+        for (start, segment, added) in self.split_added_tokens(text, &allowed_special) {
+            match added {
+                Some(id) => tokens.push(id),
+                None => self.encode_segment(segment, start == 0, &mut tokens),
+            }
+        }
+        tokens
+    }
+
+    pub fn encode_with_special_tokens(&self, text: &str) -> Vec<Rank> {
+        self.encode(text, self.special_tokens())
+    }
+
+    // Segments of the text with their offset, and the id of the added tokens.
+    fn split_added_tokens<'a>(
+        &self,
+        text: &'a str,
+        allowed_special: &HashSet<&str>
+    ) -> Vec<(usize, &'a str, Option<Rank>)> {
+        let mut segments = Vec::new();
+        let mut last = 0;
+        if let Some(regex) = &self.added_regex {
+            for m in regex.find_iter(text).filter_map(|m| m.ok()) {
+                let content = m.as_str();
+                if self.special_tokens.contains(content) && !allowed_special.contains(content) {
+                    continue;
+                }
+                if last < m.start() {
+                    segments.push((last, &text[last..m.start()], None));
+                }
+                segments.push((m.start(), content, self.vocab.get(content).copied()));
+                last = m.end();
+            }
+        }
+        if last < text.len() {
+            segments.push((last, &text[last..], None));
+        }
+        segments
+    }
+
+    fn encode_segment(&self, text: &str, first: bool, tokens: &mut Vec<Rank>) {
+        let mut text = text.to_string();
+        if let Some(normalizer) = &self.normalizer {
+            text = normalizer.normalize(text);
+        }
+        let pieces = match &self.pre_tokenizer {
+            Some(pre_tokenizer) => self.pre_tokenize(pre_tokenizer, vec![text], first),
+            None => vec![text],
+        };
+        for piece in pieces {
+            match &self.model {
+                Model::Bpe { .. } => self.tokenize_bpe(&piece, tokens),
+                Model::Unigram { .. } => self.tokenize_unigram(&piece, tokens),
+            }
+        }
+    }
+
+    fn pre_tokenize(
+        &self,
+        pre_tokenizer: &PreTokenizer,
+        pieces: Vec<String>,
+        first: bool
+    ) -> Vec<String> {
+        static BYTE_LEVEL: OnceLock<Regex> = OnceLock::new();
+        static WHITESPACE: OnceLock<Regex> = OnceLock::new();
+        static DIGIT: OnceLock<Regex> = OnceLock::new();
+        static DIGITS: OnceLock<Regex> = OnceLock::new();
+        static PUNCTUATION: OnceLock<Regex> = OnceLock::new();
+
+        let mut result = Vec::with_capacity(pieces.len());
+        for (index, piece) in pieces.into_iter().enumerate() {
+            match pre_tokenizer {
+                PreTokenizer::Sequence { pretokenizers } => {
+                    let pieces = pretokenizers
+                        .iter()
+                        .fold(vec![piece], |pieces, pre_tokenizer| {
+                            self.pre_tokenize(pre_tokenizer, pieces, first && index == 0)
+                        });
+                    result.extend(pieces);
+                }
+                PreTokenizer::ByteLevel { add_prefix_space, use_regex } => {
+                    let piece = if *add_prefix_space && !piece.starts_with(' ') {
+                        format!(" {}", piece)
+                    } else {
+                        piece
+                    };
+                    let parts = if *use_regex {
+                        let regex = static_regex(&BYTE_LEVEL, GPT2_PATTERN);
+                        let matches = regex_matches(regex, &piece);
+                        split_pieces(&piece, matches, SplitBehavior::Isolated, false)
+                    } else {
+                        vec![piece]
+                    };
+                    result.extend(
+                        parts.iter().map(|part| {
+                            part.bytes()
+                                .map(|byte| self.byte_encoder[byte as usize])
+                                .collect::<String>()
+                        })
+                    );
+                }
+                PreTokenizer::Split { pattern, behavior, invert } => {
+                    let matches = pattern.find_matches(&piece);
+                    result.extend(split_pieces(&piece, matches, *behavior, *invert));
+                }
+                PreTokenizer::Metaspace {
+                    replacement,
+                    prepend_scheme: scheme,
+                    add_prefix_space,
+                    split,
+                } => {
+                    let mut piece = piece.replace(' ', &replacement.to_string());
+                    let prepend = match prepend_scheme(*scheme, *add_prefix_space) {
+                        PrependScheme::Always => true,
+                        PrependScheme::First => first && index == 0,
+                        PrependScheme::Never => false,
+                    };
+                    if prepend && !piece.starts_with(*replacement) {
+                        piece.insert(0, *replacement);
+                    }
+                    if *split {
+                        let matches = piece
+                            .match_indices(*replacement)
+                            .map(|(start, m)| (start, start + m.len()))
+                            .collect();
+                        let behavior = SplitBehavior::MergedWithNext;
+                        result.extend(split_pieces(&piece, matches, behavior, false));
+                    } else {
+                        result.push(piece);
+                    }
+                }
+                PreTokenizer::Whitespace {} => {
+                    let regex = static_regex(&WHITESPACE, "\\w+|[^\\w\\s]+");
+                    let matches = regex_matches(regex, &piece);
+                    result.extend(split_pieces(&piece, matches, SplitBehavior::Removed, true));
+                }
+                PreTokenizer::WhitespaceSplit {} => {
+                    result.extend(piece.split_whitespace().map(|p| p.to_string()));
+                }
+                PreTokenizer::Digits { individual_digits } => {
+                    let regex = if *individual_digits {
+                        static_regex(&DIGIT, "\\p{N}")
+                    } else {
+                        static_regex(&DIGITS, "\\p{N}+")
+                    };
+                    let matches = regex_matches(regex, &piece);
+                    result.extend(split_pieces(&piece, matches, SplitBehavior::Isolated, false));
+                }
+                PreTokenizer::Punctuation { behavior } => {
+                    let regex = static_regex(&PUNCTUATION, "\\p{P}");
+                    let matches = regex_matches(regex, &piece);
+                    result.extend(split_pieces(&piece, matches, *behavior, false));
+                }
+            }
+        }
+        result
+    }
+
+    // Unknown pieces fallback to their bytes if the model has byte tokens, or to the unknown token.
+    fn push_unknown(&self, piece: &str, fuse_unk: bool, tokens: &mut Vec<Rank>) {
+        if self.byte_fallback {
+            let bytes: Option<Vec<Rank>> = piece
+                .bytes()
+                .map(|byte| self.vocab.get(&format!("<0x{:02X}>", byte)).copied())
+                .collect();
+            if let Some(bytes) = bytes {
+                tokens.extend(bytes);
+                return;
+            }
+        }
+        if let Some(unk_id) = self.unk_id {
+            if !fuse_unk || tokens.last() != Some(&unk_id) {
+                tokens.push(unk_id);
+            }
+        }
+    }
+
+    fn tokenize_bpe(&self, piece: &str, tokens: &mut Vec<Rank>) {
+        let (merges, prefix, suffix, fuse_unk, ignore_merges) = match &self.model {
+            Model::Bpe {
+                merges,
+                continuing_subword_prefix,
+                end_of_word_suffix,
+                fuse_unk,
+                ignore_merges,
+            } => (merges, continuing_subword_prefix, end_of_word_suffix, *fuse_unk, *ignore_merges),
+            _ => {
+                return;
+            }
+        };
+        if ignore_merges {
+            if let Some(id) = self.vocab.get(piece) {
+                tokens.push(*id);
+                return;
+            }
+        }
+
+        let char_count = piece.chars().count();
+        let mut symbols: Vec<Rank> = Vec::with_capacity(char_count);
+        let mut unknown = Vec::new();
+        for (index, c) in piece.chars().enumerate() {
+            let mut symbol = String::new();
+            if let (Some(prefix), true) = (prefix, index > 0) {
+                symbol.push_str(prefix);
+            }
+            symbol.push(c);
+            if let (Some(suffix), true) = (suffix, index + 1 == char_count) {
+                symbol.push_str(suffix);
+            }
+            match self.vocab.get(&symbol) {
+                Some(id) => symbols.push(*id),
+                None => {
+                    // Unknown chars are never merged
+                    let start = symbols.len();
+                    self.push_unknown(&c.to_string(), fuse_unk, &mut symbols);
+                    unknown.extend(start..symbols.len());
+                }
+            }
+        }
+
+        loop {
+            let best = symbols
+                .windows(2)
+                .enumerate()
+                .filter(|(i, _)| !unknown.contains(i) && !unknown.contains(&(i + 1)))
+                .filter_map(|(i, pair)| {
+                    merges.get(&(pair[0], pair[1])).map(|(rank, merged)| (*rank, i, *merged))
+                })
+                .min();
+            let (_, i, merged) = match best {
+                Some(best) => best,
+                None => {
+                    break;
+                }
+            };
+            symbols[i] = merged;
+            symbols.remove(i + 1);
+            for index in unknown.iter_mut().filter(|index| **index > i) {
+                *index -= 1;
+            }
+        }
+        tokens.extend(symbols);
+    }
+
+    // Viterbi search of the pieces with the best total score
+    fn tokenize_unigram(&self, piece: &str, tokens: &mut Vec<Rank>) {
+        let (scores, unk_score, max_piece_length) = match &self.model {
+            Model::Unigram { scores, unk_score, max_piece_length } => (
+                scores,
+                *unk_score,
+                *max_piece_length,
+            ),
+            _ => {
+                return;
+            }
+        };
+        let mut bounds: Vec<usize> = piece
+            .char_indices()
+            .map(|(i, _)| i)
+            .collect();
+        bounds.push(piece.len());
+        let n = bounds.len() - 1;
+
+        // Best score ending at each char with the start of its last piece
+        let mut best: Vec<(f64, usize, Option<Rank>)> = vec![(f64::NEG_INFINITY, 0, None); n + 1];
+        best[0].0 = 0.0;
+        for end in 1..=n {
+            for start in end.saturating_sub(max_piece_length)..end {
+                if best[start].0 == f64::NEG_INFINITY {
+                    continue;
+                }
+                let sub = &piece[bounds[start]..bounds[end]];
+                let candidate = match self.vocab.get(sub) {
+                    Some(id) => {
+                        let score = scores.get(*id as usize).copied().unwrap_or(unk_score);
+                        Some((best[start].0 + score, start, Some(*id)))
+                    }
+                    None if end == start + 1 => Some((best[start].0 + unk_score, start, None)),
+                    None => None,
+                };
+                if let Some(candidate) = candidate {
+                    if candidate.0 > best[end].0 {
+                        best[end] = candidate;
+                    }
+                }
+            }
+        }
+
+        let mut path = Vec::new();
+        let mut end = n;
+        while end > 0 {
+            let (_, start, id) = best[end];
+            path.push((start, end, id));
+            end = start;
+        }
+        for (start, end, id) in path.into_iter().rev() {
+            match id {
+                Some(id) => tokens.push(id),
+                None => self.push_unknown(&piece[bounds[start]..bounds[end]], true, tokens),
+            }
+        }
+    }
+
+    // ====================
+    // Decoding
+    // ====================
+
+    pub fn decode(&self, tokens: Vec<Rank>) -> Result<String, anyhow::Error> {
+        let mut pieces = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            match self.decoder_vocab.get(&token) {
+                Some(piece) => pieces.push(piece.clone()),
+                None => {
+                    return Err(anyhow!("Unknown token {}", token));
+                }
+            }
+        }
+        match &self.decoder {
+            Some(decoder) => Ok(self.decode_chain(decoder, pieces).concat()),
+            None => Ok(pieces.join(" ")),
+        }
+    }
+
+    fn decode_chain(&self, decoder: &Decoder, pieces: Vec<String>) -> Vec<String> {
+        match decoder {
+            Decoder::Sequence { decoders } =>
+                decoders.iter().fold(pieces, |pieces, decoder| self.decode_chain(decoder, pieces)),
+            Decoder::ByteLevel {} => {
+                let mut bytes = Vec::new();
+                for c in pieces.iter().flat_map(|piece| piece.chars()) {
+                    match self.byte_decoder.get(&c) {
+                        Some(byte) => bytes.push(*byte),
+                        None => {
+                            let mut buffer = [0; 4];
+                            bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+                        }
+                    }
+                }
+                vec![String::from_utf8_lossy(&bytes).into_owned()]
+            }
+            Decoder::ByteFallback {} => {
+                let mut result = Vec::with_capacity(pieces.len());
+                let mut bytes = Vec::new();
+                for piece in pieces {
+                    match byte_token(&piece) {
+                        Some(byte) => bytes.push(byte),
+                        None => {
+                            if !bytes.is_empty() {
+                                result.push(String::from_utf8_lossy(&bytes).into_owned());
+                                bytes.clear();
+                            }
+                            result.push(piece);
+                        }
+                    }
+                }
+                if !bytes.is_empty() {
+                    result.push(String::from_utf8_lossy(&bytes).into_owned());
+                }
+                result
+            }
+            Decoder::Fuse {} => vec![pieces.concat()],
+            Decoder::Strip { content, start, stop } =>
+                pieces
+                    .into_iter()
+                    .map(|piece| {
+                        let mut chars: Vec<char> = piece.chars().collect();
+                        let leading = chars
+                            .iter()
+                            .take(*start)
+                            .take_while(|c| *c == content)
+                            .count();
+                        chars.drain(..leading);
+                        let trailing = chars
+                            .iter()
+                            .rev()
+                            .take(*stop)
+                            .take_while(|c| *c == content)
+                            .count();
+                        chars.truncate(chars.len() - trailing);
+                        chars.into_iter().collect()
+                    })
+                    .collect(),
+            Decoder::Replace { pattern, content } =>
+                pieces
+                    .iter()
+                    .map(|piece| pattern.replace(piece, content))
+                    .collect(),
+            Decoder::Metaspace { replacement, prepend_scheme: scheme, add_prefix_space } => {
+                let scheme = prepend_scheme(*scheme, *add_prefix_space);
+                let strip_first = scheme != PrependScheme::Never;
+                pieces
+                    .iter()
+                    .enumerate()
+                    .map(|(index, piece)| {
+                        let piece = piece.replace(*replacement, " ");
+                        match piece.strip_prefix(' ') {
+                            Some(stripped) if index == 0 && strip_first => stripped.to_string(),
+                            _ => piece,
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl Tokenizer for HFTokenizer {
+    fn encode(&self, text: &str) -> Vec<Rank> {
+        self.encode_ordinary(text)
+    }
+
+    fn encode_with_special(&self, text: &str) -> Vec<Rank> {
+        self.encode_with_special_tokens(text)
+    }
+
+    fn decode(&self, tokens: &[Rank]) -> Result<String, String> {
+        HFTokenizer::decode(self, tokens.to_vec()).map_err(|err| err.to_string())
+    }
+
+    fn token_bytes(&self, token: Rank) -> Option<Vec<u8>> {
+        let piece = self.decoder_vocab.get(&token)?;
+        if self.added_tokens.contains(piece) {
+            return Some(piece.as_bytes().to_vec());
+        }
+        if let (true, Some(byte)) = (self.byte_fallback, byte_token(piece)) {
+            return Some(vec![byte]);
+        }
+        let decoder = match &self.decoder {
+            Some(decoder) => decoder,
+            None => {
+                return Some(piece.as_bytes().to_vec());
+            }
+        };
+        if decoder.is_byte_level() {
+            let mut bytes = Vec::with_capacity(piece.len());
+            for c in piece.chars() {
+                match self.byte_decoder.get(&c) {
+                    Some(byte) => bytes.push(*byte),
+                    None => {
+                        let mut buffer = [0; 4];
+                        bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+                    }
+                }
+            }
+            return Some(bytes);
+        }
+        match decoder.metaspace_replacement() {
+            Some(replacement) => Some(piece.replace(replacement, " ").into_bytes()),
+            None => Some(piece.as_bytes().to_vec()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use crate::tokenizer::{ TokenSpan, Tokenizer };
+    use super::HFTokenizer;
+
+    #[test]
+    fn test_bpe_byte_level() {
+        let json =
+            r#"{
+            "added_tokens": [{ "id": 17, "content": "<|endoftext|>", "special": true }],
+            "normalizer": null,
+            "pre_tokenizer": { "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true },
+            "decoder": { "type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true },
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": null,
+                "continuing_subword_prefix": "",
+                "end_of_word_suffix": "",
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "vocab": {
+                    "h": 0, "e": 1, "l": 2, "o": 3, "Ġ": 4, "w": 5, "r": 6, "d": 7, "he": 8, "ll": 9,
+                    "llo": 10, "hello": 11, "Ġw": 12, "or": 13, "Ġwor": 14, "ld": 15, "Ġworld": 16
+                },
+                "merges": ["h e", "l l", "ll o", "he llo", ["Ġ", "w"], ["o", "r"], ["Ġw", "or"], ["l", "d"], ["Ġwor", "ld"]]
+            }
+        }"#;
+        let tokenizer = HFTokenizer::from_json(json).unwrap();
+        assert_eq!(tokenizer.encode_ordinary("hello world"), vec![11, 16]);
+        assert_eq!(
+            tokenizer.encode_with_special_tokens("hello<|endoftext|> world"),
+            vec![11, 17, 16]
+        );
+        let allowed_special = HashSet::from(["<|endoftext|>"]);
+        assert_eq!(tokenizer.encode("hello<|endoftext|>", allowed_special), vec![11, 17]);
+        assert_eq!(tokenizer.decode(vec![11, 17, 16]).unwrap(), "hello<|endoftext|> world");
+        assert!(tokenizer.decode(vec![42]).is_err());
+
+        assert_eq!(tokenizer.encode_with_offsets("hello world"), vec![
+            TokenSpan { token: 11, start: 0, end: 5, char_start: 0, char_end: 5 },
+            TokenSpan { token: 16, start: 5, end: 11, char_start: 5, char_end: 11 },
+        ]);
+    }
+
+    #[test]
+    fn test_unigram_metaspace() {
+        let json =
+            r#"{
+            "added_tokens": [{ "id": 0, "content": "<unk>", "special": true }],
+            "normalizer": { "type": "Sequence", "normalizers": [{ "type": "NFKC" }, { "type": "Lowercase" }] },
+            "pre_tokenizer": { "type": "Metaspace", "replacement": "▁", "prepend_scheme": "always", "split": true },
+            "decoder": { "type": "Metaspace", "replacement": "▁", "prepend_scheme": "always", "split": true },
+            "model": {
+                "type": "Unigram",
+                "unk_id": 0,
+                "vocab": [
+                    ["<unk>", 0.0], ["▁", -2.0], ["▁hello", -3.0], ["▁he", -2.5], ["llo", -2.5],
+                    ["▁world", -4.0], ["h", -5.0], ["e", -5.0], ["l", -5.0], ["o", -5.0]
+                ],
+                "byte_fallback": false
+            }
+        }"#;
+        let tokenizer = HFTokenizer::from_json(json).unwrap();
+        assert_eq!(tokenizer.encode_ordinary("Hello World!"), vec![2, 5, 0]);
+        assert_eq!(tokenizer.encode_ordinary("hello hello"), vec![2, 2]);
+        assert_eq!(tokenizer.decode(vec![2, 5]).unwrap(), "hello world");
+        assert_eq!(tokenizer.encode_with_offsets("Hello World!"), vec![
+            TokenSpan { token: 2, start: 0, end: 5, char_start: 0, char_end: 5 },
+            TokenSpan { token: 5, start: 5, end: 11, char_start: 5, char_end: 11 },
+            TokenSpan { token: 0, start: 11, end: 12, char_start: 11, char_end: 12 },
+        ]);
+    }
+}
@@ -13,12 +13,41 @@
 // limitations under the License.
 
 mod encodings;
+pub mod gguf;
+pub mod hf;
+mod ranks;
+mod ranks_hash;
+pub mod stream;
+mod tokenizer;
 mod vendors;
 
-use std::collections::HashSet;
-use vendors::tiktoken::Rank;
+use std::{
+    collections::{ HashMap, HashSet },
+    fs::File,
+    path::Path,
+    sync::{ Arc, Mutex, OnceLock },
+};
 
-use crate::encodings::cl100k_base_singleton;
+use crate::encodings::{ cl100k_base_singleton, get_encoding };
+use crate::gguf::GGUFTokenizer;
+use crate::hf::HFTokenizer;
+
+pub use tokenizer::{ TokenSpan, Tokenizer };
+pub use vendors::tiktoken::{ CoreBPE, Rank };
+pub use encodings::{
+    cl100k_base,
+    encoding_for_model,
+    load_bpe,
+    CL100K_BASE,
+    CL100K_PATTERN,
+    GPT2,
+    O200K_BASE,
+    O200K_PATTERN,
+    P50K_BASE,
+    P50K_EDIT,
+    R50K_BASE,
+    R50K_PATTERN,
+};
 
 pub fn encode_gpt(text: String) -> Result<Vec<Rank>, String> {
     let allowed_special = HashSet::new();
@@ -26,23 +55,176 @@ pub fn encode_gpt(text: String) -> Result<Vec<Rank>, String> {
     Ok(ranks)
 }
 
-pub fn encode(text: String, model: String, encoding: Option<String>) -> Result<Vec<Rank>, String> {
-    if model.to_lowercase().starts_with("gpt") {
-        return encode_gpt(text);
+pub fn encode_with_encoding(text: String, encoding: &str) -> Result<Vec<Rank>, String> {
+    let bpe = match get_encoding(encoding) {
+        Some(bpe) => bpe,
+        None => {
+            return Err(format!("Encoding not supported {}", encoding));
+        }
+    };
+    Ok(bpe.encode(&text, HashSet::new()))
+}
+
+type TokenizerCache<T> = OnceLock<Mutex<HashMap<String, Arc<T>>>>;
+
+// Tokenizers are loaded once per path.
+fn cached_tokenizer<T>(
+    cache: &TokenizerCache<T>,
+    path: &str,
+    load: impl FnOnce(&str) -> Result<T, String>
+) -> Result<Arc<T>, String> {
+    let tokenizers = cache.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(tokenizer) = tokenizers.lock().map_err(|err| err.to_string())?.get(path) {
+        return Ok(tokenizer.clone());
+    }
+    let tokenizer = Arc::new(load(path)?);
+    tokenizers
+        .lock()
+        .map_err(|err| err.to_string())?
+        .insert(path.to_string(), tokenizer.clone());
+    Ok(tokenizer)
+}
+
+// Tokenizer of a local GGUF model.
+pub fn gguf_tokenizer(path: &str) -> Result<Arc<GGUFTokenizer>, String> {
+    static GGUF_TOKENIZERS: TokenizerCache<GGUFTokenizer> = OnceLock::new();
+    cached_tokenizer(&GGUF_TOKENIZERS, path, GGUFTokenizer::from_file)
+}
+
+// Tokenizer of a HuggingFace tokenizer.json file.
+pub fn hf_tokenizer(path: &str) -> Result<Arc<HFTokenizer>, String> {
+    static HF_TOKENIZERS: TokenizerCache<HFTokenizer> = OnceLock::new();
+    cached_tokenizer(&HF_TOKENIZERS, path, |path| {
+        HFTokenizer::from_file(path).map_err(|err| format!("Invalid tokenizer {}: {}", path, err))
+    })
+}
+
+// The model is either an OpenAI model name, the path of a GGUF model file or of a tokenizer.json file.
+// The encoding, if any, takes precedence over the model.
+pub fn get_tokenizer(model: &str, encoding: Option<&str>) -> Result<Arc<dyn Tokenizer>, String> {
+    if let Some(encoding) = encoding {
+        return match get_encoding(encoding) {
+            Some(bpe) => Ok(Arc::new(bpe)),
+            None => Err(format!("Encoding not supported {}", encoding)),
+        };
+    }
+    let lowercase = model.to_lowercase();
+    if lowercase.ends_with(".gguf") {
+        return Ok(gguf_tokenizer(model)?);
+    }
+    if lowercase.ends_with(".json") {
+        return Ok(hf_tokenizer(model)?);
     }
-    if encoding.is_some() {
-        return Err("Encoding not supported".to_string());
+    let encoding = encoding_for_model(model).or(lowercase.starts_with("gpt").then_some(CL100K_BASE));
+    match encoding.and_then(get_encoding) {
+        Some(bpe) => Ok(Arc::new(bpe)),
+        None => Err(format!("Model not supported {}", model)),
     }
-    Err(format!("Model not supported {}", model))
+}
+
+pub fn encode(text: String, model: String, encoding: Option<String>) -> Result<Vec<Rank>, String> {
+    Ok(get_tokenizer(&model, encoding.as_deref())?.encode(&text))
+}
+
+// Counts the tokens of a file of any size, it is read in chunks.
+pub fn count_file(path: &Path, model: &str, encoding: Option<&str>) -> Result<usize, String> {
+    let tokenizer = get_tokenizer(model, encoding)?;
+    let file = File::open(path).map_err(|err| format!("Can't open {:?}: {}", path, err))?;
+    stream
+        ::count_reader(tokenizer.as_ref(), file)
+        .map_err(|err| format!("Can't read {:?}: {}", path, err))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::encode;
+    use super::{
+        count_file,
+        encode,
+        encoding_for_model,
+        get_tokenizer,
+        CL100K_BASE,
+        O200K_BASE,
+        P50K_BASE,
+        R50K_BASE,
+    };
 
     #[test]
     fn it_works() {
         let result = encode("hello".to_string(), "gpt".to_string(), None);
-        assert_eq!(result.ok(), Some(vec![5]));
+        assert_eq!(result.ok(), Some(vec![15339]));
+    }
+
+    #[test]
+    fn test_encoding_for_model() {
+        assert_eq!(encoding_for_model("gpt-4o"), Some(O200K_BASE));
+        assert_eq!(encoding_for_model("gpt-4o-2024-08-06"), Some(O200K_BASE));
+        assert_eq!(encoding_for_model("o1-mini"), Some(O200K_BASE));
+        assert_eq!(encoding_for_model("gpt-4-turbo"), Some(CL100K_BASE));
+        assert_eq!(encoding_for_model("ft:gpt-3.5-turbo:org:id"), Some(CL100K_BASE));
+        assert_eq!(encoding_for_model("text-davinci-003"), Some(P50K_BASE));
+        assert_eq!(encoding_for_model("davinci"), Some(R50K_BASE));
+        assert_eq!(encoding_for_model("llama3"), None);
+    }
+
+    #[test]
+    fn test_encode_with_encoding() {
+        let text = "hello world".to_string();
+        assert_eq!(encode(text.clone(), "gpt-4o".to_string(), None), Ok(vec![24912, 2375]));
+        assert_eq!(encode(text.clone(), "gpt-4".to_string(), None), Ok(vec![15339, 1917]));
+        assert_eq!(encode(text.clone(), "davinci".to_string(), None), Ok(vec![31373, 995]));
+        assert_eq!(
+            encode(text.clone(), "gpt-4".to_string(), Some(P50K_BASE.to_string())),
+            Ok(vec![31373, 995])
+        );
+        assert!(encode(text.clone(), "gpt-4".to_string(), Some("unknown".to_string())).is_err());
+        assert!(encode(text, "llama3".to_string(), None).is_err());
+    }
+
+    #[test]
+    fn test_count_file() {
+        let text = "Hello world! ".repeat(10000);
+        let path = std::env::temp_dir().join("opla_tokenizer_count_file.txt");
+        std::fs::write(&path, &text).unwrap();
+        let count = count_file(&path, "gpt-4", None);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(count, Ok(get_tokenizer("gpt-4", None).unwrap().count(&text)));
+        assert!(count_file(&path, "gpt-4", None).is_err());
+    }
+
+    #[test]
+    fn test_batch() {
+        let tokenizer = get_tokenizer("gpt-4o", None).unwrap();
+        let texts: Vec<String> = (0..100).map(|i| format!("Message {} 🌍 ", i).repeat(i)).collect();
+        let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+        let expected: Vec<_> = texts.iter().map(|text| tokenizer.encode(text)).collect();
+        assert_eq!(tokenizer.encode_batch(&texts), expected);
+        assert_eq!(
+            tokenizer.count_batch(&texts),
+            expected.iter().map(|tokens| tokens.len()).collect::<Vec<_>>()
+        );
+        assert!(tokenizer.encode_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        let tokenizer = get_tokenizer("gpt-4", None).unwrap();
+        let text = "hello 🌍";
+        let tokens = tokenizer.encode(text);
+        assert_eq!(tokenizer.count(text), tokens.len());
+        assert_eq!(tokenizer.decode(&tokens), Ok(text.to_string()));
+        assert_eq!(
+            tokenizer.encode_with_special("hello<|endoftext|>"),
+            vec![15339, 100257]
+        );
+        assert!(tokenizer.encode("hello<|endoftext|>").len() > 2);
+
+        let spans = tokenizer.encode_with_offsets(text);
+        assert_eq!(spans.len(), tokens.len());
+        assert_eq!((spans[0].start, spans[0].end, spans[0].char_end), (0, 5, 5));
+        assert_eq!(spans.last().map(|s| (s.end, s.char_end)), Some((text.len(), 7)));
+        // The emoji is split over several tokens which all span its char
+        for span in spans.iter().skip(1).filter(|s| s.start > 6) {
+            assert_eq!((span.char_start, span.char_end), (6, 7));
+        }
     }
 }
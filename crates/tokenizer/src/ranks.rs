@@ -0,0 +1,118 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use crate::{ ranks_hash::{ displace, hash, reduce, short_word }, vendors::tiktoken::Rank };
+
+// Ranks of an encoding precompiled by build.rs: the token bytes in rank order, and a perfect
+// hash table from the token bytes to their rank. Integers are stored in little endian.
+pub struct StaticRanks {
+    key: u64,
+    tokens: &'static [u8],
+    // Start of each rank in tokens, followed by the end of the last one.
+    // Ranks without a token, like the special ones, are empty.
+    offsets: &'static [u8],
+    disps: &'static [u8],
+    // Slots of the hash table: the rank, the start of its token on 24 bits and its length
+    // on 8 bits, and the token bytes as a ranks_hash::short_word when it fits
+    map: &'static [u8],
+}
+
+#[inline]
+fn read_u32(bytes: &[u8], index: usize) -> u32 {
+    let start = index * 4;
+    u32::from_le_bytes(bytes[start..start + 4].try_into().unwrap())
+}
+
+impl StaticRanks {
+    pub fn len(&self) -> usize {
+        self.map.len() / 16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn token(&self, rank: Rank) -> Option<&'static [u8]> {
+        let rank = rank as usize;
+        if rank + 1 >= self.offsets.len() / 4 {
+            return None;
+        }
+        let start = read_u32(self.offsets, rank) as usize;
+        let end = read_u32(self.offsets, rank + 1) as usize;
+        if start == end {
+            return None;
+        }
+        Some(&self.tokens[start..end])
+    }
+
+    #[inline]
+    pub fn rank(&self, piece: &[u8]) -> Option<Rank> {
+        if self.is_empty() {
+            return None;
+        }
+        let (g, f1, f2) = hash(piece, self.key);
+        let index = reduce(g, self.disps.len() / 8);
+        let (d1, d2) = (read_u32(self.disps, index * 2), read_u32(self.disps, index * 2 + 1));
+        let index = reduce(displace(f1, f2, d1, d2), self.len());
+        let slot = &self.map[index * 16..index * 16 + 16];
+        let location = read_u32(slot, 1);
+        let len = (location >> 24) as usize;
+        if len != piece.len() {
+            return None;
+        }
+        let found = if len <= 8 {
+            (read_u32(slot, 2) as u64) | ((read_u32(slot, 3) as u64) << 32) == short_word(piece)
+        } else {
+            let start = (location & 0xff_ffff) as usize;
+            &self.tokens[start..start + len] == piece
+        };
+        found.then(|| read_u32(slot, 0))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static [u8], Rank)> + '_ {
+        let ranks = (self.offsets.len() / 4).saturating_sub(1) as Rank;
+        (0..ranks).filter_map(|rank| self.token(rank).map(|token| (token, rank)))
+    }
+}
+
+impl fmt::Debug for StaticRanks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StaticRanks({} tokens)", self.len())
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/ranks.rs"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static_ranks() {
+        assert_eq!(CL100K_BASE_RANKS.len(), 100256);
+        assert_eq!(CL100K_BASE_RANKS.token(15339), Some(&b"hello"[..]));
+        assert_eq!(CL100K_BASE_RANKS.rank(b"hello"), Some(15339));
+        assert_eq!(CL100K_BASE_RANKS.rank(b" world"), Some(1917));
+        assert_eq!(CL100K_BASE_RANKS.rank(b"not a token at all"), None);
+        assert_eq!(CL100K_BASE_RANKS.token(100256), None);
+        for (token, rank) in P50K_BASE_RANKS.iter() {
+            assert_eq!(P50K_BASE_RANKS.rank(token), Some(rank));
+        }
+        // Rank of <|endoftext|>
+        assert_eq!(P50K_BASE_RANKS.token(50256), None);
+        assert_eq!(P50K_BASE_RANKS.iter().count(), P50K_BASE_RANKS.len());
+    }
+}
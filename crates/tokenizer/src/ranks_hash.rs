@@ -0,0 +1,76 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Hash of the perfect hash tables of the precompiled ranks, also used by build.rs.
+// It is computed on every BPE merge, the bytes are hashed 8 at a time with a folded
+// multiply, the high bits of the product are mixed back in the low ones.
+
+const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+fn folded_multiply(a: u64, b: u64) -> u64 {
+    let full = (a as u128) * (b as u128);
+    (full as u64) ^ ((full >> 64) as u64)
+}
+
+fn read_u32(bytes: &[u8], start: usize) -> u64 {
+    u32::from_le_bytes(bytes[start..start + 4].try_into().unwrap()) as u64
+}
+
+fn read_u64(bytes: &[u8], start: usize) -> u64 {
+    u64::from_le_bytes(bytes[start..start + 8].try_into().unwrap())
+}
+
+// The bytes of a piece of at most 8 bytes, with its length they give back the piece.
+#[inline]
+pub fn short_word(bytes: &[u8]) -> u64 {
+    let len = bytes.len();
+    if len >= 4 {
+        read_u32(bytes, 0) | (read_u32(bytes, len - 4) << 32)
+    } else if len > 0 {
+        (bytes[0] as u64) | ((bytes[len / 2] as u64) << 8) | ((bytes[len - 1] as u64) << 16)
+    } else {
+        0
+    }
+}
+
+// The bucket and the two displacement hashes of the bytes. Short pieces, the most looked up,
+// are read with overlapping loads instead of being copied in a padded word.
+#[inline]
+pub fn hash(bytes: &[u8], key: u64) -> (u32, u32, u32) {
+    let len = bytes.len();
+    let mut h = key;
+    let last = if len > 8 {
+        for start in (0..len - 8).step_by(8) {
+            h = folded_multiply(h ^ read_u64(bytes, start), SEED);
+        }
+        read_u64(bytes, len - 8)
+    } else {
+        short_word(bytes)
+    };
+    let lower = folded_multiply(h ^ last, SEED ^ (len as u64));
+    let upper = folded_multiply(lower, key ^ SEED);
+    ((lower >> 32) as u32, lower as u32, upper as u32)
+}
+
+// Maps the hash to 0..len with a multiply instead of a modulo.
+#[inline]
+pub fn reduce(hash: u32, len: usize) -> usize {
+    (((hash as u64) * (len as u64)) >> 32) as usize
+}
+
+// Multiplied by an odd constant so the displacements change the high bits kept by reduce.
+#[inline]
+pub fn displace(f1: u32, f2: u32, d1: u32, d2: u32) -> u32 {
+    d2.wrapping_add(f1.wrapping_mul(d1)).wrapping_add(f2).wrapping_mul(0x9e37_79b9)
+}
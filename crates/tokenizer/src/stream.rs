@@ -0,0 +1,237 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{ self, Read };
+
+use crate::{ tokenizer::Tokenizer, vendors::tiktoken::Rank };
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+// Pending text size after which it is encoded even without a split point
+pub const MAX_PENDING_SIZE: usize = 64 * 1024;
+
+// Encodes a text received in chunks, without keeping the whole text in memory.
+// The pending text starts at the last split point given by the tokenizer, so a piece
+// straddling two chunks is encoded once complete. The tokens are the ones of the whole text,
+// except when the tokenizer can't split the text (Llama models, or no spaces in the text):
+// past MAX_PENDING_SIZE the pending text is cut at its last newline, or as a whole,
+// and the tokens around the cut may differ.
+pub struct StreamEncoder<'a, T: Tokenizer + ?Sized> {
+    tokenizer: &'a T,
+    text: String,
+    // Incomplete UTF-8 sequence at the end of the last bytes chunk
+    bytes: Vec<u8>,
+    count: usize,
+    tokens: Option<Vec<Rank>>,
+}
+
+impl<'a, T: Tokenizer + ?Sized> StreamEncoder<'a, T> {
+    // Only counts the tokens.
+    pub fn new(tokenizer: &'a T) -> Self {
+        StreamEncoder {
+            tokenizer,
+            text: String::new(),
+            bytes: vec![],
+            count: 0,
+            tokens: None,
+        }
+    }
+
+    pub fn with_tokens(tokenizer: &'a T) -> Self {
+        let mut encoder = Self::new(tokenizer);
+        encoder.tokens = Some(vec![]);
+        encoder
+    }
+
+    // Tokens of the text encoded so far.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    fn encode(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        match &mut self.tokens {
+            Some(tokens) => {
+                let encoded = self.tokenizer.encode(text);
+                self.count += encoded.len();
+                tokens.extend(encoded);
+            }
+            None => {
+                self.count += self.tokenizer.count(text);
+            }
+        }
+    }
+
+    pub fn push_str(&mut self, chunk: &str) {
+        self.text.push_str(chunk);
+        let mut len = self.tokenizer.stable_len(&self.text);
+        if len == 0 && self.text.len() > MAX_PENDING_SIZE {
+            len = self.text.rfind('\n').map_or(self.text.len(), |index| index + 1);
+        }
+        if len > 0 {
+            let text: String = self.text.drain(..len).collect();
+            self.encode(&text);
+        }
+    }
+
+    // Invalid UTF-8 sequences are replaced with U+FFFD.
+    pub fn push_bytes(&mut self, chunk: &[u8]) {
+        self.bytes.extend_from_slice(chunk);
+        let mut text = String::new();
+        let mut start = 0;
+        loop {
+            match std::str::from_utf8(&self.bytes[start..]) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    start = self.bytes.len();
+                    break;
+                }
+                Err(error) => {
+                    let valid_end = start + error.valid_up_to();
+                    // Safe, the bytes were validated
+                    let valid = unsafe { std::str::from_utf8_unchecked(&self.bytes[start..valid_end]) };
+                    text.push_str(valid);
+                    start = valid_end;
+                    match error.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            start += len;
+                        }
+                        // Completed by the next chunk
+                        None => {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.bytes.drain(..start);
+        self.push_str(&text);
+    }
+
+    pub fn read<R: Read>(&mut self, mut reader: R) -> io::Result<()> {
+        let mut buffer = vec![0; READ_BUFFER_SIZE];
+        loop {
+            let len = match reader.read(&mut buffer) {
+                Ok(0) => {
+                    return Ok(());
+                }
+                Ok(len) => len,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+            self.push_bytes(&buffer[..len]);
+        }
+    }
+
+    // Encodes the pending text, returns the tokens count and the tokens if they are kept.
+    pub fn finish(mut self) -> (usize, Option<Vec<Rank>>) {
+        if !self.bytes.is_empty() {
+            let bytes = std::mem::take(&mut self.bytes);
+            self.text.push_str(&String::from_utf8_lossy(&bytes));
+        }
+        let text = std::mem::take(&mut self.text);
+        self.encode(&text);
+        (self.count, self.tokens)
+    }
+}
+
+pub fn count_reader<T: Tokenizer + ?Sized, R: Read>(
+    tokenizer: &T,
+    reader: R
+) -> io::Result<usize> {
+    let mut encoder = StreamEncoder::new(tokenizer);
+    encoder.read(reader)?;
+    Ok(encoder.finish().0)
+}
+
+pub fn encode_reader<T: Tokenizer + ?Sized, R: Read>(
+    tokenizer: &T,
+    reader: R
+) -> io::Result<Vec<Rank>> {
+    let mut encoder = StreamEncoder::with_tokens(tokenizer);
+    encoder.read(reader)?;
+    Ok(encoder.finish().1.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ encodings::{ cl100k_base_singleton, o200k_base_singleton }, gguf };
+    use super::*;
+
+    const TEXT: &str =
+        "Hello world!  It's 2024,\n\n  and    tokens   straddle chunks 🌍🚀.\r\n\tdon't   they?   ";
+
+    #[test]
+    fn test_stream_chunks() {
+        for bpe in [cl100k_base_singleton(), o200k_base_singleton()] {
+            let expected = bpe.encode_ordinary(TEXT);
+            let bytes = TEXT.as_bytes();
+            for size in 1..12 {
+                let mut encoder = StreamEncoder::with_tokens(bpe);
+                for chunk in bytes.chunks(size) {
+                    encoder.push_bytes(chunk);
+                }
+                assert_eq!(encoder.finish(), (expected.len(), Some(expected.clone())));
+            }
+            for split in (0..=TEXT.len()).filter(|i| TEXT.is_char_boundary(*i)) {
+                let mut encoder = StreamEncoder::new(bpe);
+                encoder.push_str(&TEXT[..split]);
+                encoder.push_str(&TEXT[split..]);
+                assert_eq!(encoder.finish(), (expected.len(), None));
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_reader() {
+        let bpe = cl100k_base_singleton();
+        let text = TEXT.repeat(5000);
+        let expected = bpe.encode_ordinary(&text);
+        assert_eq!(count_reader(bpe, text.as_bytes()).unwrap(), expected.len());
+        assert_eq!(encode_reader(bpe, text.as_bytes()).unwrap(), expected);
+
+        // Invalid UTF-8 is replaced
+        let bytes = [b"abc ".as_slice(), &[0xff], b" def \xf0\x9f"].concat();
+        let expected = bpe.encode_ordinary(&String::from_utf8_lossy(&bytes));
+        assert_eq!(encode_reader(bpe, bytes.as_slice()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_stream_without_split_point() {
+        let chunk_size = 4096;
+        let bpe = cl100k_base_singleton();
+        let llama = gguf::tests::llama_tokenizer();
+        let texts = ["hello,world;".repeat(20_000), "hello!\n".repeat(40_000)];
+        for (tokenizer, text) in [
+            (bpe as &dyn Tokenizer, &texts[0]),
+            (&llama as &dyn Tokenizer, &texts[0]),
+            (&llama as &dyn Tokenizer, &texts[1]),
+        ] {
+            let mut encoder = StreamEncoder::new(tokenizer);
+            for chunk in text.as_bytes().chunks(chunk_size) {
+                encoder.push_bytes(chunk);
+                assert!(encoder.text.len() <= MAX_PENDING_SIZE + chunk_size);
+            }
+            let expected = tokenizer.count(text);
+            let (count, _) = encoder.finish();
+            assert!(count.abs_diff(expected) <= expected / 100, "{} != {}", count, expected);
+        }
+    }
+}
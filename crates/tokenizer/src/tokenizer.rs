@@ -0,0 +1,218 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rayon::prelude::*;
+use serde::{ Deserialize, Serialize };
+
+use crate::vendors::tiktoken::{ CoreBPE, Rank };
+
+// Span of a token in the encoded text.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenSpan {
+    pub token: Rank,
+    // Byte range
+    pub start: usize,
+    pub end: usize,
+    // Char range, a char split over several tokens belongs to each of them
+    pub char_start: usize,
+    pub char_end: usize,
+}
+
+pub trait Tokenizer: Send + Sync {
+    // Special tokens in the text are encoded as ordinary text.
+    fn encode(&self, text: &str) -> Vec<Rank>;
+
+    fn encode_with_special(&self, text: &str) -> Vec<Rank>;
+
+    fn decode(&self, tokens: &[Rank]) -> Result<String, String>;
+
+    // Bytes of the text represented by the token, as found in the encoded text.
+    fn token_bytes(&self, token: Rank) -> Option<Vec<u8>>;
+
+    fn count(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+
+    // The texts are encoded in parallel on the rayon thread pool.
+    fn encode_batch(&self, texts: &[&str]) -> Vec<Vec<Rank>> {
+        texts
+            .par_iter()
+            .map(|text| self.encode(text))
+            .collect()
+    }
+
+    fn count_batch(&self, texts: &[&str]) -> Vec<usize> {
+        texts
+            .par_iter()
+            .map(|text| self.count(text))
+            .collect()
+    }
+
+    // Token of the start of the prompt.
+    fn bos_token(&self) -> Option<Rank> {
+        None
+    }
+
+    // Whether the inference server adds the bos token before the prompt.
+    fn add_bos_token(&self) -> bool {
+        false
+    }
+
+    // Length of the start of the text whose tokens don't depend on the text that follows,
+    // a long text can be encoded in chunks split there. Zero if the text can't be split.
+    fn stable_len(&self, _text: &str) -> usize {
+        0
+    }
+
+    // The spans are exact for byte-level tokenizers. For tokenizers that normalize the text,
+    // a token that can't be found in the text spans the next char.
+    fn encode_with_offsets(&self, text: &str) -> Vec<TokenSpan> {
+        let tokens = self.encode(text);
+        let bytes = text.as_bytes();
+        let mut offsets = Vec::with_capacity(tokens.len());
+        let mut offset = 0;
+        for token in tokens {
+            let piece = self.token_bytes(token).unwrap_or_default();
+            let remaining = &bytes[offset..];
+            let matches = |piece: &[u8]| {
+                remaining.len() >= piece.len() && remaining[..piece.len()].eq_ignore_ascii_case(piece)
+            };
+            let len = if matches(&piece) {
+                piece.len()
+            } else if piece.first() == Some(&b' ') && matches(&piece[1..]) {
+                // Space prefix added by the tokenizer
+                piece.len() - 1
+            } else {
+                (offset + 1..=text.len())
+                    .find(|end| text.is_char_boundary(*end))
+                    .map_or(0, |end| end - offset)
+            };
+            offsets.push((token, offset, offset + len));
+            offset += len;
+        }
+        spans_from_offsets(text, offsets)
+    }
+}
+
+// Start of the last space following a non-whitespace char. With the tiktoken and GPT-2 split
+// patterns, no regex piece straddles it whatever the text that follows.
+pub(crate) fn last_word_boundary(text: &str) -> usize {
+    let mut end = text.len();
+    while let Some(index) = text[..end].rfind(' ') {
+        match text[..index].chars().next_back() {
+            Some(c) if !c.is_whitespace() => {
+                return index;
+            }
+            _ => {
+                end = index;
+            }
+        }
+    }
+    0
+}
+
+fn spans_from_offsets(text: &str, offsets: Vec<(Rank, usize, usize)>) -> Vec<TokenSpan> {
+    // Number of chars starting strictly before each byte offset
+    let mut chars_before = Vec::with_capacity(text.len() + 1);
+    let mut count = 0;
+    for offset in 0..=text.len() {
+        chars_before.push(count);
+        if offset < text.len() && text.is_char_boundary(offset) {
+            count += 1;
+        }
+    }
+    offsets
+        .into_iter()
+        .map(|(token, start, end)| TokenSpan {
+            token,
+            start,
+            end,
+            // A start inside a char belongs to that char
+            char_start: if text.is_char_boundary(start) {
+                chars_before[start]
+            } else {
+                chars_before[start] - 1
+            },
+            char_end: chars_before[end],
+        })
+        .collect()
+}
+
+impl<T: Tokenizer + ?Sized> Tokenizer for &T {
+    fn encode(&self, text: &str) -> Vec<Rank> {
+        (**self).encode(text)
+    }
+
+    fn encode_with_special(&self, text: &str) -> Vec<Rank> {
+        (**self).encode_with_special(text)
+    }
+
+    fn decode(&self, tokens: &[Rank]) -> Result<String, String> {
+        (**self).decode(tokens)
+    }
+
+    fn token_bytes(&self, token: Rank) -> Option<Vec<u8>> {
+        (**self).token_bytes(token)
+    }
+
+    fn count(&self, text: &str) -> usize {
+        (**self).count(text)
+    }
+
+    fn encode_batch(&self, texts: &[&str]) -> Vec<Vec<Rank>> {
+        (**self).encode_batch(texts)
+    }
+
+    fn count_batch(&self, texts: &[&str]) -> Vec<usize> {
+        (**self).count_batch(texts)
+    }
+
+    fn bos_token(&self) -> Option<Rank> {
+        (**self).bos_token()
+    }
+
+    fn add_bos_token(&self) -> bool {
+        (**self).add_bos_token()
+    }
+
+    fn stable_len(&self, text: &str) -> usize {
+        (**self).stable_len(text)
+    }
+
+    fn encode_with_offsets(&self, text: &str) -> Vec<TokenSpan> {
+        (**self).encode_with_offsets(text)
+    }
+}
+
+impl Tokenizer for CoreBPE {
+    fn encode(&self, text: &str) -> Vec<Rank> {
+        self.encode_ordinary(text)
+    }
+
+    fn encode_with_special(&self, text: &str) -> Vec<Rank> {
+        self.encode_with_special_tokens(text)
+    }
+
+    fn decode(&self, tokens: &[Rank]) -> Result<String, String> {
+        CoreBPE::decode(self, tokens.to_vec()).map_err(|err| err.to_string())
+    }
+
+    fn token_bytes(&self, token: Rank) -> Option<Vec<u8>> {
+        self.decode_single_token_bytes(token).ok()
+    }
+
+    fn stable_len(&self, text: &str) -> usize {
+        last_word_boundary(text)
+    }
+}
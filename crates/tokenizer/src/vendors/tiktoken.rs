@@ -1,34 +1,40 @@
 // https://raw.githubusercontent.com/openai/tiktoken/main/src/lib.rs
-
-// This check is new and seems buggy (possibly with PyO3 interaction)
-#![allow(clippy::borrow_deref_ref)]
+// The Python bindings are replaced with their Rust API counterparts.
 
 use anyhow::anyhow;
 use std::collections::HashSet;
 use std::num::NonZeroU64;
+use std::sync::OnceLock;
 use std::thread;
 
 use fancy_regex::Regex;
 use rustc_hash::FxHashMap as HashMap;
 
-#[cfg(feature = "python")]
-use pyo3::exceptions;
-
-#[cfg(feature = "python")]
-use pyo3::prelude::*;
+use crate::ranks::StaticRanks;
 
-#[cfg(feature = "python")]
-use pyo3::pyclass;
+pub type Rank = u32;
 
-#[cfg(feature = "python")]
-use pyo3::PyResult;
+/// Lookup of the rank of a piece of bytes, in a map parsed at runtime or in ranks precompiled
+/// at build time
+pub trait Ranks {
+    fn rank(&self, piece: &[u8]) -> Option<Rank>;
+}
 
-#[cfg(feature = "python")]
-use pyo3::types::{ PyBytes, PyList, PyTuple };
+impl Ranks for HashMap<Vec<u8>, Rank> {
+    #[inline]
+    fn rank(&self, piece: &[u8]) -> Option<Rank> {
+        self.get(piece).copied()
+    }
+}
 
-pub type Rank = u32;
+impl Ranks for StaticRanks {
+    #[inline]
+    fn rank(&self, piece: &[u8]) -> Option<Rank> {
+        StaticRanks::rank(self, piece)
+    }
+}
 
-fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
+fn _byte_pair_merge<R: Ranks + ?Sized>(ranks: &R, piece: &[u8]) -> Vec<(usize, Rank)> {
     // This is a vector of (start, rank).
     // The rank is of the pair starting at position start.
     let mut parts = Vec::with_capacity(piece.len() + 1);
@@ -38,7 +44,7 @@ fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize,
     // merge priority from token index or to prevent specific token merges.
     let mut min_rank: (Rank, usize) = (Rank::MAX, usize::MAX);
     for i in 0..piece.len() - 1 {
-        let rank = *ranks.get(&piece[i..i + 2]).unwrap_or(&Rank::MAX);
+        let rank = ranks.rank(&piece[i..i + 2]).unwrap_or(Rank::MAX);
         if rank < min_rank.0 {
             min_rank = (rank, i);
         }
@@ -53,7 +59,7 @@ fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize,
             if i + 3 < parts.len() {
                 // Similar to `piece[i..i + 2]` above. The +3 is because we haven't yet deleted
                 // parts[i + 1], see comment in the main loop.
-                *ranks.get(&piece[parts[i].0..parts[i + 3].0]).unwrap_or(&Rank::MAX)
+                ranks.rank(&piece[parts[i].0..parts[i + 3].0]).unwrap_or(Rank::MAX)
             } else {
                 Rank::MAX
             }
@@ -84,11 +90,11 @@ fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize,
     parts
 }
 
-pub fn byte_pair_encode(piece: &[u8], ranks: &HashMap<Vec<u8>, Rank>) -> Vec<Rank> {
+pub fn byte_pair_encode<R: Ranks + ?Sized>(piece: &[u8], ranks: &R) -> Vec<Rank> {
     assert!(piece.len() > 1);
-    _byte_pair_merge(&ranks, &piece)
+    _byte_pair_merge(ranks, piece)
         .windows(2)
-        .map(|part| ranks[&piece[part[0].0..part[1].0]])
+        .map(|part| ranks.rank(&piece[part[0].0..part[1].0]).unwrap())
         .collect()
 }
 
@@ -150,29 +156,43 @@ fn hash_current_thread() -> usize {
 
 const MAX_NUM_THREADS: usize = 128;
 
-#[cfg(feature = "python")]
-#[pyclass]
-struct CoreBPE {
-    encoder: HashMap<Vec<u8>, Rank>,
-    special_tokens_encoder: HashMap<String, Rank>,
-    decoder: HashMap<Rank, Vec<u8>>,
-    special_tokens_decoder: HashMap<Rank, Vec<u8>>,
-    regex_tls: Vec<Regex>,
-    special_regex_tls: Vec<Regex>,
-    sorted_token_bytes: Vec<Vec<u8>>,
+#[derive(Debug, Clone)]
+enum Vocab {
+    Map {
+        encoder: HashMap<Vec<u8>, Rank>,
+        decoder: HashMap<Rank, Vec<u8>>,
+    },
+    Static(&'static StaticRanks),
+}
+
+impl Vocab {
+    fn token(&self, rank: Rank) -> Option<&[u8]> {
+        match self {
+            Vocab::Map { decoder, .. } => decoder.get(&rank).map(|bytes| bytes.as_slice()),
+            Vocab::Static(ranks) => ranks.token(rank),
+        }
+    }
+}
+
+impl Ranks for Vocab {
+    #[inline]
+    fn rank(&self, piece: &[u8]) -> Option<Rank> {
+        match self {
+            Vocab::Map { encoder, .. } => encoder.rank(piece),
+            Vocab::Static(ranks) => ranks.rank(piece),
+        }
+    }
 }
 
-#[cfg(not(feature = "python"))]
 #[derive(Debug, Clone)]
-#[allow(dead_code)] // sorted_token_bytes is used but is read only in python version
 pub struct CoreBPE {
-    encoder: HashMap<Vec<u8>, Rank>,
+    encoder: Vocab,
     special_tokens_encoder: HashMap<String, Rank>,
-    decoder: HashMap<Rank, Vec<u8>>,
     special_tokens_decoder: HashMap<Rank, Vec<u8>>,
     regex_tls: Vec<Regex>,
     special_regex_tls: Vec<Regex>,
-    sorted_token_bytes: Vec<Vec<u8>>,
+    // Only needed by the unstable encoding, sorted on first use
+    sorted_token_bytes: OnceLock<Vec<Vec<u8>>>,
 }
 
 impl CoreBPE {
@@ -190,9 +210,9 @@ impl CoreBPE {
     fn _decode_native(&self, tokens: &[Rank]) -> Vec<u8> {
         let mut ret = Vec::with_capacity(tokens.len() * 2);
         for token in tokens {
-            let token_bytes = self.decoder
-                .get(token)
-                .unwrap_or_else(|| &self.special_tokens_decoder[token]);
+            let token_bytes = self.encoder
+                .token(*token)
+                .unwrap_or_else(|| self.special_tokens_decoder[token].as_slice());
             ret.extend(token_bytes);
         }
         ret
@@ -200,10 +220,10 @@ impl CoreBPE {
 
     fn _decode_native_and_split(&self, tokens: Vec<Rank>) -> impl Iterator<Item = Vec<u8>> + '_ {
         tokens.into_iter().map(|token| {
-            let token_bytes = self.decoder
-                .get(&token)
-                .unwrap_or_else(|| &self.special_tokens_decoder[&token]);
-            token_bytes.clone()
+            let token_bytes = self.encoder
+                .token(token)
+                .unwrap_or_else(|| self.special_tokens_decoder[&token].as_slice());
+            token_bytes.to_vec()
         })
     }
 
@@ -214,8 +234,8 @@ impl CoreBPE {
         let mut ret = vec![];
         for mat in regex.find_iter(text) {
             let piece = mat.unwrap().as_str().as_bytes();
-            match self.encoder.get(piece) {
-                Some(token) => ret.push(*token),
+            match self.encoder.rank(piece) {
+                Some(token) => ret.push(token),
                 None => ret.extend(&byte_pair_encode(piece, &self.encoder)),
             }
         }
@@ -252,9 +272,9 @@ impl CoreBPE {
             // Okay, here we go, compare this logic to _encode_ordinary_native
             for mat in regex.find_iter(&text[start..end]) {
                 let piece = mat.unwrap().as_str().as_bytes();
-                if let Some(token) = self.encoder.get(piece) {
+                if let Some(token) = self.encoder.rank(piece) {
                     last_piece_token_len = 1;
-                    ret.push(*token);
+                    ret.push(token);
                     continue;
                 }
                 let tokens = byte_pair_encode(piece, &self.encoder);
@@ -295,9 +315,9 @@ impl CoreBPE {
         // pattern. This can e.g. cause "\n" + " " to become "\n \n".
         // Here is a quick and dirty fix:
         {
-            let token_is_all_space = |token| {
-                self.decoder
-                    .get(token)
+            let token_is_all_space = |token: &Rank| {
+                self.encoder
+                    .token(*token)
                     .map(|token_bytes| {
                         token_bytes
                             .iter()
@@ -354,14 +374,15 @@ impl CoreBPE {
         // This is the easy bit. Just find all single tokens that start with unstable_bytes
         // (including tokens that exactly match unstable_bytes)
         // Separating this from the loop below helps with performance in a common case.
-        let mut point = self.sorted_token_bytes.partition_point(
+        let sorted_token_bytes = self.token_byte_values();
+        let mut point = sorted_token_bytes.partition_point(
             |x| x.as_slice() < unstable_bytes.as_slice()
         );
         while
-            point < self.sorted_token_bytes.len() &&
-            self.sorted_token_bytes[point].starts_with(&unstable_bytes)
+            point < sorted_token_bytes.len() &&
+            sorted_token_bytes[point].starts_with(&unstable_bytes)
         {
-            completions.insert(vec![self.encoder[self.sorted_token_bytes[point].as_slice()]]);
+            completions.insert(vec![self.encoder.rank(&sorted_token_bytes[point]).unwrap()]);
             point += 1;
         }
 
@@ -371,13 +392,13 @@ impl CoreBPE {
         for i in 1..unstable_bytes.len() {
             let prefix = &unstable_bytes[..i];
             let suffix = &unstable_bytes[i..];
-            let mut point = self.sorted_token_bytes.partition_point(|x| x.as_slice() < suffix);
+            let mut point = sorted_token_bytes.partition_point(|x| x.as_slice() < suffix);
             // TODO: Perf optimisation if suffix starts with " "?
             while
-                point < self.sorted_token_bytes.len() &&
-                self.sorted_token_bytes[point].starts_with(suffix)
+                point < sorted_token_bytes.len() &&
+                sorted_token_bytes[point].starts_with(suffix)
             {
-                let possibility = [prefix, self.sorted_token_bytes[point].as_slice()].concat();
+                let possibility = [prefix, sorted_token_bytes[point].as_slice()].concat();
                 let encoded = match std::str::from_utf8(&possibility) {
                     // Morally, this is byte_pair_encode(&possibility, &self.encoder)
                     // But we might have introduced a regex split which would prevent merges.
@@ -401,7 +422,7 @@ impl CoreBPE {
                 let mut seq_len = 0;
                 for token in encoded {
                     seq.push(token);
-                    seq_len += self.decoder[&token].len();
+                    seq_len += self.encoder.token(token).unwrap().len();
                     if seq_len >= unstable_bytes.len() {
                         break;
                     }
@@ -423,7 +444,7 @@ impl CoreBPE {
             let last_decoded = bstr::decode_last_utf8(unstable_bytes.as_slice());
             if
                 unstable_bytes.len() - last_decoded.1 > 0 &&
-                last_decoded.0.map_or(false, |c| c.is_whitespace())
+                last_decoded.0.is_some_and(|c| c.is_whitespace())
             {
                 let mut reencoded = byte_pair_encode(
                     &unstable_bytes[..unstable_bytes.len() - last_decoded.1],
@@ -443,7 +464,6 @@ impl CoreBPE {
     }
 }
 
-#[cfg(not(feature = "python"))]
 impl CoreBPE {
     // ====================
     // Encoding
@@ -451,11 +471,34 @@ impl CoreBPE {
 
     // This function a copy of the similar function in python API, but it return
     // Rust's results and errors
-    #[cfg(not(feature = "python"))]
     pub fn new(
         encoder: HashMap<Vec<u8>, Rank>,
         special_tokens_encoder: HashMap<String, Rank>,
         pattern: &str
+    ) -> Result<Self, anyhow::Error> {
+        let decoder: HashMap<Rank, Vec<u8>> = encoder
+            .iter()
+            .map(|(k, v)| (*v, k.clone()))
+            .collect();
+
+        assert!(encoder.len() == decoder.len());
+
+        Self::with_vocab(Vocab::Map { encoder, decoder }, special_tokens_encoder, pattern)
+    }
+
+    /// Build from ranks precompiled at build time, without parsing or copying them
+    pub fn from_static(
+        ranks: &'static StaticRanks,
+        special_tokens_encoder: HashMap<String, Rank>,
+        pattern: &str
+    ) -> Result<Self, anyhow::Error> {
+        Self::with_vocab(Vocab::Static(ranks), special_tokens_encoder, pattern)
+    }
+
+    fn with_vocab(
+        encoder: Vocab,
+        special_tokens_encoder: HashMap<String, Rank>,
+        pattern: &str
     ) -> Result<Self, anyhow::Error> {
         let regex = Regex::new(pattern).map_err(|e| anyhow!(e.to_string()))?;
 
@@ -467,65 +510,157 @@ impl CoreBPE {
             Regex::new(&_parts.join("|")).map_err(|e| anyhow!(e.to_string()))?
         };
 
-        let decoder: HashMap<Rank, Vec<u8>> = encoder
-            .iter()
-            .map(|(k, v)| (*v, k.clone()))
-            .collect();
-
-        assert!(encoder.len() == decoder.len());
-
         let special_tokens_decoder: HashMap<Rank, Vec<u8>> = special_tokens_encoder
             .iter()
             .map(|(k, v)| (*v, k.as_bytes().to_vec()))
             .collect();
 
-        // Clone because I don't know how to tell Rust I'm not going to change the map
-        let mut sorted_token_bytes: Vec<Vec<u8>> = encoder.keys().cloned().collect();
-        sorted_token_bytes.sort();
-
         Ok(CoreBPE {
             encoder,
             special_tokens_encoder,
-            decoder,
             special_tokens_decoder,
             regex_tls: (0..MAX_NUM_THREADS).map(|_| regex.clone()).collect(),
             special_regex_tls: (0..MAX_NUM_THREADS).map(|_| special_regex.clone()).collect(),
-            sorted_token_bytes,
+            sorted_token_bytes: OnceLock::new(),
         })
     }
 
-    /*
     pub fn encode_ordinary(&self, text: &str) -> Vec<Rank> {
         self._encode_ordinary_native(text)
-    } */
+    }
 
     pub fn encode(&self, text: &str, allowed_special: HashSet<&str>) -> Vec<Rank> {
         self._encode_native(text, &allowed_special).0
     }
 
-    /*
     pub fn encode_with_special_tokens(&self, text: &str) -> Vec<Rank> {
         let allowed_special = self.special_tokens_encoder
             .keys()
             .map(|s| s.as_str())
             .collect();
         self._encode_native(text, &allowed_special).0
-    } */
+    }
+
+    /// Encode bytes that may end with an incomplete UTF-8 sequence, the invalid bytes are
+    /// merged with the tokens of the last piece
+    pub fn encode_bytes(&self, bytes: &[u8]) -> Vec<Rank> {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => self._encode_ordinary_native(text),
+            Err(e) => {
+                let text = unsafe { std::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) };
+                let (tokens, last_piece_token_len) = self._encode_native(text, &HashSet::new());
+                let (mut tokens, last_piece_token_len) = self._increase_last_piece_token_len(
+                    tokens,
+                    last_piece_token_len
+                );
+                let mut unstable_bytes = Vec::new();
+                if !tokens.is_empty() && last_piece_token_len > 0 {
+                    // Lop off the tokens from the last piece and run BPE on the remaining bytes
+                    // Somewhat niche, but this may not be correct if we'd have had a regex
+                    // split between the valid UTF-8 and the invalid bytes
+                    unstable_bytes = self._decode_native(
+                        &tokens[tokens.len() - last_piece_token_len..]
+                    );
+                    tokens.truncate(tokens.len() - last_piece_token_len);
+                }
+                unstable_bytes.extend_from_slice(&bytes[e.valid_up_to()..]);
+                match self.encoder.rank(&unstable_bytes) {
+                    Some(token) => tokens.push(token),
+                    None => tokens.extend(&byte_pair_encode(&unstable_bytes, &self.encoder)),
+                }
+                tokens
+            }
+        }
+    }
+
+    /// Encode the text, returning the stable tokens and the possible token sequences
+    /// completing the unstable end of the text
+    pub fn encode_with_unstable(
+        &self,
+        text: &str,
+        allowed_special: HashSet<&str>
+    ) -> (Vec<Rank>, HashSet<Vec<Rank>>) {
+        self._encode_unstable_native(text, &allowed_special)
+    }
+
+    /// Token of the exact piece of bytes, special tokens included
+    pub fn encode_single_token(&self, piece: &[u8]) -> Result<Rank, anyhow::Error> {
+        if let Some(token) = self.encoder.rank(piece) {
+            return Ok(token);
+        }
+        if let Ok(piece_str) = std::str::from_utf8(piece) {
+            if let Some(token) = self.special_tokens_encoder.get(piece_str).copied() {
+                return Ok(token);
+            }
+        }
+        Err(anyhow!("Unknown token for piece: {}", bstr::BStr::new(piece)))
+    }
+
+    /// Tokens of a piece of bytes without regex splitting
+    pub fn encode_single_piece(&self, piece: &[u8]) -> Vec<Rank> {
+        if let Some(token) = self.encoder.rank(piece) {
+            return vec![token];
+        }
+        byte_pair_encode(piece, &self.encoder)
+    }
 
-    /*
     // ====================
     // Decoding
     // ====================
 
+    /// Bytes of a single token, special tokens included
+    pub fn decode_single_token_bytes(&self, token: Rank) -> Result<Vec<u8>, anyhow::Error> {
+        if let Some(bytes) = self.encoder.token(token) {
+            return Ok(bytes.to_vec());
+        }
+        if let Some(bytes) = self.special_tokens_decoder.get(&token) {
+            return Ok(bytes.clone());
+        }
+        Err(anyhow!("Invalid token for decoding: {}", token))
+    }
+
+    /// Decode a vector of tokens into bytes, failing on unknown tokens
+    pub fn decode_bytes(&self, tokens: &[Rank]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut ret = Vec::with_capacity(tokens.len() * 2);
+        for token in tokens {
+            let token_bytes = self.encoder
+                .token(*token)
+                .or_else(|| self.special_tokens_decoder.get(token).map(|bytes| bytes.as_slice()));
+            match token_bytes {
+                Some(token_bytes) => ret.extend(token_bytes),
+                None => {
+                    return Err(anyhow!("Invalid token for decoding: {}", token));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
     /// Decode a vector of tokens into a valid UTF-8 String
     ///
-    /// If unicode validation is not wanted, see _decode_native.
+    /// If unicode validation is not wanted, see decode_bytes.
     pub fn decode(&self, tokens: Vec<Rank>) -> Result<String, anyhow::Error> {
-        match String::from_utf8(self._decode_native(&tokens)) {
+        match String::from_utf8(self.decode_bytes(&tokens)?) {
             Ok(text) => Ok(text),
             Err(e) => Err(anyhow!("Unable to decode into a valid UTF-8 string: {}", e)),
         }
-    } */
+    }
+
+    // ====================
+    // Miscellaneous
+    // ====================
+
+    /// Bytes of the ordinary tokens, sorted
+    pub fn token_byte_values(&self) -> &[Vec<u8>] {
+        self.sorted_token_bytes.get_or_init(|| {
+            let mut sorted_token_bytes: Vec<Vec<u8>> = match &self.encoder {
+                Vocab::Map { encoder, .. } => encoder.keys().cloned().collect(),
+                Vocab::Static(ranks) => ranks.iter().map(|(token, _)| token.to_vec()).collect(),
+            };
+            sorted_token_bytes.sort();
+            sorted_token_bytes
+        })
+    }
 
     /*
     /// Tokenize a string and return the decoded tokens using the correct BPE model.
@@ -612,186 +747,15 @@ impl CoreBPE {
     } */
 }
 
-#[cfg(feature = "python")]
-#[pymethods]
-impl CoreBPE {
-    #[new]
-    fn new(
-        encoder: HashMap<Vec<u8>, Rank>,
-        special_tokens_encoder: HashMap<String, Rank>,
-        pattern: &str
-    ) -> PyResult<Self> {
-        let regex = Regex::new(pattern).map_err(|e|
-            PyErr::new::<exceptions::PyValueError, _>(e.to_string())
-        )?;
-
-        let special_regex = {
-            let _parts = special_tokens_encoder
-                .keys()
-                .map(|s| fancy_regex::escape(s))
-                .collect::<Vec<_>>();
-            Regex::new(&_parts.join("|")).map_err(|e|
-                PyErr::new::<exceptions::PyValueError, _>(e.to_string())
-            )?
-        };
-
-        let decoder: HashMap<Rank, Vec<u8>> = encoder
-            .iter()
-            .map(|(k, v)| (*v, k.clone()))
-            .collect();
-
-        assert!(
-            encoder.len() == decoder.len(),
-            "Encoder and decoder must be of equal length; maybe you had duplicate token indices in your encoder?"
-        );
-
-        let special_tokens_decoder: HashMap<Rank, Vec<u8>> = special_tokens_encoder
-            .iter()
-            .map(|(k, v)| (*v, k.as_bytes().to_vec()))
-            .collect();
-
-        // Clone because I don't know how to tell Rust I'm not going to change the map
-        let mut sorted_token_bytes: Vec<Vec<u8>> = encoder.keys().cloned().collect();
-        sorted_token_bytes.sort();
-
-        Ok(CoreBPE {
-            encoder,
-            special_tokens_encoder,
-            decoder,
-            special_tokens_decoder,
-            regex_tls: (0..MAX_NUM_THREADS).map(|_| regex.clone()).collect(),
-            special_regex_tls: (0..MAX_NUM_THREADS).map(|_| special_regex.clone()).collect(),
-            sorted_token_bytes,
-        })
-    }
-
-    // ====================
-    // Encoding
-    // ====================
-
-    fn encode_ordinary(&self, py: Python, text: &str) -> Vec<Rank> {
-        py.allow_threads(|| self._encode_ordinary_native(text))
-    }
-
-    fn encode(&self, py: Python, text: &str, allowed_special: HashSet<&str>) -> Vec<Rank> {
-        py.allow_threads(|| self._encode_native(text, &allowed_special).0)
-    }
-
-    fn _encode_bytes(&self, py: Python, bytes: &[u8]) -> Vec<Rank> {
-        py.allow_threads(|| {
-            match std::str::from_utf8(bytes) {
-                Ok(text) => self._encode_ordinary_native(text),
-                Err(e) => {
-                    let text = unsafe { std::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) };
-                    let (tokens, last_piece_token_len) = self._encode_native(text, &HashSet::new());
-                    let (mut tokens, last_piece_token_len) = self._increase_last_piece_token_len(
-                        tokens,
-                        last_piece_token_len
-                    );
-                    if !tokens.is_empty() && last_piece_token_len > 0 {
-                        // Lop off the tokens from the last piece and run BPE on the remaining bytes
-                        // Somewhat niche, but this may not be correct if we'd have had a regex
-                        // split between the valid UTF-8 and the invalid bytes, which is why this
-                        // method is private
-                        let mut unstable_bytes = self._decode_native(
-                            &tokens[tokens.len() - last_piece_token_len..]
-                        );
-                        unstable_bytes.extend_from_slice(&bytes[e.valid_up_to()..]);
-
-                        tokens.truncate(tokens.len() - last_piece_token_len);
-                        match self.encoder.get(&unstable_bytes) {
-                            Some(token) => tokens.push(*token),
-                            None =>
-                                tokens.extend(&byte_pair_encode(&unstable_bytes, &self.encoder)),
-                        }
-                    }
-                    tokens
-                }
-            }
-        })
-    }
-
-    fn encode_with_unstable(
-        &self,
-        py: Python,
-        text: &str,
-        allowed_special: HashSet<&str>
-    ) -> Py<PyTuple> {
-        let (tokens, completions) = py.allow_threads(||
-            self._encode_unstable_native(text, &allowed_special)
-        );
-        let py_completions = PyList::new(
-            py,
-            completions.iter().map(|seq| PyList::new(py, &seq[..]))
-        );
-        (tokens, py_completions).into_py(py)
-    }
-
-    fn encode_single_token(&self, piece: &[u8]) -> PyResult<Rank> {
-        if let Some(token) = self.encoder.get(piece).copied() {
-            return Ok(token);
-        }
-        if let Ok(piece_str) = std::str::from_utf8(piece) {
-            if let Some(token) = self.special_tokens_encoder.get(piece_str).copied() {
-                return Ok(token);
-            }
-        }
-        Err(PyErr::new::<exceptions::PyKeyError, _>(piece.to_owned()))
-    }
-
-    fn encode_single_piece(&self, piece: &[u8]) -> Vec<Rank> {
-        if let Some(token) = self.encoder.get(piece) {
-            return vec![*token];
-        }
-        byte_pair_encode(piece, &self.encoder)
-    }
-
-    // ====================
-    // Decoding
-    // ====================
-
-    fn decode_bytes(&self, py: Python, tokens: Vec<Rank>) -> Py<PyBytes> {
-        let bytes = py.allow_threads(|| self._decode_native(&tokens));
-        PyBytes::new(py, &bytes).into()
-    }
-
-    fn decode_single_token_bytes(&self, py: Python, token: Rank) -> PyResult<Py<PyBytes>> {
-        if let Some(bytes) = self.decoder.get(&token) {
-            return Ok(PyBytes::new(py, bytes).into());
-        }
-        if let Some(bytes) = self.special_tokens_decoder.get(&token) {
-            return Ok(PyBytes::new(py, bytes).into());
-        }
-        Err(PyErr::new::<exceptions::PyKeyError, _>(token.to_string()))
-    }
-
-    // ====================
-    // Miscellaneous
-    // ====================
-
-    fn token_byte_values(&self, py: Python) -> Vec<Py<PyBytes>> {
-        self.sorted_token_bytes
-            .iter()
-            .map(|x| PyBytes::new(py, x).into())
-            .collect()
-    }
-}
-
-#[cfg(feature = "python")]
-#[pymodule]
-fn _tiktoken(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_class::<CoreBPE>()?;
-    Ok(())
-}
-
 #[cfg(test)]
 mod tests {
     use rustc_hash::FxHashMap as HashMap;
 
-    use super::{_byte_pair_merge, Rank};
+    use std::collections::HashSet;
+    use super::{ _byte_pair_merge, CoreBPE, Rank };
     pub fn byte_pair_split<'a>(piece: &'a [u8], ranks: &HashMap<Vec<u8>, Rank>) -> Vec<&'a [u8]> {
         assert!(piece.len() > 1);
-        _byte_pair_merge(&ranks, &piece)
+        _byte_pair_merge(ranks, piece)
             .windows(2)
             .map(|part| &piece[part[0].0..part[1].0])
             .collect()
@@ -818,4 +782,38 @@ mod tests {
         let res = byte_pair_split(b"abab", &ranks);
         assert_eq!(res, vec![b"ab", b"ab"]);
     }
+
+    fn setup_bpe() -> CoreBPE {
+        let mut encoder: HashMap<Vec<u8>, Rank> = (0..=255u8)
+            .map(|b| (vec![b], b as Rank))
+            .collect();
+        encoder.insert(b"ab".to_vec(), 256);
+        let special_tokens = HashMap::from_iter([("<|end|>".to_string(), 1000)]);
+        CoreBPE::new(encoder, special_tokens, "\\S+|\\s+").unwrap()
+    }
+
+    #[test]
+    fn test_single_token() {
+        let bpe = setup_bpe();
+        assert_eq!(bpe.encode_single_token(b"ab").unwrap(), 256);
+        assert_eq!(bpe.encode_single_token(b"<|end|>").unwrap(), 1000);
+        assert!(bpe.encode_single_token(b"abc").is_err());
+        assert_eq!(bpe.encode_single_piece(b"abc"), vec![256, 99]);
+        assert_eq!(bpe.decode_single_token_bytes(256).unwrap(), b"ab");
+        assert_eq!(bpe.decode_single_token_bytes(1000).unwrap(), b"<|end|>");
+        assert!(bpe.decode_single_token_bytes(2000).is_err());
+        assert_eq!(bpe.token_byte_values().len(), 257);
+        assert_eq!(bpe.token_byte_values()[98], b"ab");
+    }
+
+    #[test]
+    fn test_encode_unstable_and_bytes() {
+        let bpe = setup_bpe();
+        let (tokens, completions) = bpe.encode_with_unstable("ab a", HashSet::new());
+        assert_eq!(tokens, vec![256, 32]);
+        assert!(completions.contains(&vec![97]));
+        assert!(completions.contains(&vec![256]));
+        assert_eq!(bpe.encode_bytes(b"ab"), vec![256]);
+        assert_eq!(bpe.encode_bytes(b"ab\xc3"), vec![256, 0xc3]);
+    }
 }
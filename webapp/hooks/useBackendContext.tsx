@@ -22,6 +22,7 @@ import {
   useState,
 } from 'react';
 import logger from '@/utils/logger';
+import { toast } from '@/components/ui/Toast';
 import getBackend from '@/utils/backend';
 import {
   OplaContext,
@@ -268,6 +269,9 @@ function BackendProvider({ children }: { children: React.ReactNode }) {
           updateServer({
             stderr,
           });
+        } else if (event.payload.status === ServerStatus.WARNING) {
+          logger.warn('server warning', event.payload.message);
+          toast.warning(event.payload.message);
         } else {
           if (
             event.payload.status === ServerStatus.STARTING &&
@@ -12,21 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use tauri::Runtime;
-use crate::data::asset::Asset;
+use tauri::{ Runtime, State };
+use crate::{ data::asset::Asset, OplaContext };
 
 
 #[tauri::command]
 pub async fn validate_assets<R: Runtime>(
     _app: tauri::AppHandle<R>,
     _window: tauri::Window<R>,
+    context: State<'_, OplaContext>,
     assets: Vec<Asset>
 ) -> Result<Vec<Asset>, String> {
+    // Count tokens with the local model in use, or fallback to the OpenAI tokenizer
+    let model = {
+        let mut store = context.store.lock().await;
+        store
+            .get_local_active_model_id()
+            .and_then(|id| store.models.get_model_path(id).ok())
+            .unwrap_or("gpt".to_string())
+    };
     let assets = assets
         .iter()
         .map(|asset| {
             let mut asset = asset.clone();
-            asset.validate();
+            asset.validate(&model);
             asset
         })
         .collect();
@@ -100,3 +100,15 @@ pub async fn llm_call_models<R: Runtime>(
     let mut manager = context.providers_manager.lock().await;
     manager.llm_call_models::<R>(provider).await
 }
+
+#[tauri::command]
+pub async fn llm_pull_model<R: Runtime>(
+    app: tauri::AppHandle<R>,
+    _window: tauri::Window<R>,
+    context: State<'_, OplaContext>,
+    provider: Provider,
+    model: String
+) -> Result<(), String> {
+    let mut manager = context.providers_manager.lock().await;
+    manager.llm_pull_model::<R>(app, provider, model).await
+}
@@ -131,6 +131,9 @@ pub async fn install_model<R: Runtime>(
         None => {
             model_entity.state = Some("ok".to_string());
             store.models.add_model(model_entity);
+            if let Err(err) = store.models.update_model_from_file(&model_id) {
+                println!("Install model metadata error: {:?}", err);
+            }
             store.save().map_err(|err| err.to_string())?;
             store.models.emit_update_all(app.app_handle());
             drop(store);
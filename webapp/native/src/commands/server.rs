@@ -12,8 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use opla_core::gguf::GGMLType;
 use tauri::{ Manager, Runtime, State };
-use crate::{data::{Metadata, Payload}, OplaContext};
+use crate::{ data::{ Metadata, Payload, ServerPayload }, local_server::ServerStatus, OplaContext };
 
 #[tauri::command]
 pub async fn get_opla_server_status<R: Runtime>(
@@ -57,6 +58,34 @@ pub async fn start_opla_server<R: Runtime>(
     
     let mut configuration = store.server.configuration.clone();
     configuration.parameters = parameters.clone();
+
+    if let Ok(gguf) = store.models.get_model_file(model_id.clone()) {
+        let context_size = configuration.get_parameter_int("context_size", 512).max(0) as u64;
+        let kv_type = match configuration.parameters.get("memory_f32") {
+            Some(v) if v.to_bool(false) => GGMLType::F32,
+            _ => GGMLType::F16,
+        };
+        let estimate = gguf.estimate_memory(context_size, kv_type);
+        let total_memory = context.sys.lock().await.refresh().total_memory;
+        if estimate.total() > total_memory {
+            // In GB like the max_ram of the models
+            let message = format!(
+                "Model {} needs {:.1} GB with a context of {} but the machine has {:.1} GB of memory",
+                model_id,
+                (estimate.total() as f64) / 1e9,
+                context_size,
+                (total_memory as f64) / 1e9
+            );
+            println!("Opla server warning: {} {:?}", message, estimate);
+            app
+                .emit_all("opla-server", Payload::Server(ServerPayload {
+                    message,
+                    status: ServerStatus::Warning.as_str().to_string(),
+                }))
+                .map_err(|err| err.to_string())?;
+        }
+    }
+
     configuration.set_parameter_string("model_id", model_id);
     configuration.set_parameter_string("model_path", model_path);
 
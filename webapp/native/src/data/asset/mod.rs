@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{ fs, path::Path };
+use std::path::Path;
 
 use chrono::{ DateTime, Utc };
 use serde::{ Deserialize, Serialize };
@@ -61,13 +61,16 @@ pub struct Asset {
 }
 
 const EXTENSIONS: &'static [&'static str] = &["pdf", "txt", "csv", "md", "json"];
+// Files are tokenized in chunks, the cap bounds the time spent counting.
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
 
 impl Asset {
     pub fn extensions() -> &'static [&'static str] {
         EXTENSIONS
     }
 
-    pub fn validate(&mut self) {
+    // The model is the OpenAI model name or the GGUF file of the local model used to count tokens.
+    pub fn validate(&mut self, model: &str) {
         if self.r#type == AssetType::File && self.file.is_some() {
             let file = match &self.file {
                 Some(f) => f,
@@ -83,33 +86,25 @@ impl Asset {
                 println!("Validate {} ext={:?} metadata={:?}", file, extension, metadata);
                 match metadata {
                     Ok(m) => {
-                        let len = m.len();
-                        if len > 200000 {
+                        if m.len() > MAX_FILE_SIZE {
                             println!("Error file too big");
                             self.state = AssetState::Error;
                             return;
                         }
                         self.size = Some(m.len());
                         if extension != "pdf" {
-                            let content = match fs::read_to_string(path) {
-                                Ok(c) => { c }
-                                Err(err) => {
-                                    println!("Error reading file {:?}", err);
-                                    self.state = AssetState::Error;
-                                    return;
+                            // Read in chunks, the file isn't loaded in memory
+                            match tokenizer::count_file(path, model, None) {
+                                Ok(count) => {
+                                    self.state = AssetState::Ok;
+                                    self.tokens_count = Some(count.try_into().unwrap_or(u32::MAX));
                                 }
-                            };
-                            self.state = AssetState::Ok;
-                            // Choose tokenizer based on activeModel
-                            let tokens = tokenizer::encode(content, "gpt".to_string(), None);
-                            self.tokens_count = match tokens {
-                                Ok(t) => { Some(t.len().try_into().unwrap_or(0)) }
                                 Err(err) => {
                                     println!("Error tokenize file {:?}", err);
                                     self.state = AssetState::Error;
-                                    None
+                                    self.tokens_count = None;
                                 }
-                            };
+                            }
                         } else {
                             // TODO Parsing
                             self.state = AssetState::Error;
@@ -14,6 +14,7 @@
 
 use std::str::FromStr;
 use chrono::{ DateTime, Utc };
+use opla_core::gguf::{ GGMLType, GGUF };
 use serde::{ self, Deserialize, Serialize };
 use serde_with::{ serde_as, OneOrMany, formats::PreferOne };
 use void::Void;
@@ -21,6 +22,8 @@ use crate::data::{ option_date_format, option_string_or_struct };
 
 use super::{ Entity, Resource };
 
+pub const DEFAULT_CONTEXT_SIZE: u64 = 512;
+
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Logo {
@@ -226,6 +229,24 @@ impl Model {
     pub fn get_sha(&self) -> Option<String> {
         return self.sha.clone();
     }
+
+    // Fill the fields not already set from the GGUF file metadata
+    pub fn set_from_gguf(&mut self, gguf: &GGUF) {
+        if self.context_window.is_none() {
+            self.context_window = gguf.context_length().map(|c| c as i32);
+        }
+        if self.quantization.is_none() {
+            self.quantization = gguf.quantization().map(|q| q.to_string());
+        }
+        if self.chat_template.is_none() {
+            self.chat_template = gguf.chat_template().map(|t| t.to_string());
+        }
+        if self.max_ram.is_none() {
+            // In Gb, for the default llama.cpp context size
+            let estimate = gguf.estimate_memory(DEFAULT_CONTEXT_SIZE, GGMLType::F16);
+            self.max_ram = Some((estimate.total() as f64 / 1e9) as f32);
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
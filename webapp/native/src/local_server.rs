@@ -44,6 +44,7 @@ pub enum ServerStatus {
     Error,
     Stdout,
     Stderr,
+    Warning,
 }
 
 impl ServerStatus {
@@ -58,6 +59,7 @@ impl ServerStatus {
             ServerStatus::Error => "error",
             ServerStatus::Stdout => "stdout",
             ServerStatus::Stderr => "stderr",
+            ServerStatus::Warning => "warning",
         }
     }
 }
@@ -105,8 +105,13 @@ async fn model_download_event<R: Runtime>(
     match model {
         Some(mut m) => {
             m.state = Some(state.clone());
-            let model_id = &store.server.configuration.get_optional_parameter_string("model_id");
             store.models.update_model_entity(&m);
+            if state == "ok" {
+                if let Err(err) = store.models.update_model_from_file(&model_id) {
+                    println!("Model download metadata error: {:?}", err);
+                }
+            }
+            let model_id = &store.server.configuration.get_optional_parameter_string("model_id");
             store.save().map_err(|err| err.to_string())?;
             drop(store);
             // println!("model_download {} {}", state, model_id);
@@ -386,6 +391,7 @@ fn main() {
                 crate::commands::llm::llm_call_tokenize,
                 crate::commands::llm::llm_call_image_generation,
                 crate::commands::llm::llm_call_models,
+                crate::commands::llm::llm_pull_model,
                 crate::commands::thread::load_conversation_messages,
                 crate::commands::thread::save_conversation_messages,
                 crate::commands::thread::remove_conversation_messages
@@ -0,0 +1,492 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Anthropic Messages API, see https://docs.anthropic.com/en/api/messages
+
+use serde::{ Deserialize, Serialize };
+use tauri::Runtime;
+use eventsource_stream::Eventsource;
+use futures_util::stream::StreamExt;
+
+use crate::providers::llm::{
+    LlmCompletionOptions,
+    LlmCompletionResponse,
+    LlmError,
+    LlmMessage,
+    LlmQuery,
+    LlmQueryCompletion,
+    LlmUsage,
+};
+
+use super::tokens::{ ChatFormat, TokenCounter };
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+// max_tokens is required by the API
+const DEFAULT_MAX_TOKENS: f32 = 4096.0;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnthropicMessage {
+    pub role: String,
+    pub content: String,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnthropicBodyCompletion {
+    pub model: String,
+    pub messages: Vec<AnthropicMessage>,
+    pub system: Option<String>,
+    pub max_tokens: i32,
+    pub stream: Option<bool>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<f32>,
+    pub stop_sequences: Option<Vec<String>>,
+}
+
+impl AnthropicBodyCompletion {
+    pub fn new(
+        model: String,
+        from: &LlmQueryCompletion,
+        options: Option<LlmCompletionOptions>,
+        counter: &TokenCounter,
+        context_window: Option<i32>
+    ) -> Result<Self, LlmError> {
+        let max_tokens = from.get_parameter_as_f32("max_tokens").unwrap_or(DEFAULT_MAX_TOKENS);
+        let messages = counter.fit_messages(
+            from.get_messages(&options),
+            &options,
+            Some(max_tokens),
+            context_window
+        )?;
+        let (system, messages) = to_anthropic_messages(&messages);
+        Ok(Self {
+            model,
+            messages,
+            system,
+            max_tokens: max_tokens as i32,
+            stream: from.get_parameter_as_boolean("stream"),
+            temperature: from.get_parameter_as_f32("temperature"),
+            top_p: from.get_parameter_as_f32("top_p"),
+            top_k: from.get_parameter_as_f32("top_k"),
+            stop_sequences: from.get_parameter_array("stop"),
+        })
+    }
+
+    // Messages as counted by the token counter
+    fn llm_messages(&self) -> Vec<LlmMessage> {
+        let system = self.system.iter().map(|content| LlmMessage::new("system", content));
+        let messages = self.messages.iter().map(|m| LlmMessage::new(&m.role, &m.content));
+        system.chain(messages).collect()
+    }
+}
+
+// The system messages go to the top-level system prompt, the roles of the other messages
+// have to alternate between user and assistant, starting with user.
+pub fn to_anthropic_messages(
+    messages: &Vec<LlmMessage>
+) -> (Option<String>, Vec<AnthropicMessage>) {
+    let system: Vec<&str> = messages
+        .iter()
+        .filter(|m| m.role == "system")
+        .map(|m| m.content.as_str())
+        .collect();
+    let mut anthropic_messages: Vec<AnthropicMessage> = vec![];
+    for message in messages.iter().filter(|m| m.role != "system") {
+        let role = if message.role == "assistant" { "assistant" } else { "user" };
+        match anthropic_messages.last_mut() {
+            Some(last) if last.role == role => {
+                last.content.push_str("\n\n");
+                last.content.push_str(&message.content);
+            }
+            None if role == "assistant" => {
+                // Empty contents are rejected, a placeholder starts the conversation
+                anthropic_messages.push(AnthropicMessage {
+                    role: "user".to_string(),
+                    content: "...".to_string(),
+                });
+                anthropic_messages.push(AnthropicMessage {
+                    role: role.to_string(),
+                    content: message.content.clone(),
+                });
+            }
+            _ => {
+                anthropic_messages.push(AnthropicMessage {
+                    role: role.to_string(),
+                    content: message.content.clone(),
+                });
+            }
+        }
+    }
+    let system = if system.is_empty() { None } else { Some(system.join("\n\n")) };
+    (system, anthropic_messages)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnthropicContentBlock {
+    pub r#type: String,
+    pub text: Option<String>,
+}
+
+// input_tokens isn't in the usage of message_delta
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnthropicUsage {
+    #[serde(default)]
+    pub input_tokens: i32,
+    #[serde(default)]
+    pub output_tokens: i32,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnthropicMessageResponse {
+    pub id: String,
+    pub model: String,
+    pub role: String,
+    pub content: Vec<AnthropicContentBlock>,
+    pub stop_reason: Option<String>,
+    pub usage: AnthropicUsage,
+}
+
+impl AnthropicMessageResponse {
+    fn text(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|block| block.text.as_deref())
+            .collect()
+    }
+}
+
+fn to_llm_usage(usage: &AnthropicUsage) -> LlmUsage {
+    let mut llm_usage = LlmUsage::new();
+    llm_usage.prompt_tokens = Some(usage.input_tokens);
+    llm_usage.completion_tokens = Some(usage.output_tokens);
+    llm_usage.total_tokens = Some(usage.input_tokens + usage.output_tokens);
+    llm_usage
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnthropicError {
+    pub r#type: String,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnthropicResponseError {
+    pub error: AnthropicError,
+}
+
+impl AnthropicError {
+    fn to_llm_error(&self) -> LlmError {
+        LlmError::new(&self.message, &self.r#type)
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnthropicDelta {
+    pub r#type: Option<String>,
+    pub text: Option<String>,
+    pub stop_reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicStreamEvent {
+    MessageStart {
+        message: AnthropicMessageResponse,
+    },
+    ContentBlockStart {
+        index: i32,
+        content_block: AnthropicContentBlock,
+    },
+    ContentBlockDelta {
+        index: i32,
+        delta: AnthropicDelta,
+    },
+    ContentBlockStop {
+        index: i32,
+    },
+    MessageDelta {
+        delta: AnthropicDelta,
+        usage: AnthropicUsage,
+    },
+    MessageStop,
+    Ping,
+    Error {
+        error: AnthropicError,
+    },
+    // New event types have to be ignored
+    #[serde(other)]
+    Unknown,
+}
+
+fn post(
+    url: String,
+    secret_key: &str,
+    parameters: &AnthropicBodyCompletion
+) -> reqwest::RequestBuilder {
+    reqwest::Client
+        ::new()
+        .post(url)
+        .header("x-api-key", secret_key)
+        .header("anthropic-version", ANTHROPIC_VERSION)
+        .json(parameters)
+}
+
+async fn response_error(response: reqwest::Response) -> LlmError {
+    let status = response.status();
+    let error = match response.json::<AnthropicResponseError>().await {
+        Ok(e) => e.error.to_llm_error(),
+        Err(error) => {
+            let message = format!("Failed to deserialize error response: {} {}", status, error);
+            LlmError::new(&message, "FailedDeserialize")
+        }
+    };
+    println!("Failed to get response: {} {:?}", status, error);
+    error
+}
+
+async fn request(
+    url: String,
+    secret_key: &str,
+    parameters: AnthropicBodyCompletion
+) -> Result<LlmCompletionResponse, Box<dyn std::error::Error>> {
+    let result = post(url, secret_key, &parameters).send().await;
+    let response = match result {
+        Ok(res) => res,
+        Err(error) => {
+            println!("Failed to send: {}", error);
+            return Err(Box::new(error));
+        }
+    };
+    if !response.status().is_success() {
+        return Err(Box::new(response_error(response).await));
+    }
+    let response = match response.json::<AnthropicMessageResponse>().await {
+        Ok(r) => r,
+        Err(error) => {
+            println!("Failed to dezerialize response: {}", error);
+            return Err(Box::new(error));
+        }
+    };
+
+    let mut result = LlmCompletionResponse::new(
+        chrono::Utc::now().timestamp_millis(),
+        "finished",
+        &response.text()
+    );
+    result.usage = Some(to_llm_usage(&response.usage));
+    Ok(result)
+}
+
+async fn stream_request(
+    url: String,
+    secret_key: &str,
+    parameters: AnthropicBodyCompletion,
+    callback: Option<impl FnMut(Result<LlmCompletionResponse, LlmError>) + Copy>
+) -> Result<LlmCompletionResponse, Box<dyn std::error::Error>> {
+    let send = |result: Result<LlmCompletionResponse, LlmError>| {
+        if let Some(mut cb) = callback {
+            cb(result);
+        }
+    };
+    let result = post(url, secret_key, &parameters).send().await;
+    let response = match result {
+        Ok(res) => res,
+        Err(error) => {
+            let message = format!("Failed to send: {}", error);
+            println!("{}", message);
+            send(Err(LlmError::new(&message, "FailedSend")));
+            return Err(Box::new(error));
+        }
+    };
+    if !response.status().is_success() {
+        let error = response_error(response).await;
+        send(Err(error.clone()));
+        return Err(Box::new(error));
+    }
+    let mut stream = response.bytes_stream().eventsource();
+    let mut content = String::new();
+    let mut usage = AnthropicUsage { input_tokens: 0, output_tokens: 0 };
+    while let Some(event) = stream.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(error) => {
+                let message = format!("Error in event stream: {}", error);
+                println!("{}", message);
+                send(Err(LlmError::new(&message, "StreamError")));
+                return Err(Box::new(error));
+            }
+        };
+        let event = match serde_json::from_str::<AnthropicStreamEvent>(&event.data) {
+            Ok(t) => t,
+            Err(error) => {
+                println!("Failed to dezerialize event data: {}", error);
+                return Err(Box::new(error));
+            }
+        };
+        match event {
+            AnthropicStreamEvent::MessageStart { message } => {
+                usage = message.usage;
+            }
+            AnthropicStreamEvent::ContentBlockDelta { delta, .. } => {
+                // input_json_delta of the tool use blocks have no text
+                if let Some(text) = delta.text {
+                    content.push_str(&text);
+                    send(
+                        Ok(
+                            LlmCompletionResponse::new(
+                                chrono::Utc::now().timestamp_millis(),
+                                "success",
+                                &text
+                            )
+                        )
+                    );
+                }
+            }
+            AnthropicStreamEvent::MessageDelta { usage: delta_usage, .. } => {
+                usage.output_tokens = delta_usage.output_tokens;
+            }
+            AnthropicStreamEvent::MessageStop => {
+                send(
+                    Ok(
+                        LlmCompletionResponse::new(
+                            chrono::Utc::now().timestamp_millis(),
+                            "finished",
+                            "done"
+                        )
+                    )
+                );
+                break;
+            }
+            AnthropicStreamEvent::Error { error } => {
+                let error = error.to_llm_error();
+                println!("Error in event stream: {:?}", error);
+                send(Err(error.clone()));
+                return Err(Box::new(error));
+            }
+            _ => {}
+        }
+    }
+
+    let mut result = LlmCompletionResponse::new(
+        chrono::Utc::now().timestamp_millis(),
+        "finished",
+        &content
+    );
+    result.usage = Some(to_llm_usage(&usage));
+    Ok(result)
+}
+
+pub async fn call_completion<R: Runtime>(
+    api: &str,
+    secret_key: &str,
+    model: &str,
+    context_window: Option<i32>,
+    query: LlmQuery<LlmQueryCompletion>,
+    completion_options: Option<LlmCompletionOptions>,
+    callback: Option<impl FnMut(Result<LlmCompletionResponse, LlmError>) + Copy>
+) -> Result<LlmCompletionResponse, Box<dyn std::error::Error>> {
+    let start_time = chrono::Utc::now().timestamp_millis();
+    let url = format!("{}/messages", api);
+    println!(
+        "{}",
+        format!("llm call:  {:?} / {:?} / {:?} / {:?}", query.command, url, &model, query.options)
+    );
+
+    // The Claude tokenizer isn't public, the usage filled by the counter is an estimate
+    let counter = TokenCounter::new(model, ChatFormat::Anthropic)?;
+    let parameters = AnthropicBodyCompletion::new(
+        model.to_owned(),
+        &query.options,
+        completion_options,
+        &counter,
+        context_window
+    )?;
+    println!("llm call parameters:  {:?}", parameters);
+    let prompt_tokens = counter.count_messages(&parameters.llm_messages());
+    let mut result;
+    if parameters.stream.unwrap_or(false) {
+        result = stream_request(url, secret_key, parameters, callback).await?;
+    } else {
+        result = request(url, secret_key, parameters).await?;
+    }
+    let end_time = chrono::Utc::now().timestamp_millis() - start_time;
+    let mut usage = counter.fill_usage(result.usage, prompt_tokens, &result.content);
+    usage.total_ms = Some(end_time);
+    let total_tokens = usage.total_tokens.unwrap_or(0);
+    if total_tokens > 0 && end_time > 0 {
+        usage.total_per_second = Some(((total_tokens as f32) / (end_time as f32)) * 1000.0);
+    }
+    println!("llm call duration:  {:?} usage={:?}", end_time, usage);
+    result.usage = Some(usage);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turns(messages: &[AnthropicMessage]) -> Vec<(&str, &str)> {
+        messages
+            .iter()
+            .map(|m| (m.role.as_str(), m.content.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_to_anthropic_messages() {
+        let (system, anthropic_messages) = to_anthropic_messages(
+            &vec![
+                LlmMessage::new("system", "Be brief."),
+                LlmMessage::new("user", "Hi"),
+                LlmMessage::new("system", "Answer in French."),
+                LlmMessage::new("user", "How are you?"),
+                LlmMessage::new("assistant", "Bien."),
+                LlmMessage::new("assistant", "Et vous ?"),
+                LlmMessage::new("tool", "42")
+            ]
+        );
+        assert_eq!(system, Some("Be brief.\n\nAnswer in French.".to_string()));
+        assert_eq!(turns(&anthropic_messages), vec![
+            ("user", "Hi\n\nHow are you?"),
+            ("assistant", "Bien.\n\nEt vous ?"),
+            ("user", "42")
+        ]);
+    }
+
+    #[test]
+    fn test_to_anthropic_messages_placeholder() {
+        let (system, anthropic_messages) = to_anthropic_messages(
+            &vec![
+                LlmMessage::new("assistant", "Hello, how can I help?"),
+                LlmMessage::new("user", "Hi")
+            ]
+        );
+        assert_eq!(system, None);
+        assert_eq!(turns(&anthropic_messages), vec![
+            ("user", "..."),
+            ("assistant", "Hello, how can I help?"),
+            ("user", "Hi")
+        ]);
+
+        let (system, anthropic_messages) = to_anthropic_messages(
+            &vec![LlmMessage::new("system", "Be brief.")]
+        );
+        assert_eq!(system, Some("Be brief.".to_string()));
+        assert!(anthropic_messages.is_empty());
+    }
+}
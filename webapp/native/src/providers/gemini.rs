@@ -0,0 +1,506 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Google Gemini API, see https://ai.google.dev/api/generate-content
+
+use serde::{ Deserialize, Serialize };
+use tauri::Runtime;
+use eventsource_stream::Eventsource;
+use futures_util::stream::StreamExt;
+
+use crate::providers::llm::{
+    LlmCompletionOptions,
+    LlmCompletionResponse,
+    LlmError,
+    LlmMessage,
+    LlmQuery,
+    LlmQueryCompletion,
+    LlmUsage,
+};
+
+use super::tokens::{ ChatFormat, TokenCounter };
+
+// Status of the LlmError when the prompt or the response is blocked
+pub const BLOCKED_STATUS: &str = "content_blocked";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GeminiPart {
+    pub text: Option<String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GeminiContent {
+    pub role: Option<String>,
+    #[serde(default)]
+    pub parts: Vec<GeminiPart>,
+}
+
+impl GeminiContent {
+    fn text(&self) -> String {
+        self.parts
+            .iter()
+            .filter_map(|part| part.text.as_deref())
+            .collect()
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiGenerationConfig {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<f32>,
+    pub seed: Option<f32>,
+    pub max_output_tokens: Option<f32>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiBodyCompletion {
+    pub contents: Vec<GeminiContent>,
+    pub system_instruction: Option<GeminiContent>,
+    pub generation_config: GeminiGenerationConfig,
+}
+
+impl GeminiBodyCompletion {
+    pub fn new(
+        from: &LlmQueryCompletion,
+        options: Option<LlmCompletionOptions>,
+        counter: &TokenCounter,
+        context_window: Option<i32>
+    ) -> Result<Self, LlmError> {
+        let max_tokens = from.get_parameter_as_f32("max_tokens");
+        let messages = counter.fit_messages(
+            from.get_messages(&options),
+            &options,
+            max_tokens,
+            context_window
+        )?;
+        let (system_instruction, contents) = to_gemini_contents(&messages);
+        Ok(Self {
+            contents,
+            system_instruction,
+            generation_config: GeminiGenerationConfig {
+                temperature: from.get_parameter_as_f32("temperature"),
+                top_p: from.get_parameter_as_f32("top_p"),
+                top_k: from.get_parameter_as_f32("top_k"),
+                seed: from.get_parameter_as_f32("seed"),
+                max_output_tokens: max_tokens,
+                stop_sequences: from.get_parameter_array("stop"),
+                presence_penalty: from.get_parameter_as_f32("presence_penalty"),
+                frequency_penalty: from.get_parameter_as_f32("frequency_penalty"),
+            },
+        })
+    }
+
+    // Messages as counted by the token counter
+    fn llm_messages(&self) -> Vec<LlmMessage> {
+        let system = self.system_instruction.iter().map(|content| ("system", content));
+        let contents = self.contents.iter().map(|content| {
+            let role = if content.role.as_deref() == Some("model") { "assistant" } else { "user" };
+            (role, content)
+        });
+        system
+            .chain(contents)
+            .flat_map(|(role, content)| {
+                content.parts
+                    .iter()
+                    .filter_map(|part| part.text.as_ref())
+                    .map(move |text| LlmMessage::new(role, text))
+            })
+            .collect()
+    }
+}
+
+// The system messages go to the system instruction. The assistant is named "model" and the
+// turns of the same role are merged.
+pub fn to_gemini_contents(
+    messages: &Vec<LlmMessage>
+) -> (Option<GeminiContent>, Vec<GeminiContent>) {
+    let part = |text: &str| GeminiPart { text: Some(text.to_string()) };
+    let system: Vec<GeminiPart> = messages
+        .iter()
+        .filter(|m| m.role == "system")
+        .map(|m| part(&m.content))
+        .collect();
+    let mut contents: Vec<GeminiContent> = vec![];
+    for message in messages.iter().filter(|m| m.role != "system") {
+        let role = if message.role == "assistant" { "model" } else { "user" };
+        match contents.last_mut() {
+            Some(last) if last.role.as_deref() == Some(role) => {
+                last.parts.push(part(&message.content));
+            }
+            _ => {
+                contents.push(GeminiContent {
+                    role: Some(role.to_string()),
+                    parts: vec![part(&message.content)],
+                });
+            }
+        }
+    }
+    let system = if system.is_empty() {
+        None
+    } else {
+        Some(GeminiContent { role: None, parts: system })
+    };
+    (system, contents)
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiCandidate {
+    pub content: Option<GeminiContent>,
+    pub finish_reason: Option<String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiPromptFeedback {
+    pub block_reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiUsageMetadata {
+    #[serde(default)]
+    pub prompt_token_count: i32,
+    #[serde(default)]
+    pub candidates_token_count: i32,
+    #[serde(default)]
+    pub total_token_count: i32,
+}
+
+impl GeminiUsageMetadata {
+    fn to_llm_usage(&self) -> LlmUsage {
+        let mut usage = LlmUsage::new();
+        usage.prompt_tokens = Some(self.prompt_token_count);
+        usage.completion_tokens = Some(self.candidates_token_count);
+        usage.total_tokens = Some(self.total_token_count);
+        usage
+    }
+}
+
+// A response or a chunk of the stream
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiResponse {
+    #[serde(default)]
+    pub candidates: Vec<GeminiCandidate>,
+    pub prompt_feedback: Option<GeminiPromptFeedback>,
+    pub usage_metadata: Option<GeminiUsageMetadata>,
+}
+
+impl GeminiResponse {
+    // Text of the first candidate, fails if the prompt or the candidate is blocked.
+    pub fn text(&self) -> Result<String, LlmError> {
+        let block_reason = self.prompt_feedback.as_ref().and_then(|f| f.block_reason.as_ref());
+        if let Some(reason) = block_reason {
+            let message = format!("Prompt blocked: {}", reason);
+            return Err(LlmError::new(&message, BLOCKED_STATUS));
+        }
+        let candidate = match self.candidates.first() {
+            Some(candidate) => candidate,
+            None => {
+                return Ok(String::new());
+            }
+        };
+        match candidate.finish_reason.as_deref() {
+            Some(
+                reason @ ("SAFETY" |
+                "RECITATION" |
+                "BLOCKLIST" |
+                "PROHIBITED_CONTENT" |
+                "SPII" |
+                "IMAGE_SAFETY"),
+            ) => {
+                let message = format!("Response blocked: {}", reason);
+                Err(LlmError::new(&message, BLOCKED_STATUS))
+            }
+            _ => Ok(candidate.content.as_ref().map(|c| c.text()).unwrap_or_default()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GeminiError {
+    pub code: Option<i32>,
+    pub message: String,
+    pub status: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GeminiResponseError {
+    pub error: GeminiError,
+}
+
+fn post(
+    url: String,
+    secret_key: &str,
+    parameters: &GeminiBodyCompletion
+) -> reqwest::RequestBuilder {
+    reqwest::Client::new().post(url).header("x-goog-api-key", secret_key).json(parameters)
+}
+
+async fn response_error(response: reqwest::Response) -> LlmError {
+    let status = response.status();
+    let error = match response.json::<GeminiResponseError>().await {
+        Ok(e) => {
+            let error_status = e.error.status.unwrap_or(status.to_string());
+            LlmError::new(&e.error.message, &error_status)
+        }
+        Err(error) => {
+            let message = format!("Failed to deserialize error response: {} {}", status, error);
+            LlmError::new(&message, "FailedDeserialize")
+        }
+    };
+    println!("Failed to get response: {} {:?}", status, error);
+    error
+}
+
+async fn request(
+    url: String,
+    secret_key: &str,
+    parameters: GeminiBodyCompletion
+) -> Result<LlmCompletionResponse, Box<dyn std::error::Error>> {
+    let result = post(url, secret_key, &parameters).send().await;
+    let response = match result {
+        Ok(res) => res,
+        Err(error) => {
+            println!("Failed to send: {}", error);
+            return Err(Box::new(error));
+        }
+    };
+    if !response.status().is_success() {
+        return Err(Box::new(response_error(response).await));
+    }
+    let response = match response.json::<GeminiResponse>().await {
+        Ok(r) => r,
+        Err(error) => {
+            println!("Failed to dezerialize response: {}", error);
+            return Err(Box::new(error));
+        }
+    };
+
+    let mut result = LlmCompletionResponse::new(
+        chrono::Utc::now().timestamp_millis(),
+        "finished",
+        &response.text()?
+    );
+    result.usage = response.usage_metadata.map(|usage| usage.to_llm_usage());
+    Ok(result)
+}
+
+async fn stream_request(
+    url: String,
+    secret_key: &str,
+    parameters: GeminiBodyCompletion,
+    callback: Option<impl FnMut(Result<LlmCompletionResponse, LlmError>) + Copy>
+) -> Result<LlmCompletionResponse, Box<dyn std::error::Error>> {
+    let send = |result: Result<LlmCompletionResponse, LlmError>| {
+        if let Some(mut cb) = callback {
+            cb(result);
+        }
+    };
+    let result = post(url, secret_key, &parameters).send().await;
+    let response = match result {
+        Ok(res) => res,
+        Err(error) => {
+            let message = format!("Failed to send: {}", error);
+            println!("{}", message);
+            send(Err(LlmError::new(&message, "FailedSend")));
+            return Err(Box::new(error));
+        }
+    };
+    if !response.status().is_success() {
+        let error = response_error(response).await;
+        send(Err(error.clone()));
+        return Err(Box::new(error));
+    }
+    let mut stream = response.bytes_stream().eventsource();
+    let mut content = String::new();
+    let mut usage: Option<GeminiUsageMetadata> = None;
+    while let Some(event) = stream.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(error) => {
+                let message = format!("Error in event stream: {}", error);
+                println!("{}", message);
+                send(Err(LlmError::new(&message, "StreamError")));
+                return Err(Box::new(error));
+            }
+        };
+        let chunk = match serde_json::from_str::<GeminiResponse>(&event.data) {
+            Ok(t) => t,
+            Err(error) => {
+                println!("Failed to dezerialize event data: {}", error);
+                return Err(Box::new(error));
+            }
+        };
+        let text = match chunk.text() {
+            Ok(text) => text,
+            Err(error) => {
+                println!("Error in event stream: {:?}", error);
+                send(Err(error.clone()));
+                return Err(Box::new(error));
+            }
+        };
+        if !text.is_empty() {
+            content.push_str(&text);
+            let created = chrono::Utc::now().timestamp_millis();
+            send(Ok(LlmCompletionResponse::new(created, "success", &text)));
+        }
+        // Each chunk has the usage so far
+        if chunk.usage_metadata.is_some() {
+            usage = chunk.usage_metadata;
+        }
+    }
+    let created = chrono::Utc::now().timestamp_millis();
+    send(Ok(LlmCompletionResponse::new(created, "finished", "done")));
+
+    let mut result = LlmCompletionResponse::new(
+        chrono::Utc::now().timestamp_millis(),
+        "finished",
+        &content
+    );
+    result.usage = usage.map(|usage| usage.to_llm_usage());
+    Ok(result)
+}
+
+pub async fn call_completion<R: Runtime>(
+    api: &str,
+    secret_key: &str,
+    model: &str,
+    context_window: Option<i32>,
+    query: LlmQuery<LlmQueryCompletion>,
+    completion_options: Option<LlmCompletionOptions>,
+    callback: Option<impl FnMut(Result<LlmCompletionResponse, LlmError>) + Copy>
+) -> Result<LlmCompletionResponse, Box<dyn std::error::Error>> {
+    let start_time = chrono::Utc::now().timestamp_millis();
+    let model_name = model.trim_start_matches("models/");
+    let stream = query.options.get_parameter_as_boolean("stream").unwrap_or(false);
+    let url = if stream {
+        format!("{}/models/{}:streamGenerateContent?alt=sse", api, model_name)
+    } else {
+        format!("{}/models/{}:generateContent", api, model_name)
+    };
+    println!(
+        "{}",
+        format!("llm call:  {:?} / {:?} / {:?} / {:?}", query.command, url, &model, query.options)
+    );
+
+    // Gemini models are counted with cl100k_base, the usage filled by the counter is an estimate
+    let counter = TokenCounter::new(model, ChatFormat::OpenAI)?;
+    let parameters = GeminiBodyCompletion::new(
+        &query.options,
+        completion_options,
+        &counter,
+        context_window
+    )?;
+    println!("llm call parameters:  {:?}", parameters);
+    let prompt_tokens = counter.count_messages(&parameters.llm_messages());
+    let mut result;
+    if stream {
+        result = stream_request(url, secret_key, parameters, callback).await?;
+    } else {
+        result = request(url, secret_key, parameters).await?;
+    }
+    let end_time = chrono::Utc::now().timestamp_millis() - start_time;
+    let mut usage = counter.fill_usage(result.usage, prompt_tokens, &result.content);
+    usage.total_ms = Some(end_time);
+    let total_tokens = usage.total_tokens.unwrap_or(0);
+    if total_tokens > 0 && end_time > 0 {
+        usage.total_per_second = Some(((total_tokens as f32) / (end_time as f32)) * 1000.0);
+    }
+    println!("llm call duration:  {:?} usage={:?}", end_time, usage);
+    result.usage = Some(usage);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(content: &GeminiContent) -> Vec<&str> {
+        content.parts
+            .iter()
+            .filter_map(|part| part.text.as_deref())
+            .collect()
+    }
+
+    fn response(json: &str) -> GeminiResponse {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_to_gemini_contents() {
+        let (system, contents) = to_gemini_contents(
+            &vec![
+                LlmMessage::new("system", "Be brief."),
+                LlmMessage::new("user", "Hi"),
+                LlmMessage::new("user", "How are you?"),
+                LlmMessage::new("system", "Answer in French."),
+                LlmMessage::new("assistant", "Bien."),
+                LlmMessage::new("user", "Merci")
+            ]
+        );
+        let system = system.unwrap();
+        assert_eq!(system.role, None);
+        assert_eq!(texts(&system), vec!["Be brief.", "Answer in French."]);
+        let roles: Vec<_> = contents
+            .iter()
+            .map(|c| c.role.as_deref().unwrap())
+            .collect();
+        assert_eq!(roles, vec!["user", "model", "user"]);
+        assert_eq!(texts(&contents[0]), vec!["Hi", "How are you?"]);
+        assert_eq!(texts(&contents[1]), vec!["Bien."]);
+
+        let (system, contents) = to_gemini_contents(&vec![LlmMessage::new("assistant", "Hello")]);
+        assert!(system.is_none());
+        assert_eq!(contents[0].role.as_deref(), Some("model"));
+    }
+
+    #[test]
+    fn test_response_text() {
+        let text = response(
+            r#"{"candidates":[{"content":{"role":"model","parts":[{"text":"Hel"},{"text":"lo"}]},
+            "finishReason":"STOP"}],"usageMetadata":{"promptTokenCount":3,"totalTokenCount":5}}"#
+        ).text();
+        assert_eq!(text.unwrap(), "Hello");
+        assert_eq!(response("{}").text().unwrap(), "");
+
+        let error = response(r#"{"promptFeedback":{"blockReason":"SAFETY"}}"#).text().unwrap_err();
+        assert_eq!(error.status, BLOCKED_STATUS);
+        assert_eq!(error.message, "Prompt blocked: SAFETY");
+        for reason in ["SAFETY", "RECITATION", "PROHIBITED_CONTENT"] {
+            let json = format!(
+                r#"{{"candidates":[{{"content":{{"parts":[{{"text":"x"}}]}},"finishReason":"{}"}}]}}"#,
+                reason
+            );
+            let error = response(&json).text().unwrap_err();
+            assert_eq!(error.status, BLOCKED_STATUS);
+            assert_eq!(error.message, format!("Response blocked: {}", reason));
+        }
+        let text = response(r#"{"candidates":[{"finishReason":"MAX_TOKENS"}]}"#).text();
+        assert_eq!(text.unwrap(), "");
+    }
+}
@@ -20,7 +20,8 @@ use crate::{
 use async_trait::async_trait;
 use bytes::Bytes;
 use serde::{ Deserialize, Serialize };
-use crate::providers::llm::{ LlmQuery, LlmCompletionResponse, LlmUsage };
+use tokenizer::gguf_tokenizer;
+use crate::providers::llm::{ LlmQuery, LlmCompletionResponse, LlmMessage, LlmUsage };
 
 use super::{
     llm::{
@@ -30,13 +31,18 @@ use super::{
         LlmResponseError,
         LlmTokenizeResponse,
     },
+    openai,
     services::HttpService,
+    template::ChatTemplate,
+    tokens::{ ChatFormat, TokenCounter },
     ProviderAdapter, ServerParameters,
 };
 
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LlamaCppCompletionQuery {
+    // Empty when the messages are sent instead
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub prompt: String,
     pub stream: Option<bool>,
     pub temperature: Option<f32>,
@@ -62,40 +68,34 @@ pub struct LlamaCppCompletionQuery {
     pub ignore_eos: Option<bool>,
 }
 
+// Body of /v1/chat/completions, the server renders the messages with the model's template.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LlamaCppChatCompletionQuery {
+    pub messages: Vec<LlmMessage>,
+    #[serde(flatten)]
+    pub parameters: LlamaCppCompletionQuery,
+}
+
 impl LlmQueryCompletion {
+    // Returns the messages that fit in the context window and the parameters with their prompt.
+    // Without a token counter for the model, the messages are sent as is.
     fn to_llama_cpp_parameters(
         &self,
-        options: Option<LlmCompletionOptions>
-    ) -> LlamaCppCompletionQuery {
-        let mut prompt = String::new();
-        match options {
-            Some(options) => {
-                match options.system {
-                    Some(system) => {
-                        prompt.push_str(&format!("{}\n", system));
-                    }
-                    None => {}
-                }
-            }
-            None => {}
-        }
-        // TODO: handle context_window_policy and keep_system
-        for message in &self.messages {
-            match message.role.as_str() {
-                "user" => {
-                    prompt.push_str("Question:");
-                }
-                "assistant" => {
-                    prompt.push_str("Answer:");
-                }
-                _ => {}
-            }
-            prompt.push_str(&message.content.trim());
-            prompt.push('\n');
+        options: Option<LlmCompletionOptions>,
+        template: &ChatTemplate,
+        counter: Option<&TokenCounter>,
+        context_size: Option<i32>
+    ) -> Result<(Vec<LlmMessage>, LlamaCppCompletionQuery), LlmError> {
+        let n_predict = self.get_parameter_as_f32("n_predict");
+        let mut messages = self.get_messages(&options);
+        if let Some(counter) = counter {
+            messages = counter.fit_messages(messages, &options, n_predict, context_size)?;
         }
-        prompt.push_str("Answer:");
+        let prompt = template
+            .render(&messages, true)
+            .map_err(|err| LlmError::new(&err, "chat_template_error"))?;
         // println!("prompt: {}", prompt);
-        LlamaCppCompletionQuery {
+        let parameters = LlamaCppCompletionQuery {
             prompt,
             stream: self.get_parameter_as_boolean("stream"),
             temperature: self.get_parameter_as_f32("temperature"),
@@ -106,7 +106,7 @@ impl LlmQueryCompletion {
             top_p: self.get_parameter_as_f32("top_p"),
             top_k: self.get_parameter_as_f32("top_k"),
             min_p: self.get_parameter_as_f32("min_p"),
-            n_predict: self.get_parameter_as_f32("n_predict"),
+            n_predict,
             n_keep: self.get_parameter_as_f32("n_keep"),
             tfs_z: self.get_parameter_as_f32("tfs_z"),
             typical_p: self.get_parameter_as_f32("typical_p"),
@@ -119,7 +119,8 @@ impl LlmQueryCompletion {
             mirostat_eta: self.get_parameter_as_f32("mirostat_eta"),
             grammar: self.get_parameter_value("grammar"),
             ignore_eos: self.get_parameter_as_boolean("ignore_eos"),
-        }
+        };
+        Ok((messages, parameters))
     }
 }
 
@@ -235,15 +236,32 @@ impl LlamaCppTokenize {
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LlamaCppEndpoint {
+    // /completion with the prompt rendered from the model's chat template
+    Completion,
+    // OpenAI compatible /v1/chat/completions of recent servers, falls back to /completion
+    ChatCompletions,
+}
+
 #[derive(Clone, Debug)]
 pub struct LlamaCppInferenceClient {
     pub server_parameters: Option<ServerParameters>,
+    pub endpoint: LlamaCppEndpoint,
 }
 
 impl LlamaCppInferenceClient {
     pub fn new(parameters: Option<ServerParameters>) -> Self {
+        let endpoint = match &parameters {
+            Some(ServerParameters { chat_completions: true, .. }) => {
+                LlamaCppEndpoint::ChatCompletions
+            }
+            _ => LlamaCppEndpoint::Completion,
+        };
         LlamaCppInferenceClient {
             server_parameters: parameters.clone(),
+            endpoint,
         }
     }
 
@@ -269,10 +287,13 @@ impl LlamaCppInferenceClient {
 #[async_trait]
 impl LlmInferenceInterface for LlamaCppInferenceClient {
     fn set_parameters(&mut self, parameters: ServerParameters) {
-        self.server_parameters = Some(parameters);
+        *self = LlamaCppInferenceClient::new(Some(parameters));
     }
 
     fn deserialize_response(&mut self, full: &Bytes) -> Result<LlmCompletionResponse, LlmError> {
+        if self.endpoint == LlamaCppEndpoint::ChatCompletions {
+            return openai::parse_completion(full);
+        }
         serde_json
             ::from_slice::<LlamaCppCompletionResponse>(&full)
             .map(|response| response.to_llm_response())
@@ -291,6 +312,9 @@ impl LlmInferenceInterface for LlamaCppInferenceClient {
         data: String,
         _created: i64
     ) -> Result<Option<String>, LlmError> {
+        if self.endpoint == LlamaCppEndpoint::ChatCompletions {
+            return openai::parse_chunk(&data);
+        }
         let chunk = match serde_json::from_str::<LlamaCppChatCompletionChunk>(&data) {
             Ok(r) => r,
             Err(error) => {
@@ -315,7 +339,34 @@ impl LlmInferenceInterface for LlamaCppInferenceClient {
         adapter: &mut ProviderAdapter
         /* sender: Sender<Result<LlmCompletionResponse, LlmError>> */
     ) -> Result<HttpService<LlmCompletionResponse, LlmError>, LlmError> {
-        let parameters = query.options.to_llama_cpp_parameters(completion_options);
+        let (model_path, context_size, chat_template) = match &self.server_parameters {
+            Some(server) =>
+                (server.model_path.clone(), server.context_size, server.chat_template.clone()),
+            None => (None, None, None),
+        };
+        let tokenizer = match model_path.as_deref().map(gguf_tokenizer) {
+            Some(Ok(tokenizer)) => Some(tokenizer),
+            Some(Err(error)) => {
+                println!("LlamaCpp tokenizer error: {}", error);
+                None
+            }
+            None => None,
+        };
+        let template = ChatTemplate::for_model(chat_template.as_deref(), tokenizer.as_deref());
+        let counter = match (model_path, &tokenizer) {
+            (Some(path), Some(_)) => TokenCounter::new(&path, ChatFormat::LlamaCpp).ok(),
+            _ => None,
+        }.map(|counter| counter.with_template(template.clone()));
+        let (messages, parameters) = query.options.to_llama_cpp_parameters(
+            completion_options,
+            &template,
+            counter.as_ref(),
+            context_size
+        )?;
+        if let Some(counter) = counter {
+            let prompt_tokens = counter.count_prompt(&parameters.prompt);
+            adapter.set_token_counter(counter, prompt_tokens);
+        }
 
         // let is_stream = parameters.stream.unwrap_or(false);
 
@@ -327,6 +378,31 @@ impl LlmInferenceInterface for LlamaCppInferenceClient {
             }
         };
 
+        if self.endpoint == LlamaCppEndpoint::ChatCompletions {
+            let chat_url = self
+                .get_api("v1/chat/completions".to_string())
+                .map_err(|msg| LlmError::new(&msg, "Parameters_error"))?;
+            let body = LlamaCppChatCompletionQuery {
+                messages,
+                parameters: LlamaCppCompletionQuery {
+                    prompt: String::new(),
+                    ..parameters.clone()
+                },
+            };
+            // Older servers only have /completion
+            let mut fallback_adapter = adapter.clone();
+            let mut client = self.clone();
+            client.endpoint = LlamaCppEndpoint::Completion;
+            fallback_adapter.interface = Box::new(client);
+            let service: HttpService<LlmCompletionResponse, LlmError> = HttpService::post(
+                chat_url,
+                body,
+                None,
+                adapter
+            ).with_fallback(api_url, parameters, &mut fallback_adapter);
+            return Ok(service);
+        }
+
         let service: HttpService<LlmCompletionResponse, LlmError> = HttpService::post(
             api_url,
             parameters,
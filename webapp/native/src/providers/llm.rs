@@ -18,7 +18,7 @@ use async_trait::async_trait;
 use serde::{ Deserialize, Serialize };
 
 use crate::{
-    data::model::Model,
+    data::{ model::Model, ContextWindowPolicy },
     utils::http_client::{ HttpChunk, HttpError, NewHttpError },
 };
 
@@ -79,6 +79,12 @@ pub struct LlmMessage {
     pub name: Option<String>,
 }
 
+impl LlmMessage {
+    pub fn new(role: &str, content: &str) -> Self {
+        LlmMessage { content: content.to_string(), role: role.to_string(), name: None }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LlmParameter {
     pub key: String,
@@ -98,12 +104,22 @@ pub struct LlmQueryCompletion {
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LlmCompletionOptions {
-    pub context_window_policy: Option<String>,
+    pub context_window_policy: Option<ContextWindowPolicy>,
     pub keep_system: Option<bool>,
     pub system: Option<String>,
 }
 
 impl LlmQueryCompletion {
+    // Messages sent to the model, starting with the system prompt of the options.
+    pub fn get_messages(&self, options: &Option<LlmCompletionOptions>) -> Vec<LlmMessage> {
+        let mut messages: Vec<LlmMessage> = vec![];
+        if let Some(LlmCompletionOptions { system: Some(system), .. }) = options {
+            messages.push(LlmMessage::new("system", system));
+        }
+        messages.extend(self.messages.clone());
+        messages
+    }
+
     pub fn get_parameter_value(&self, key: &str) -> Option<String> {
         let parameters = match &self.parameters {
             Some(p) => p,
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{ collections::HashMap, sync::Arc };
+use std::{ collections::HashMap, sync::Arc, time::Instant };
 use llm::LlmCompletionPayload;
 use serde::Serialize;
 use tauri::{ AppHandle, Manager, Runtime };
@@ -23,6 +23,7 @@ use uuid::Uuid;
 
 use crate::{
     data::{ provider::{ Provider, ProviderType }, LLMErrorPayload, Payload },
+    downloader::Download,
     store::server::{ ServerConfiguration, ServerStorage },
     utils::http_client::{ HttpChunk, NewHttpError },
     OplaContext,
@@ -43,17 +44,31 @@ use self::{
         LlmResponseImpl,
         LlmTokenizeResponse,
     },
+    tokens::TokenCounter,
 };
 
+pub mod anthropic;
+pub mod gemini;
+pub mod ollama;
 pub mod openai;
 pub mod llama_cpp;
 pub mod llm;
 pub mod services;
+pub mod template;
+pub mod tokens;
+
+// Milliseconds between two progress events of a pull
+const PULL_UPDATE_SPEED: u128 = 50;
 
 #[derive(Clone, Debug)]
 pub struct ServerParameters {
     pub port: i32,
     pub host: String,
+    pub model_path: Option<String>,
+    pub context_size: Option<i32>,
+    pub chat_template: Option<String>,
+    // Send the messages to the OpenAI compatible endpoint of the server
+    pub chat_completions: bool,
 }
 
 #[derive(Clone)]
@@ -62,6 +77,8 @@ pub struct ProviderAdapter {
     pub created: i64,
     pub content: String,
     pub interface: Box<dyn LlmInferenceInterface + Send + Sync>,
+    pub token_counter: Option<TokenCounter>,
+    pub prompt_tokens: usize,
 }
 
 impl ProviderAdapter {
@@ -71,6 +88,23 @@ impl ProviderAdapter {
             content: String::new(),
             created: chrono::Utc::now().timestamp_millis(),
             interface,
+            token_counter: None,
+            prompt_tokens: 0,
+        }
+    }
+
+    // Used to fill the usage when the provider doesn't return it.
+    pub fn set_token_counter(&mut self, counter: TokenCounter, prompt_tokens: usize) {
+        self.token_counter = Some(counter);
+        self.prompt_tokens = prompt_tokens;
+    }
+
+    fn fill_usage(&self, response: &mut LlmCompletionResponse) {
+        if let Some(counter) = &self.token_counter {
+            let usage = response.usage.take();
+            response.usage = Some(
+                counter.fill_usage(usage, self.prompt_tokens, &response.content)
+            );
         }
     }
 
@@ -89,7 +123,10 @@ impl ProviderAdapter {
         where E: NewHttpError
     {
         match self.interface.deserialize_response(&full) {
-            Ok(response) => Ok(D::completion_to(response)),
+            Ok(mut response) => {
+                self.fill_usage(&mut response);
+                Ok(D::completion_to(response))
+            }
             Err(err) => Err(E::new(&err.message, &err.status)),
         }
     }
@@ -145,8 +182,9 @@ impl ProviderAdapter {
 
     pub fn response_to_output<R: LlmResponseImpl, E>(&mut self) -> Result<R, E> {
         // let end_time = 0;
-        let response = R::new(self.created, "finished", &self.content);
-        Ok(response)
+        let mut response = LlmCompletionResponse::new(self.created, "finished", &self.content);
+        self.fill_usage(&mut response);
+        Ok(R::completion_to(response))
     }
 
     pub fn to_err(&mut self) {}
@@ -245,7 +283,7 @@ impl ProvidersManager {
         };
         // TODO if local inference client
         let app_handle = app.app_handle();
-        let config = self.bind_local_server(app, model).await?;
+        let config = self.bind_local_server(app, model.clone()).await?;
         let context = app_handle.state::<OplaContext>();
         let mut store = context.store.lock().await;
         if
@@ -258,10 +296,18 @@ impl ProvidersManager {
             store.save().map_err(|err| err.to_string())?;
         }
 
+        let chat_template = store.models.get_model(&model).and_then(|m| m.chat_template);
+        drop(store);
+
         let mut interface = interface.clone();
+        let model_path = config.get_parameter_string("model_path", String::new());
         let parameters: ServerParameters = ServerParameters {
             host: config.get_parameter_string("host", "127.0.0.1".to_string()),
             port: config.get_parameter_int("port", 8081),
+            model_path: if model_path.is_empty() { None } else { Some(model_path) },
+            context_size: Some(config.get_parameter_int("context_size", 512)),
+            chat_template,
+            chat_completions: config.get_parameter_bool("chat_completions", false),
         };
         interface.set_parameters(parameters);
         Ok(interface)
@@ -455,7 +501,13 @@ impl ProvidersManager {
             println!("Opla call completion: {:?}", response);
             return Ok(response?);
         }
-        if llm_provider_type == "openai" || llm_provider_type == "server" {
+        if
+            llm_provider_type == "openai" ||
+            llm_provider_type == "server" ||
+            llm_provider_type == "anthropic" ||
+            llm_provider_type == "gemini" ||
+            llm_provider_type == "ollama"
+        {
             let response = {
                 let api = format!("{:}", llm_provider.url);
                 let secret_key = match llm_provider.key {
@@ -466,40 +518,91 @@ impl ProvidersManager {
                                 format!("OpenAI provider key not set: {:?}", llm_provider_type)
                             );
                         }
+                        if llm_provider_type == "anthropic" {
+                            return Err(
+                                format!("Anthropic provider key not set: {:?}", llm_provider_type)
+                            );
+                        }
+                        if llm_provider_type == "gemini" {
+                            return Err(
+                                format!("Gemini provider key not set: {:?}", llm_provider_type)
+                            );
+                        }
                         ' '.to_string()
                     }
                 };
                 // let model = model;
                 let query = query.clone();
-                openai
-                    ::call_completion::<R>(
-                        &api,
-                        &secret_key,
-                        &model,
-                        query,
-                        completion_options,
-                        Some(|result: Result<LlmCompletionResponse, LlmError>| {
-                            match result {
-                                Ok(response) => {
-                                    // let mut response = response.clone();
-                                    let payload = LlmCompletionPayload {
-                                        response,
-                                        conversation_id: conversation_id.clone(),
-                                        message_id: message_id.clone(),
-                                    };
-                                    let _ = app
-                                        .emit_all("opla-sse", payload)
-                                        .map_err(|err| err.to_string());
-                                }
-                                Err(err) => {
-                                    let _ = app
-                                        .emit_all("opla-sse", err)
-                                        .map_err(|err| err.to_string());
-                                }
-                            }
-                        })
-                    ).await
-                    .map_err(|err| err.to_string())?
+                let context_window = llm_provider.models
+                    .as_ref()
+                    .and_then(|models| models.iter().find(|m| m.is_same_id_or_name(model)))
+                    .and_then(|m| m.context_window);
+                let callback = Some(|result: Result<LlmCompletionResponse, LlmError>| {
+                    match result {
+                        Ok(response) => {
+                            // let mut response = response.clone();
+                            let payload = LlmCompletionPayload {
+                                response,
+                                conversation_id: conversation_id.clone(),
+                                message_id: message_id.clone(),
+                            };
+                            let _ = app
+                                .emit_all("opla-sse", payload)
+                                .map_err(|err| err.to_string());
+                        }
+                        Err(err) => {
+                            let _ = app.emit_all("opla-sse", err).map_err(|err| err.to_string());
+                        }
+                    }
+                });
+                if llm_provider_type == "anthropic" {
+                    anthropic
+                        ::call_completion::<R>(
+                            &api,
+                            &secret_key,
+                            &model,
+                            context_window,
+                            query,
+                            completion_options,
+                            callback
+                        ).await
+                        .map_err(|err| err.to_string())?
+                } else if llm_provider_type == "gemini" {
+                    gemini
+                        ::call_completion::<R>(
+                            &api,
+                            &secret_key,
+                            &model,
+                            context_window,
+                            query,
+                            completion_options,
+                            callback
+                        ).await
+                        .map_err(|err| err.to_string())?
+                } else if llm_provider_type == "ollama" {
+                    ollama
+                        ::call_completion::<R>(
+                            &api,
+                            &model,
+                            context_window,
+                            query,
+                            completion_options,
+                            callback
+                        ).await
+                        .map_err(|err| err.to_string())?
+                } else {
+                    openai
+                        ::call_completion::<R>(
+                            &api,
+                            &secret_key,
+                            &model,
+                            context_window,
+                            query,
+                            completion_options,
+                            callback
+                        ).await
+                        .map_err(|err| err.to_string())?
+                }
             };
             let payload = LlmCompletionPayload {
                 response,
@@ -603,6 +706,66 @@ impl ProvidersManager {
 
             return result;
         }
+        if llm_provider_type == "ollama" {
+            return ollama::call_models(&provider.url).await.map_err(|err| err.to_string());
+        }
         return Err(format!("LLM provider models not implemented: {:?}", llm_provider_type));
     }
+
+    // Pulls the model in the library of the provider, the progress is sent like the one of the
+    // model downloads.
+    pub async fn llm_pull_model<R: Runtime>(
+        &mut self,
+        app: tauri::AppHandle<R>,
+        provider: Provider,
+        model: String
+    ) -> Result<(), String> {
+        let llm_provider_type = provider.r#type;
+        if llm_provider_type != "ollama" {
+            return Err(format!("LLM provider pull not implemented: {:?}", llm_provider_type));
+        }
+        spawn(async move {
+            let mut download = Download {
+                id: model.clone(),
+                file_name: model.clone(),
+                file_size: 0,
+                transfered: 0,
+                transfer_rate: 0.0,
+                percentage: 0.0,
+                error: None,
+            };
+            let start_time = Instant::now();
+            let mut last_update = Instant::now();
+            // Only sent to the webapp, unlike the events of Download: the global downloader events
+            // are about the local model files, and a model tag like llama3:8b would be mistaken
+            // for one of them
+            let result = ollama::pull_model(&provider.url, &model, |progress| {
+                if let (Some(total), Some(completed)) = (progress.total, progress.completed) {
+                    let duration = start_time.elapsed().as_secs_f64();
+                    download.file_size = total;
+                    download.transfered = completed;
+                    download.percentage = ((completed * 100) / total.max(1)) as f64;
+                    if duration > 0.0 {
+                        download.transfer_rate = (completed as f64) / duration;
+                    }
+                }
+                if last_update.elapsed().as_millis() >= PULL_UPDATE_SPEED {
+                    app.emit_all("opla-downloader", ("progress", &download)).ok();
+                    last_update = Instant::now();
+                }
+            }).await;
+            match result {
+                Ok(_) => {
+                    app.emit_all("opla-downloader", ("finished", &download)).ok();
+                }
+                Err(error) => {
+                    println!("Pull model error: {:?}", error);
+                    download.error = Some(error.to_string());
+                    let payload = ("error", &download, error.to_string());
+                    app.emit_all("opla-downloader", payload).ok();
+                }
+            }
+        });
+        Ok(())
+    }
 }
@@ -0,0 +1,569 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Ollama API, see https://github.com/ollama/ollama/blob/main/docs/api.md
+// The streamed responses are newline delimited JSON objects.
+
+use chrono::{ DateTime, Utc };
+use serde::{ de::DeserializeOwned, Deserialize, Serialize };
+use tauri::Runtime;
+use futures_util::stream::StreamExt;
+
+use crate::{
+    data::model::Model,
+    providers::llm::{
+        LlmCompletionOptions,
+        LlmCompletionResponse,
+        LlmError,
+        LlmMessage,
+        LlmModelsResponse,
+        LlmQuery,
+        LlmQueryCompletion,
+        LlmUsage,
+    },
+};
+
+use super::tokens::{ ChatFormat, TokenCounter };
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OllamaOptions {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<f32>,
+    pub min_p: Option<f32>,
+    pub seed: Option<f32>,
+    pub stop: Option<Vec<String>>,
+    pub num_predict: Option<f32>,
+    pub num_ctx: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OllamaBodyChat {
+    pub model: String,
+    pub messages: Vec<LlmMessage>,
+    pub stream: bool,
+    pub options: OllamaOptions,
+}
+
+impl OllamaBodyChat {
+    pub fn new(
+        model: String,
+        from: &LlmQueryCompletion,
+        options: Option<LlmCompletionOptions>,
+        counter: &TokenCounter,
+        context_window: Option<i32>
+    ) -> Result<Self, LlmError> {
+        let num_predict = from
+            .get_parameter_as_f32("max_tokens")
+            .or(from.get_parameter_as_f32("n_predict"));
+        let messages = counter.fit_messages(
+            from.get_messages(&options),
+            &options,
+            num_predict,
+            context_window
+        )?;
+        Ok(Self {
+            model,
+            messages,
+            // Always sent, Ollama streams when it is missing
+            stream: from.get_parameter_as_boolean("stream").unwrap_or(false),
+            options: OllamaOptions {
+                temperature: from.get_parameter_as_f32("temperature"),
+                top_p: from.get_parameter_as_f32("top_p"),
+                top_k: from.get_parameter_as_f32("top_k"),
+                min_p: from.get_parameter_as_f32("min_p"),
+                seed: from.get_parameter_as_f32("seed"),
+                stop: from.get_parameter_array("stop"),
+                num_predict,
+                num_ctx: from.get_parameter_as_f32("num_ctx"),
+                repeat_penalty: from.get_parameter_as_f32("repeat_penalty"),
+                presence_penalty: from.get_parameter_as_f32("presence_penalty"),
+                frequency_penalty: from.get_parameter_as_f32("frequency_penalty"),
+            },
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OllamaMessage {
+    pub role: String,
+    pub content: String,
+}
+
+// A chunk of the stream, the last one has the statistics. Durations are in nanoseconds.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OllamaChatResponse {
+    pub model: Option<String>,
+    pub created_at: Option<String>,
+    pub message: Option<OllamaMessage>,
+    #[serde(default)]
+    pub done: bool,
+    pub done_reason: Option<String>,
+    pub total_duration: Option<i64>,
+    pub prompt_eval_count: Option<i32>,
+    pub prompt_eval_duration: Option<i64>,
+    pub eval_count: Option<i32>,
+    pub eval_duration: Option<i64>,
+    pub error: Option<String>,
+}
+
+impl OllamaChatResponse {
+    fn content(&self) -> &str {
+        self.message.as_ref().map_or("", |message| message.content.as_str())
+    }
+
+    fn to_llm_usage(&self) -> LlmUsage {
+        let ms = |duration: Option<i64>| duration.map(|d| d / 1_000_000);
+        let per_second = |count: Option<i32>, duration: Option<i64>| {
+            match (count, duration) {
+                (Some(count), Some(duration)) if duration > 0 => {
+                    Some(((count as f32) / (duration as f32)) * 1_000_000_000.0)
+                }
+                _ => None,
+            }
+        };
+        let mut usage = LlmUsage::new();
+        usage.prompt_tokens = self.prompt_eval_count;
+        usage.completion_tokens = self.eval_count;
+        usage.total_tokens = match (self.prompt_eval_count, self.eval_count) {
+            (None, None) => None,
+            (prompt, completion) => Some(prompt.unwrap_or(0) + completion.unwrap_or(0)),
+        };
+        usage.prompt_ms = ms(self.prompt_eval_duration);
+        usage.completion_ms = ms(self.eval_duration);
+        usage.total_ms = ms(self.total_duration);
+        usage.prompt_per_second = per_second(self.prompt_eval_count, self.prompt_eval_duration);
+        usage.completion_per_second = per_second(self.eval_count, self.eval_duration);
+        usage.total_per_second = per_second(usage.total_tokens, self.total_duration);
+        usage
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OllamaModelDetails {
+    pub format: Option<String>,
+    pub family: Option<String>,
+    pub parameter_size: Option<String>,
+    pub quantization_level: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OllamaModel {
+    pub name: String,
+    pub model: Option<String>,
+    pub modified_at: Option<String>,
+    pub size: Option<u64>,
+    pub digest: Option<String>,
+    pub details: Option<OllamaModelDetails>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OllamaTagsResponse {
+    pub models: Vec<OllamaModel>,
+}
+
+impl OllamaModel {
+    fn to_model(&self) -> Model {
+        let mut model = Model::new(self.name.clone());
+        model.id = Some(self.name.clone());
+        model.updated_at = self.modified_at
+            .as_ref()
+            .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+            .map(|date| date.with_timezone(&Utc));
+        model.file_size = self.size;
+        model.sha = self.digest.clone();
+        if let Some(details) = &self.details {
+            model.creator = details.family.clone();
+            model.library = details.format.clone();
+            model.quantization = details.quantization_level.clone();
+            model.summary = details.parameter_size.clone();
+        }
+        model
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OllamaPullProgress {
+    pub status: Option<String>,
+    pub digest: Option<String>,
+    pub total: Option<u64>,
+    pub completed: Option<u64>,
+    pub error: Option<String>,
+}
+
+fn ollama_error(message: &str) -> LlmError {
+    LlmError::new(message, "ollama_error")
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct OllamaResponseError {
+    error: String,
+}
+
+async fn send(
+    request: reqwest::RequestBuilder
+) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+    let response = match request.send().await {
+        Ok(res) => res,
+        Err(error) => {
+            println!("Failed to send: {}", error);
+            return Err(Box::new(error));
+        }
+    };
+    let status = response.status();
+    if !status.is_success() {
+        let message = match response.json::<OllamaResponseError>().await {
+            Ok(error) => error.error,
+            Err(error) => format!("Failed to dezerialize error response: {}", error),
+        };
+        println!("Failed to get response: {} {:?}", status, message);
+        return Err(Box::new(ollama_error(&message)));
+    }
+    Ok(response)
+}
+
+// Calls on_line with each line of the response until it returns false.
+async fn read_lines<T: DeserializeOwned>(
+    response: reqwest::Response,
+    mut on_line: impl FnMut(T) -> Result<bool, LlmError>
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = response.bytes_stream();
+    let mut buffer: Vec<u8> = vec![];
+    let mut parse = |line: &[u8]| -> Result<bool, Box<dyn std::error::Error>> {
+        if line.iter().all(|b| b.is_ascii_whitespace()) {
+            return Ok(true);
+        }
+        let value = serde_json::from_slice::<T>(line).map_err(|error| {
+            println!("Failed to dezerialize line: {}", error);
+            error
+        })?;
+        Ok(on_line(value)?)
+    };
+    while let Some(chunk) = stream.next().await {
+        buffer.extend_from_slice(&chunk?);
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            if !parse(&line)? {
+                return Ok(());
+            }
+        }
+    }
+    parse(&buffer)?;
+    Ok(())
+}
+
+pub async fn call_models(api: &str) -> Result<LlmModelsResponse, Box<dyn std::error::Error>> {
+    let url = format!("{}/api/tags", api);
+    println!("{}", format!("models call:  {:?}", url));
+
+    let response = send(reqwest::Client::new().get(url)).await?;
+    let response = match response.json::<OllamaTagsResponse>().await {
+        Ok(r) => r,
+        Err(error) => {
+            println!("Failed to dezerialize response: {}", error);
+            return Err(Box::new(error));
+        }
+    };
+
+    Ok(LlmModelsResponse {
+        models: response.models
+            .iter()
+            .map(|model| model.to_model())
+            .collect(),
+    })
+}
+
+pub async fn call_completion<R: Runtime>(
+    api: &str,
+    model: &str,
+    context_window: Option<i32>,
+    query: LlmQuery<LlmQueryCompletion>,
+    completion_options: Option<LlmCompletionOptions>,
+    callback: Option<impl FnMut(Result<LlmCompletionResponse, LlmError>) + Copy>
+) -> Result<LlmCompletionResponse, Box<dyn std::error::Error>> {
+    let start_time = chrono::Utc::now().timestamp_millis();
+    let url = format!("{}/api/chat", api);
+    println!(
+        "{}",
+        format!("llm call:  {:?} / {:?} / {:?} / {:?}", query.command, url, &model, query.options)
+    );
+    let send_callback = |result: Result<LlmCompletionResponse, LlmError>| {
+        if let Some(mut cb) = callback {
+            cb(result);
+        }
+    };
+
+    let counter = TokenCounter::new(model, ChatFormat::OpenAI)?;
+    let parameters = OllamaBodyChat::new(
+        model.to_owned(),
+        &query.options,
+        completion_options,
+        &counter,
+        context_window
+    )?;
+    println!("llm call parameters:  {:?}", parameters);
+    let prompt_tokens = counter.count_messages(&parameters.messages);
+    let stream = parameters.stream;
+    let response = match send(reqwest::Client::new().post(url).json(&parameters)).await {
+        Ok(response) => response,
+        Err(error) => {
+            send_callback(Err(ollama_error(&error.to_string())));
+            return Err(error);
+        }
+    };
+    let mut content = String::new();
+    let mut last: Option<OllamaChatResponse> = None;
+    let result = read_lines(response, |chunk: OllamaChatResponse| {
+        if let Some(error) = &chunk.error {
+            return Err(ollama_error(error));
+        }
+        content.push_str(chunk.content());
+        if stream {
+            let (status, text) = if chunk.done {
+                ("finished", "done")
+            } else {
+                ("success", chunk.content())
+            };
+            let created = chrono::Utc::now().timestamp_millis();
+            send_callback(Ok(LlmCompletionResponse::new(created, status, text)));
+        }
+        let done = chunk.done;
+        last = Some(chunk);
+        Ok(!done)
+    }).await;
+    if let Err(error) = result {
+        println!("Error in stream: {}", error);
+        send_callback(Err(ollama_error(&error.to_string())));
+        return Err(error);
+    }
+
+    let mut result = LlmCompletionResponse::new(
+        chrono::Utc::now().timestamp_millis(),
+        "finished",
+        &content
+    );
+    let usage = last.map(|chunk| chunk.to_llm_usage());
+    let end_time = chrono::Utc::now().timestamp_millis() - start_time;
+    let mut usage = counter.fill_usage(usage, prompt_tokens, &result.content);
+    if usage.total_ms.is_none() {
+        usage.total_ms = Some(end_time);
+    }
+    println!("llm call duration:  {:?} usage={:?}", end_time, usage);
+    result.usage = Some(usage);
+    Ok(result)
+}
+
+// Downloads the model into the Ollama library.
+pub async fn pull_model(
+    api: &str,
+    model: &str,
+    mut on_progress: impl FnMut(&OllamaPullProgress)
+) -> Result<(), Box<dyn std::error::Error>> {
+    let url = format!("{}/api/pull", api);
+    println!("{}", format!("pull call:  {:?} / {:?}", url, model));
+
+    let body = serde_json::json!({ "model": model, "stream": true });
+    let response = send(reqwest::Client::new().post(url).json(&body)).await?;
+    let mut success = false;
+    read_lines(response, |progress: OllamaPullProgress| {
+        if let Some(error) = &progress.error {
+            return Err(ollama_error(error));
+        }
+        success = progress.status.as_deref() == Some("success");
+        on_progress(&progress);
+        Ok(!success)
+    }).await?;
+    if !success {
+        return Err(Box::new(ollama_error(&format!("Pull of {} not finished", model))));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ cell::RefCell, io::{ Read, Write }, net::TcpListener, thread, time::Duration };
+    use crate::providers::llm::LlmParameter;
+    use super::*;
+
+    // Serves one request with the chunks, sent separately in a chunked response.
+    fn serve(chunks: &[&str]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let chunks: Vec<String> = chunks
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                let len = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..len]);
+            }
+            let header = concat!(
+                "HTTP/1.1 200 OK\r\n",
+                "Content-Type: application/x-ndjson\r\n",
+                "Transfer-Encoding: chunked\r\n\r\n"
+            );
+            stream.write_all(header.as_bytes()).unwrap();
+            for chunk in chunks {
+                write!(stream, "{:x}\r\n{}\r\n", chunk.len(), chunk).unwrap();
+                stream.flush().unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+            stream.write_all(b"0\r\n\r\n").unwrap();
+        });
+        url
+    }
+
+    fn query(stream: bool) -> LlmQuery<LlmQueryCompletion> {
+        LlmQuery {
+            command: "completion".to_string(),
+            options: LlmQueryCompletion {
+                conversation_id: None,
+                message_id: None,
+                messages: vec![LlmMessage::new("user", "Hello")],
+                prompt: None,
+                parameters: Some(
+                    vec![LlmParameter { key: "stream".to_string(), value: stream.to_string() }]
+                ),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_lines_across_chunks() {
+        let url = serve(&["{\"a\":", "1}\n{\"a\":2}\n\n{\"a\"", ":3}"]);
+        let response = reqwest::get(url).await.unwrap();
+        let mut values = vec![];
+        read_lines(response, |value: serde_json::Value| {
+            values.push(value["a"].as_i64().unwrap());
+            Ok(true)
+        }).await.unwrap();
+        assert_eq!(values, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_completion_usage() {
+        let url = serve(
+            &[
+                "{\"message\":{\"role\":\"assistant\",\"content\":\"Hel\"},\"done\":false}\n{\"mess",
+                "age\":{\"role\":\"assistant\",\"content\":\"lo\"},\"done\":false}\n",
+                concat!(
+                    "{\"done\":true,\"done_reason\":\"stop\",\"total_duration\":3000000000,",
+                    "\"prompt_eval_count\":10,\"prompt_eval_duration\":1000000000,",
+                    "\"eval_count\":2,\"eval_duration\":1000000000}\n"
+                ),
+            ]
+        );
+        let events = RefCell::new(vec![]);
+        let events_ref = &events;
+        let callback = Some(move |result: Result<LlmCompletionResponse, LlmError>| {
+            events_ref.borrow_mut().push(result.map(|r| (r.status, r.content)).unwrap());
+        });
+        let response = call_completion::<tauri::Wry>(
+            &url,
+            "llama3",
+            None,
+            query(true),
+            None,
+            callback
+        ).await.unwrap();
+        assert_eq!(response.content, "Hello");
+        assert_eq!(events.borrow().len(), 3);
+        assert_eq!(events.borrow()[2], ("finished".to_string(), "done".to_string()));
+        let usage = response.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, Some(10));
+        assert_eq!(usage.completion_tokens, Some(2));
+        assert_eq!(usage.total_tokens, Some(12));
+        assert_eq!(usage.prompt_ms, Some(1000));
+        assert_eq!(usage.total_ms, Some(3000));
+        assert_eq!(usage.completion_per_second, Some(2.0));
+        assert_eq!(usage.total_per_second, Some(4.0));
+    }
+
+    #[tokio::test]
+    async fn test_completion_error_line() {
+        let url = serve(
+            &[
+                "{\"message\":{\"role\":\"assistant\",\"content\":\"Hel\"},\"done\":false}\n",
+                "{\"error\":\"model crashed\"}\n",
+            ]
+        );
+        let errors = RefCell::new(vec![]);
+        let errors_ref = &errors;
+        let callback = Some(move |result: Result<LlmCompletionResponse, LlmError>| {
+            if let Err(error) = result {
+                errors_ref.borrow_mut().push(error.message);
+            }
+        });
+        let error = call_completion::<tauri::Wry>(
+            &url,
+            "llama3",
+            None,
+            query(false),
+            None,
+            callback
+        ).await.unwrap_err();
+        assert!(error.to_string().contains("model crashed"));
+        assert_eq!(errors.borrow().len(), 1);
+        assert!(errors.borrow()[0].contains("model crashed"));
+    }
+
+    #[tokio::test]
+    async fn test_pull_model() {
+        let url = serve(
+            &[
+                "{\"status\":\"pulling manifest\"}\n{\"status\":\"downloading\",\"digest\":\"sha256:1\",",
+                "\"total\":100,\"completed\":50}\n{\"status\":\"success\"}\n",
+            ]
+        );
+        let mut statuses = vec![];
+        pull_model(&url, "llama3", |progress| {
+            statuses.push(progress.status.clone().unwrap());
+        }).await.unwrap();
+        assert_eq!(statuses, vec!["pulling manifest", "downloading", "success"]);
+
+        // The stream ends before success
+        let url = serve(
+            &[
+                "{\"status\":\"pulling manifest\"}\n",
+                "{\"status\":\"downloading\",\"total\":100,\"completed\":50}\n",
+            ]
+        );
+        let mut completed = vec![];
+        let error = pull_model(&url, "llama3", |progress| {
+            completed.push(progress.completed);
+        }).await.unwrap_err();
+        assert!(error.to_string().contains("Pull of llama3 not finished"));
+        assert_eq!(completed, vec![None, Some(50)]);
+
+        let url = serve(
+            &[
+                "{\"status\":\"pulling manifest\"}\n",
+                "{\"error\":\"pull model manifest: file does not exist\"}\n",
+            ]
+        );
+        let error = pull_model(&url, "unknown", |_| {}).await.unwrap_err();
+        assert!(error.to_string().contains("file does not exist"));
+    }
+}
@@ -18,8 +18,6 @@ use tauri::Runtime;
 use eventsource_stream::Eventsource;
 use futures_util::stream::StreamExt;
 
-use tokenizer::encode;
-
 use crate::{
     data::model::{Logo, Model},
     providers::llm::{
@@ -34,7 +32,10 @@ use crate::{
     },
 };
 
-use super::llm::{ LlmImageGenerationResponse, LlmModelsResponse };
+use super::{
+    llm::{ LlmImageGenerationResponse, LlmModelsResponse },
+    tokens::{ ChatFormat, TokenCounter },
+};
 
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -55,28 +56,18 @@ impl OpenAIBodyCompletion {
     pub fn new(
         model: String,
         from: &LlmQueryCompletion,
-        options: Option<LlmCompletionOptions>
-    ) -> Self {
-        let mut messages: Vec<LlmMessage> = vec![];
-
-        match options {
-            Some(options) => {
-                match options.system {
-                    Some(system) => {
-                        messages.push(LlmMessage {
-                            content: system.clone(),
-                            role: "system".to_owned(),
-                            name: None,
-                        });
-                    }
-                    None => {}
-                }
-            }
-            None => {}
-        }
-        messages.extend(from.messages.clone());
-        // TODO: handle context_window_policy and keep_system
-        Self {
+        options: Option<LlmCompletionOptions>,
+        counter: &TokenCounter,
+        context_window: Option<i32>
+    ) -> Result<Self, LlmError> {
+        let max_tokens = from.get_parameter_as_f32("max_tokens");
+        let messages = counter.fit_messages(
+            from.get_messages(&options),
+            &options,
+            max_tokens,
+            context_window
+        )?;
+        Ok(Self {
             model,
             messages,
             stream: from.get_parameter_as_boolean("stream"),
@@ -86,8 +77,8 @@ impl OpenAIBodyCompletion {
             presence_penalty: from.get_parameter_as_f32("presence_penalty"),
             seed: from.get_parameter_as_f32("seed"),
             top_p: from.get_parameter_as_f32("top_p"),
-            max_tokens: from.get_parameter_as_f32("max_tokens"),
-        }
+            max_tokens,
+        })
     }
 }
 
@@ -139,7 +130,7 @@ pub struct OpenAIChatCompletion {
     pub object: String,
     pub created: i64,
     pub system_fingerprint: Option<String>,
-    pub usage: OpenAIChatUsage,
+    pub usage: Option<OpenAIChatUsage>,
 }
 
 impl OpenAIChatCompletion {
@@ -181,13 +172,9 @@ impl OpenAIChatCompletion {
             object,
             created,
             system_fingerprint,
-            usage: OpenAIChatUsage {
-                completion_tokens: 0,
-                prompt_tokens: 0,
-                total_tokens: 0,
-            },
-            // TODO implement usage
+            // Usage isn't streamed, it's counted by call_completion
             // see : https://community.openai.com/t/why-there-is-no-usage-object-returned-with-streaming-api-call/385160/15
+            usage: None,
         }
     }
 
@@ -197,22 +184,46 @@ impl OpenAIChatCompletion {
             "finished",
             &self.choices[0].message.content
         );
-        let usage = LlmUsage {
-            completion_tokens: Some(self.usage.completion_tokens),
-            prompt_tokens: Some(self.usage.prompt_tokens),
-            total_tokens: Some(self.usage.total_tokens),
+        response.usage = self.usage.as_ref().map(|usage| LlmUsage {
+            completion_tokens: Some(usage.completion_tokens),
+            prompt_tokens: Some(usage.prompt_tokens),
+            total_tokens: Some(usage.total_tokens),
             completion_ms: None,
             prompt_ms: None,
             total_ms: None,
             prompt_per_second: None,
             completion_per_second: None,
             total_per_second: None,
-        };
-        response.usage = Some(usage);
+        });
         response
     }
 }
 
+// Content of a streamed chat completion chunk, None at the end of the stream.
+// Used by the other OpenAI compatible APIs.
+pub fn parse_chunk(data: &str) -> Result<Option<String>, LlmError> {
+    if data == "[DONE]" {
+        return Ok(None);
+    }
+    let chunk = serde_json::from_str::<OpenAIChatCompletionChunk>(data).map_err(|error| {
+        let message = format!("Failed to parse chunk: {}", error);
+        println!("{}", message);
+        LlmError::new(&message, "FailedParsingResponse")
+    })?;
+    let content = chunk.choices
+        .first()
+        .and_then(|choice| choice.delta.content.clone())
+        .unwrap_or_default();
+    Ok(Some(content))
+}
+
+pub fn parse_completion(full: &[u8]) -> Result<LlmCompletionResponse, LlmError> {
+    serde_json
+        ::from_slice::<OpenAIChatCompletion>(full)
+        .map(|completion| completion.to_llm_response())
+        .map_err(|error| LlmError::new(&error.to_string(), "FailedParsingResponse"))
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OpenAIBodyImageGeneration {
@@ -470,6 +481,7 @@ pub async fn call_completion<R: Runtime>(
     api: &str,
     secret_key: &str,
     model: &str,
+    context_window: Option<i32>,
     query: LlmQuery<LlmQueryCompletion>,
     completion_options: Option<LlmCompletionOptions>,
     callback: Option<impl FnMut(Result<LlmCompletionResponse, LlmError>) + Copy>
@@ -481,12 +493,16 @@ pub async fn call_completion<R: Runtime>(
         format!("llm call:  {:?} / {:?} / {:?} / {:?}", query.command, url, &model, query.options)
     );
 
+    let counter = TokenCounter::new(model, ChatFormat::OpenAI)?;
     let parameters = OpenAIBodyCompletion::new(
         model.to_owned(),
         &query.options,
-        completion_options
-    );
+        completion_options,
+        &counter,
+        context_window
+    )?;
     println!("llm call parameters:  {:?}", parameters);
+    let prompt_tokens = counter.count_messages(&parameters.messages);
     let stream = match parameters.stream {
         Some(t) => t,
         None => false,
@@ -499,7 +515,7 @@ pub async fn call_completion<R: Runtime>(
         result = request::<R>(url, secret_key, parameters).await?;
     }
     let end_time = chrono::Utc::now().timestamp_millis() - start_time;
-    let mut usage = result.usage.unwrap_or(LlmUsage::new());
+    let mut usage = counter.fill_usage(result.usage, prompt_tokens, &result.content);
     usage.total_ms = Some(end_time);
     let total_tokens = usage.total_tokens.unwrap_or(0);
     if total_tokens > 0 && end_time > 0 {
@@ -662,30 +678,3 @@ pub async fn call_models(
         models,
     })
 }
-
-fn encode_length(text: String) -> usize {
-    match encode(text, "gpt".to_owned(), None) {
-        Ok(ranks) => ranks.len(),
-        Err(_) => 0,
-    }
-}
-
-// See:
-// https://platform.openai.com/docs/guides/text-generation/managing-tokens
-// https://cookbook.openai.com/examples/how_to_count_tokens_with_tiktoken
-pub fn num_tokens_from_messages(messages: &Vec<LlmMessage>) -> usize {
-    let mut num_tokens = 0;
-    for message in messages {
-        num_tokens += 4; // every message follows <im_start>{role/name}\n{content}<im_end>\n
-        num_tokens += encode_length(message.content.clone());
-        num_tokens += 1; // message.role
-        match &message.name {
-            Some(name) => {
-                num_tokens += encode_length(name.to_string()) - 1;
-            }
-            None => {}
-        }
-        num_tokens += 2; // # every reply is primed with <im_start>assistant
-    }
-    num_tokens
-}
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use reqwest::{ header::{ HeaderValue, CONTENT_TYPE }, Client, RequestBuilder, Response };
+use reqwest::{ header::{ HeaderValue, CONTENT_TYPE }, Client, Response, StatusCode };
 use serde::{ Deserialize, Serialize };
 use eventsource_stream::Eventsource;
 use futures_util::stream::StreamExt;
@@ -31,6 +31,14 @@ pub struct HttpService<R, E> {
     pub secret_key: Option<String>,
     pub output: Option<R>,
     pub error: Option<E>,
+    pub fallback: Option<HttpFallback<E>>,
+}
+
+// Request sent instead when the server doesn't have the endpoint of the first one.
+pub struct HttpFallback<E> {
+    pub adapter: ProviderAdapter,
+    pub url: String,
+    pub body: Result<Vec<u8>, E>,
 }
 
 impl<R, E> HttpService<R, E>
@@ -58,9 +66,24 @@ impl<R, E> HttpService<R, E>
             adapter: adapter.clone(),
             output: None,
             error: None,
+            fallback: None,
         }
     }
 
+    pub fn with_fallback<S: Serialize>(
+        mut self,
+        url: String,
+        body: S,
+        adapter: &mut ProviderAdapter
+    ) -> Self {
+        self.fallback = Some(HttpFallback {
+            url,
+            body: adapter.serialize_parameters(body),
+            adapter: adapter.clone(),
+        });
+        self
+    }
+
     pub async fn stream_request(
         &mut self,
         response: Response,
@@ -138,7 +161,34 @@ impl<R, E> HttpService<R, E>
         result
     }
 
-    async fn get_response(&mut self, client_builder: RequestBuilder) -> Result<Response, E>
+    async fn send_request(&mut self) -> Result<Response, E> {
+        let body = match &self.body {
+            Ok(body) => body.clone(),
+            Err(err) => {
+                println!("HttpClient body error {}", err);
+                return Err(err.clone());
+            }
+        };
+        let client_builder = Client::new().post(&self.url);
+        let client_builder = match &self.secret_key {
+            Some(secret) => client_builder.bearer_auth(&secret),
+            None => client_builder,
+        };
+        // let client_builder = client_builder.json(&self.body);
+        let result = client_builder
+            .body(body)
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .send().await;
+        match result {
+            Ok(res) => Ok(res),
+            Err(error) => {
+                println!("Failed to get response: {}", error);
+                Err(E::new(&error.to_string(), "http_error"))
+            }
+        }
+    }
+
+    async fn get_response(&mut self) -> Result<Response, E>
         where
             E: for<'de> Deserialize<'de> +
                 HttpError +
@@ -147,15 +197,16 @@ impl<R, E> HttpService<R, E>
                 std::error::Error +
                 'static
     {
-        let result = client_builder.send().await;
-        let response = match result {
-            Ok(res) => res,
-            Err(error) => {
-                println!("Failed to get response: {}", error);
-                let err = E::new(&error.to_string(), "http_error");
-                return Err(err);
+        let mut response = self.send_request().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            if let Some(fallback) = self.fallback.take() {
+                println!("HttpClient {} not found, fallback to {}", self.url, fallback.url);
+                self.url = fallback.url;
+                self.body = fallback.body;
+                self.adapter = fallback.adapter;
+                response = self.send_request().await?;
             }
-        };
+        }
         let status = response.status();
         if !status.is_success() {
             let result = response
@@ -173,24 +224,7 @@ impl<R, E> HttpService<R, E>
         is_stream: bool,
         /* sender: Sender<Result<R, E>> */ mut send: impl FnMut(Result<R, E>) -> ()
     ) {
-        let body = match &self.body {
-            Ok(body) => body.clone(),
-            Err(err) => {
-                println!("HttpClient body error {}", err);
-                let _ = send(Err(err.clone()));
-                return;
-            }
-        };
-        let client_builder = Client::new().post(&self.url);
-        let client_builder = match &self.secret_key {
-            Some(secret) => client_builder.bearer_auth(&secret),
-            None => client_builder,
-        };
-        // let client_builder = client_builder.json(&self.body);
-        let client_builder = client_builder
-            .body(body)
-            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        let response = match self.get_response(client_builder).await {
+        let response = match self.get_response().await {
             Ok(r) => r,
             Err(err) => {
                 println!("HttpClient getResponse error {}", err);
@@ -0,0 +1,391 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Jinja chat templates, rendered like the apply_chat_template of the transformers library
+// https://huggingface.co/docs/transformers/main/en/chat_templating
+
+use std::sync::Arc;
+use minijinja::{ context, Environment, Error, ErrorKind };
+use minijinja_contrib::pycompat::unknown_method_callback;
+use tokenizer::{ gguf::{ GGUFTokenizer, GGUFTokenizerModel }, Rank, Tokenizer };
+
+use super::llm::LlmMessage;
+
+pub const CHATML: &str =
+    "{% for message in messages %}\
+{{ '<|im_start|>' + message['role'] + '\\n' + message['content'] + '<|im_end|>' + '\\n' }}\
+{% endfor %}\
+{% if add_generation_prompt %}{{ '<|im_start|>assistant\\n' }}{% endif %}";
+
+pub const LLAMA3: &str =
+    "{{ bos_token }}\
+{% for message in messages %}\
+{{ '<|start_header_id|>' + message['role'] + '<|end_header_id|>\\n\\n' + message['content'] | trim + '<|eot_id|>' }}\
+{% endfor %}\
+{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>\\n\\n' }}{% endif %}";
+
+// Mistral and Llama 2 have no system role, the system prompt starts the next user message
+pub const MISTRAL: &str =
+    "{{ bos_token }}\
+{% set ns = namespace(system='') %}\
+{% for message in messages %}\
+{% if message['role'] == 'system' %}{% set ns.system = ns.system + message['content'] + '\\n\\n' %}\
+{% elif message['role'] == 'user' %}{{ '[INST] ' + ns.system + message['content'] + ' [/INST]' }}{% set ns.system = '' %}\
+{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token }}\
+{% endif %}\
+{% endfor %}";
+
+// Gemma has no system role and names the assistant "model"
+pub const GEMMA: &str =
+    "{{ bos_token }}\
+{% set ns = namespace(system='') %}\
+{% for message in messages %}\
+{% if message['role'] == 'system' %}{% set ns.system = ns.system + message['content'] + '\\n\\n' %}\
+{% else %}\
+{% set role = 'model' if message['role'] == 'assistant' else message['role'] %}\
+{{ '<start_of_turn>' + role + '\\n' + ns.system + message['content'] | trim + '<end_of_turn>\\n' }}\
+{% set ns.system = '' %}\
+{% endif %}\
+{% endfor %}\
+{% if add_generation_prompt %}{{ '<start_of_turn>model\\n' }}{% endif %}";
+
+pub const PHI3: &str =
+    "{% for message in messages %}\
+{{ '<|' + message['role'] + '|>\\n' + message['content'] + '<|end|>\\n' }}\
+{% endfor %}\
+{% if add_generation_prompt %}{{ '<|assistant|>\\n' }}{% endif %}";
+
+const TEMPLATE_NAME: &str = "chat_template";
+
+#[derive(Clone, Debug)]
+pub struct ChatTemplate {
+    source: String,
+    // Compiled once, as a template is rendered many times to fit the messages in the context
+    env: Result<Arc<Environment<'static>>, String>,
+    pub bos_token: String,
+    pub eos_token: String,
+    // The server adds the BOS token to the prompt, it is removed from the rendered template
+    pub add_bos_token: bool,
+}
+
+impl ChatTemplate {
+    pub fn new(source: &str) -> Self {
+        ChatTemplate {
+            source: source.to_string(),
+            env: ChatTemplate::compile(source),
+            bos_token: String::new(),
+            eos_token: String::new(),
+            add_bos_token: false,
+        }
+    }
+
+    fn compile(source: &str) -> Result<Arc<Environment<'static>>, String> {
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(unknown_method_callback);
+        env.add_function("raise_exception", |message: String| -> Result<String, Error> {
+            Err(Error::new(ErrorKind::InvalidOperation, message))
+        });
+        env.add_function("strftime_now", |format: String| {
+            chrono::Local::now().format(&format).to_string()
+        });
+        env
+            .add_template_owned(TEMPLATE_NAME, source.to_string())
+            .map_err(|err| format!("Invalid chat template: {}", err))?;
+        Ok(Arc::new(env))
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn builtin(name: &str) -> Option<&'static str> {
+        match name.to_lowercase().as_str() {
+            "chatml" => Some(CHATML),
+            "llama3" => Some(LLAMA3),
+            "mistral" | "llama2" => Some(MISTRAL),
+            "gemma" => Some(GEMMA),
+            "phi3" => Some(PHI3),
+            _ => None,
+        }
+    }
+
+    // Guesses the template from the special tokens of the vocabulary.
+    pub fn detect(tokenizer: &GGUFTokenizer) -> &'static str {
+        let has_token = |token: &str| tokenizer.token_to_id(token).is_some();
+        if has_token("<|start_header_id|>") {
+            LLAMA3
+        } else if has_token("<start_of_turn>") {
+            GEMMA
+        } else if has_token("<|im_start|>") {
+            CHATML
+        } else if has_token("<|assistant|>") && has_token("<|end|>") {
+            PHI3
+        } else if has_token("[INST]") || tokenizer.model == GGUFTokenizerModel::Llama {
+            MISTRAL
+        } else {
+            CHATML
+        }
+    }
+
+    // The template of a local model is its chat_template, either a builtin template name or a
+    // Jinja template, then the one of its GGUF file, or else the one guessed from its vocabulary.
+    // Defaults to ChatML.
+    pub fn for_model(chat_template: Option<&str>, tokenizer: Option<&GGUFTokenizer>) -> Self {
+        let chat_template = match chat_template {
+            Some(template) if !template.trim().is_empty() => Some(template),
+            _ => tokenizer.and_then(|tokenizer| tokenizer.chat_template()),
+        };
+        let source = match (chat_template, tokenizer) {
+            (Some(template), _) if !template.trim().is_empty() =>
+                ChatTemplate::builtin(template.trim()).unwrap_or(template),
+            (_, Some(tokenizer)) => ChatTemplate::detect(tokenizer),
+            _ => CHATML,
+        };
+        let mut template = ChatTemplate::new(source);
+        if let Some(tokenizer) = tokenizer {
+            let token = |id: Option<Rank>| {
+                id.and_then(|id| tokenizer.id_to_token(id))
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            template.bos_token = token(tokenizer.bos_token_id());
+            template.eos_token = token(tokenizer.eos_token_id());
+            template.add_bos_token = tokenizer.add_bos_token();
+        }
+        template
+    }
+
+    pub fn render(
+        &self,
+        messages: &Vec<LlmMessage>,
+        add_generation_prompt: bool
+    ) -> Result<String, String> {
+        let env = self.env.as_ref().map_err(|err| err.clone())?;
+        let template = env
+            .get_template(TEMPLATE_NAME)
+            .map_err(|err| format!("Invalid chat template: {}", err))?;
+        let prompt = template
+            .render(context! {
+                messages => messages,
+                bos_token => self.bos_token,
+                eos_token => self.eos_token,
+                add_generation_prompt => add_generation_prompt,
+            })
+            .map_err(|err| format!("Chat template error: {}", err))?;
+        match prompt.strip_prefix(&self.bos_token) {
+            Some(prompt) if self.add_bos_token && !self.bos_token.is_empty() => {
+                Ok(prompt.to_string())
+            }
+            _ => Ok(prompt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opla_core::gguf::{
+        GGUFMetadataArrayValue,
+        GGUFMetadataValue,
+        GGUFMetadataValueType,
+        GGUF,
+        TOKENIZER_CHAT_TEMPLATE,
+        TOKENIZER_MODEL,
+        TOKENIZER_TOKENS,
+    };
+    use tokenizer::gguf::{
+        TOKENIZER_ADD_BOS_TOKEN,
+        TOKENIZER_BOS_TOKEN_ID,
+        TOKENIZER_EOS_TOKEN_ID,
+        TOKENIZER_MERGES,
+    };
+    use super::*;
+
+    // The Mistral template of HuggingFace, only alternating user and assistant roles
+    const HF_MISTRAL: &str =
+        "{{ bos_token }}{% for message in messages %}\
+{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}\
+{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}\
+{% endif %}\
+{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'].strip() + ' [/INST]' }}\
+{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token}}\
+{% else %}{{ raise_exception('Only user and assistant roles are supported!') }}\
+{% endif %}{% endfor %}";
+
+    fn messages() -> Vec<LlmMessage> {
+        vec![
+            LlmMessage::new("system", "You are helpful."),
+            LlmMessage::new("user", "Hi"),
+            LlmMessage::new("assistant", "Hello!"),
+            LlmMessage::new("user", "How are you?")
+        ]
+    }
+
+    fn template(source: &str, bos_token: &str, eos_token: &str) -> ChatTemplate {
+        let mut template = ChatTemplate::new(source);
+        template.bos_token = bos_token.to_string();
+        template.eos_token = eos_token.to_string();
+        template
+    }
+
+    fn tokenizer(
+        tokens: &[&str],
+        add_bos_token: bool,
+        chat_template: Option<&str>
+    ) -> GGUFTokenizer {
+        let strings = |values: &[&str]| {
+            GGUFMetadataValue::Array(GGUFMetadataArrayValue {
+                value_type: GGUFMetadataValueType::String,
+                len: values.len() as u64,
+                value: values
+                    .iter()
+                    .map(|v| GGUFMetadataValue::String(v.to_string()))
+                    .collect(),
+            })
+        };
+        let mut gguf = GGUF::new("template");
+        gguf.set_metadata(TOKENIZER_MODEL, GGUFMetadataValue::String("gpt2".to_string()));
+        gguf.set_metadata(TOKENIZER_TOKENS, strings(tokens));
+        gguf.set_metadata(TOKENIZER_MERGES, strings(&[]));
+        gguf.set_metadata(TOKENIZER_BOS_TOKEN_ID, GGUFMetadataValue::Uint32(0));
+        gguf.set_metadata(TOKENIZER_EOS_TOKEN_ID, GGUFMetadataValue::Uint32(1));
+        gguf.set_metadata(TOKENIZER_ADD_BOS_TOKEN, GGUFMetadataValue::Bool(add_bos_token));
+        if let Some(chat_template) = chat_template {
+            let value = GGUFMetadataValue::String(chat_template.to_string());
+            gguf.set_metadata(TOKENIZER_CHAT_TEMPLATE, value);
+        }
+        GGUFTokenizer::from_gguf(&gguf).unwrap()
+    }
+
+    #[test]
+    fn test_render_builtins() {
+        let messages = messages();
+        let render = |source: &str, bos_token: &str, eos_token: &str| {
+            template(source, bos_token, eos_token).render(&messages, true).unwrap()
+        };
+        assert_eq!(
+            render(CHATML, "", ""),
+            "<|im_start|>system\nYou are helpful.<|im_end|>\n\
+<|im_start|>user\nHi<|im_end|>\n\
+<|im_start|>assistant\nHello!<|im_end|>\n\
+<|im_start|>user\nHow are you?<|im_end|>\n\
+<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            render(LLAMA3, "<|begin_of_text|>", "<|eot_id|>"),
+            "<|begin_of_text|>\
+<|start_header_id|>system<|end_header_id|>\n\nYou are helpful.<|eot_id|>\
+<|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>\
+<|start_header_id|>assistant<|end_header_id|>\n\nHello!<|eot_id|>\
+<|start_header_id|>user<|end_header_id|>\n\nHow are you?<|eot_id|>\
+<|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+        assert_eq!(
+            render(MISTRAL, "<s>", "</s>"),
+            "<s>[INST] You are helpful.\n\nHi [/INST]Hello!</s>[INST] How are you? [/INST]"
+        );
+        assert_eq!(
+            render(GEMMA, "<bos>", "<eos>"),
+            "<bos><start_of_turn>user\nYou are helpful.\n\nHi<end_of_turn>\n\
+<start_of_turn>model\nHello!<end_of_turn>\n\
+<start_of_turn>user\nHow are you?<end_of_turn>\n\
+<start_of_turn>model\n"
+        );
+        assert_eq!(
+            render(PHI3, "<s>", "<|endoftext|>"),
+            "<|system|>\nYou are helpful.<|end|>\n\
+<|user|>\nHi<|end|>\n\
+<|assistant|>\nHello!<|end|>\n\
+<|user|>\nHow are you?<|end|>\n\
+<|assistant|>\n"
+        );
+        assert_eq!(
+            template(CHATML, "", "").render(&messages[1..2].to_vec(), false).unwrap(),
+            "<|im_start|>user\nHi<|im_end|>\n"
+        );
+    }
+
+    #[test]
+    fn test_render_bos_token() {
+        let messages = messages()[1..2].to_vec();
+        let mut template = template(LLAMA3, "<|begin_of_text|>", "<|eot_id|>");
+        let prompt = "<|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>";
+        assert_eq!(
+            template.render(&messages, false).unwrap(),
+            format!("<|begin_of_text|>{}", prompt)
+        );
+        // The server adds it
+        template.add_bos_token = true;
+        assert_eq!(template.render(&messages, false).unwrap(), prompt);
+        // Only a leading BOS token is removed
+        let mut template = ChatTemplate { add_bos_token: true, ..ChatTemplate::new(CHATML) };
+        template.bos_token = "<|begin_of_text|>".to_string();
+        assert_eq!(
+            template.render(&messages, false).unwrap(),
+            "<|im_start|>user\nHi<|im_end|>\n"
+        );
+    }
+
+    #[test]
+    fn test_render_raise_exception() {
+        let template = template(HF_MISTRAL, "<s>", "</s>");
+        let error = template.render(&messages(), true).unwrap_err();
+        assert!(error.contains("Conversation roles must alternate"), "{}", error);
+        assert_eq!(
+            template.render(&messages()[1..].to_vec(), true).unwrap(),
+            "<s>[INST] Hi [/INST]Hello!</s>[INST] How are you? [/INST]"
+        );
+        assert!(ChatTemplate::new("{% if %}").render(&messages(), true).is_err());
+    }
+
+    #[test]
+    fn test_detect() {
+        let detect = |tokens: &[&str]| ChatTemplate::detect(&tokenizer(tokens, false, None));
+        assert_eq!(detect(&["<s>", "</s>", "<|start_header_id|>", "<|im_start|>"]), LLAMA3);
+        assert_eq!(detect(&["<bos>", "<eos>", "<start_of_turn>"]), GEMMA);
+        assert_eq!(detect(&["<s>", "</s>", "<|im_start|>"]), CHATML);
+        assert_eq!(detect(&["<s>", "</s>", "<|assistant|>", "<|end|>"]), PHI3);
+        assert_eq!(detect(&["<s>", "</s>", "<|assistant|>"]), CHATML);
+        assert_eq!(detect(&["<s>", "</s>", "[INST]"]), MISTRAL);
+        assert_eq!(detect(&["<s>", "</s>"]), CHATML);
+    }
+
+    #[test]
+    fn test_for_model() {
+        let tokenizer = tokenizer(&["<bos>", "<eos>", "<start_of_turn>"], true, None);
+        let template = ChatTemplate::for_model(None, Some(&tokenizer));
+        assert_eq!(template.source(), GEMMA);
+        assert_eq!(template.bos_token, "<bos>");
+        assert_eq!(template.eos_token, "<eos>");
+        assert!(template.add_bos_token);
+        assert_eq!(ChatTemplate::for_model(Some("  "), Some(&tokenizer)).source(), GEMMA);
+        assert_eq!(ChatTemplate::for_model(Some("Phi3"), Some(&tokenizer)).source(), PHI3);
+        assert_eq!(ChatTemplate::for_model(Some("llama2"), None).source(), MISTRAL);
+        assert_eq!(ChatTemplate::for_model(Some(HF_MISTRAL), None).source(), HF_MISTRAL);
+        assert_eq!(ChatTemplate::for_model(None, None).source(), CHATML);
+    }
+
+    #[test]
+    fn test_for_model_gguf_template() {
+        // The template of the GGUF file comes before the detected one
+        let tokens = ["<s>", "</s>", "<|im_start|>"];
+        let hf_mistral = tokenizer(&tokens, false, Some(HF_MISTRAL));
+        assert_eq!(ChatTemplate::for_model(None, Some(&hf_mistral)).source(), HF_MISTRAL);
+        assert_eq!(ChatTemplate::for_model(Some(" "), Some(&hf_mistral)).source(), HF_MISTRAL);
+        assert_eq!(ChatTemplate::for_model(Some("phi3"), Some(&hf_mistral)).source(), PHI3);
+        let gemma = tokenizer(&tokens, false, Some("gemma"));
+        assert_eq!(ChatTemplate::for_model(None, Some(&gemma)).source(), GEMMA);
+    }
+}
@@ -0,0 +1,468 @@
+// Copyright 2024 Mik Bry
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+use tokenizer::{ get_tokenizer, Tokenizer, CL100K_BASE };
+
+use crate::data::ContextWindowPolicy;
+
+use super::{
+    llm::{ LlmCompletionOptions, LlmError, LlmMessage, LlmUsage },
+    template::{ ChatTemplate, CHATML },
+};
+
+// How the messages are sent to the model.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatFormat {
+    // OpenAI chat completions, see:
+    // https://cookbook.openai.com/examples/how_to_count_tokens_with_tiktoken
+    OpenAI,
+    // Prompt rendered from the messages for the llama.cpp server
+    LlamaCpp,
+    // Human / Assistant turns after the system prompt
+    Anthropic,
+}
+
+// Counts the prompt tokens of a conversation as the provider sees them.
+#[derive(Clone)]
+pub struct TokenCounter {
+    model: String,
+    format: ChatFormat,
+    tokenizer: Arc<dyn Tokenizer>,
+    // Renders the LlamaCpp prompt
+    template: ChatTemplate,
+}
+
+impl TokenCounter {
+    // The model is a model name or the GGUF file of a local model.
+    // Models without a known tokenizer, like the Anthropic ones, are counted with cl100k_base.
+    pub fn new(model: &str, format: ChatFormat) -> Result<Self, String> {
+        let tokenizer = match get_tokenizer(model, None) {
+            Ok(tokenizer) => tokenizer,
+            Err(error) => {
+                if format == ChatFormat::LlamaCpp {
+                    return Err(error);
+                }
+                get_tokenizer(model, Some(CL100K_BASE))?
+            }
+        };
+        Ok(TokenCounter {
+            model: model.to_string(),
+            format,
+            tokenizer,
+            template: ChatTemplate::new(CHATML),
+        })
+    }
+
+    pub fn with_template(mut self, template: ChatTemplate) -> Self {
+        self.template = template;
+        self
+    }
+
+    pub fn count_text(&self, text: &str) -> usize {
+        self.tokenizer.count(text)
+    }
+
+    // Tokens of a prompt sent as is, with its special tokens.
+    pub fn count_prompt(&self, prompt: &str) -> usize {
+        let bos = match self.tokenizer.bos_token() {
+            Some(_) if self.tokenizer.add_bos_token() => 1,
+            _ => 0,
+        };
+        bos + self.tokenizer.encode_with_special(prompt).len()
+    }
+
+    pub fn count_messages(&self, messages: &Vec<LlmMessage>) -> usize {
+        match self.format {
+            ChatFormat::OpenAI => self.count_openai_messages(messages),
+            ChatFormat::LlamaCpp =>
+                match self.template.render(messages, true) {
+                    Ok(prompt) => self.count_prompt(&prompt),
+                    // The error is returned when the prompt is rendered
+                    Err(_) => messages.iter().map(|m| self.count_text(&m.content)).sum(),
+                }
+            ChatFormat::Anthropic => self.count_text(&anthropic_prompt(messages)),
+        }
+    }
+
+    fn count_openai_messages(&self, messages: &Vec<LlmMessage>) -> usize {
+        // gpt-3.5-turbo-0301 messages are <|im_start|>{role/name}\n{content}<|im_end|>\n
+        let legacy = self.model.contains("0301");
+        let tokens_per_message = if legacy { 4 } else { 3 };
+        let mut texts = vec![];
+        let mut num_tokens = 0;
+        for message in messages {
+            num_tokens += tokens_per_message;
+            texts.push(message.role.as_str());
+            texts.push(message.content.as_str());
+            if let Some(name) = &message.name {
+                // With a name the role is omitted in gpt-3.5-turbo-0301
+                texts.push(name.as_str());
+                num_tokens = if legacy { num_tokens - 1 } else { num_tokens + 1 };
+            }
+        }
+        num_tokens += self.tokenizer.count_batch(&texts).iter().sum::<usize>();
+        num_tokens + 3 // every reply is primed with <|start|>assistant<|message|>
+    }
+
+    // Applies the context window policy of the options to the messages sent to the model.
+    // Without a policy the messages are sent only if they fit in the context window.
+    // Summarizing the removed messages isn't a policy, it would need a completion call.
+    pub fn fit_messages(
+        &self,
+        messages: Vec<LlmMessage>,
+        options: &Option<LlmCompletionOptions>,
+        max_tokens: Option<f32>,
+        context_window: Option<i32>
+    ) -> Result<Vec<LlmMessage>, LlmError> {
+        let (policy, keep_system) = match options {
+            Some(options) =>
+                (
+                    options.context_window_policy.clone().unwrap_or(ContextWindowPolicy::Stop),
+                    options.keep_system.unwrap_or(true),
+                ),
+            None => (ContextWindowPolicy::Stop, true),
+        };
+        let completion_tokens = max_tokens.map(|m| m.max(0.0) as usize).unwrap_or(0);
+        let budget = context_window
+            .filter(|c| *c > 0)
+            .map(|c| (c as usize).saturating_sub(completion_tokens));
+        let messages = match policy {
+            ContextWindowPolicy::None => {
+                return Ok(messages);
+            }
+            ContextWindowPolicy::Stop => messages,
+            ContextWindowPolicy::Rolling => self.trim_messages(messages, keep_system, budget),
+            ContextWindowPolicy::Last => {
+                let last = messages.iter().rposition(|m| m.role == "user");
+                let messages = messages
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(index, m)| {
+                        Some(*index) == last || (keep_system && m.role == "system")
+                    })
+                    .map(|(_, m)| m)
+                    .collect();
+                self.trim_messages(messages, keep_system, budget)
+            }
+        };
+        self.check_context(self.count_messages(&messages), max_tokens, context_window)?;
+        Ok(messages)
+    }
+
+    // Removes the oldest messages until the messages fit in the budget, the last message and
+    // the system messages if they are kept are never removed. If it isn't enough, the start
+    // of the last message is removed.
+    fn trim_messages(
+        &self,
+        messages: Vec<LlmMessage>,
+        keep_system: bool,
+        budget: Option<usize>
+    ) -> Vec<LlmMessage> {
+        let budget = match budget {
+            Some(b) => b,
+            None => {
+                return messages;
+            }
+        };
+        if messages.is_empty() || self.count_messages(&messages) <= budget {
+            return messages;
+        }
+        let removable: Vec<usize> = (0..messages.len() - 1)
+            .filter(|&index| !keep_system || messages[index].role != "system")
+            .collect();
+        let without = |count: usize| -> Vec<LlmMessage> {
+            messages
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| !removable[..count].contains(index))
+                .map(|(_, m)| m.clone())
+                .collect()
+        };
+        // Fewest removed messages, each removed message lowers the count
+        let (mut low, mut high) = (0, removable.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.count_messages(&without(middle)) <= budget {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        let mut trimmed = without(low);
+        let last = trimmed.len() - 1;
+        if low == removable.len() && !(keep_system && trimmed[last].role == "system") {
+            self.truncate_start(&mut trimmed, last, budget);
+        }
+        trimmed
+    }
+
+    // Removes the first tokens of the content of a message until the messages fit in the
+    // budget, the content is cut on a token boundary.
+    fn truncate_start(&self, messages: &mut Vec<LlmMessage>, index: usize, budget: usize) {
+        let excess = self.count_messages(messages).saturating_sub(budget);
+        if excess == 0 {
+            return;
+        }
+        let content = messages[index].content.clone();
+        let spans = self.tokenizer.encode_with_offsets(&content);
+        let cut = |count: usize| -> String {
+            spans
+                .iter()
+                .skip(count)
+                .find(|span| content.is_char_boundary(span.start))
+                .map(|span| content[span.start..].to_string())
+                .unwrap_or_default()
+        };
+        let (mut low, mut high) = (excess.min(spans.len()), spans.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            messages[index].content = cut(middle);
+            if self.count_messages(messages) <= budget {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        messages[index].content = cut(low);
+    }
+
+    // Fails when the prompt and the completion don't fit in the context window.
+    pub fn check_context(
+        &self,
+        prompt_tokens: usize,
+        max_tokens: Option<f32>,
+        context_window: Option<i32>
+    ) -> Result<(), LlmError> {
+        let context_window = match context_window {
+            Some(c) if c > 0 => c as usize,
+            _ => {
+                return Ok(());
+            }
+        };
+        let max_tokens = max_tokens.map(|m| m.max(0.0) as usize).unwrap_or(0);
+        if prompt_tokens + max_tokens > context_window {
+            let message = format!(
+                "Prompt of {} tokens and {} max tokens exceed the context window of {} tokens",
+                prompt_tokens,
+                max_tokens,
+                context_window
+            );
+            return Err(LlmError::new(&message, "context_length_exceeded"));
+        }
+        Ok(())
+    }
+
+    // Sets the token counts missing from the usage returned by the provider. The counts are
+    // exact only for the models whose tokenizer is known, the others are cl100k_base estimates.
+    pub fn fill_usage(
+        &self,
+        usage: Option<LlmUsage>,
+        prompt_tokens: usize,
+        completion: &str
+    ) -> LlmUsage {
+        let mut usage = usage.unwrap_or(LlmUsage::new());
+        if usage.prompt_tokens.unwrap_or(0) == 0 {
+            usage.prompt_tokens = Some(prompt_tokens as i32);
+        }
+        if usage.completion_tokens.unwrap_or(0) == 0 {
+            usage.completion_tokens = Some(self.count_text(completion) as i32);
+        }
+        if usage.total_tokens.unwrap_or(0) == 0 {
+            usage.total_tokens = Some(
+                usage.prompt_tokens.unwrap_or(0) + usage.completion_tokens.unwrap_or(0)
+            );
+        }
+        usage
+    }
+}
+
+fn anthropic_prompt(messages: &Vec<LlmMessage>) -> String {
+    let mut prompt = String::new();
+    for message in messages {
+        match message.role.as_str() {
+            "system" => {}
+            "assistant" => {
+                prompt.push_str("\n\nAssistant: ");
+            }
+            _ => {
+                prompt.push_str("\n\nHuman: ");
+            }
+        }
+        prompt.push_str(&message.content);
+    }
+    prompt.push_str("\n\nAssistant:");
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(policy: ContextWindowPolicy, keep_system: bool) -> Option<LlmCompletionOptions> {
+        Some(LlmCompletionOptions {
+            context_window_policy: Some(policy),
+            keep_system: Some(keep_system),
+            system: None,
+        })
+    }
+
+    fn conversation() -> Vec<LlmMessage> {
+        vec![
+            LlmMessage::new("system", "You are a helpful assistant."),
+            LlmMessage::new("user", "What is the capital of France?"),
+            LlmMessage::new("assistant", "The capital of France is Paris."),
+            LlmMessage::new("user", "And the capital of Italy?")
+        ]
+    }
+
+    fn counter() -> TokenCounter {
+        TokenCounter::new("gpt-3.5-turbo", ChatFormat::OpenAI).unwrap()
+    }
+
+    fn fit(
+        policy: ContextWindowPolicy,
+        keep_system: bool,
+        messages: Vec<LlmMessage>,
+        context_window: usize
+    ) -> Result<Vec<LlmMessage>, LlmError> {
+        counter().fit_messages(
+            messages,
+            &options(policy, keep_system),
+            None,
+            Some(context_window as i32)
+        )
+    }
+
+    fn contents(messages: &[LlmMessage]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_count_openai_messages() {
+        let counter = counter();
+        let messages = vec![LlmMessage::new("user", "Hello")];
+        // 3 per message, the role, the content and 3 for the reply
+        assert_eq!(counter.count_messages(&messages), 3 + 1 + 1 + 3);
+    }
+
+    #[test]
+    fn test_fit_none_and_stop() {
+        let messages = conversation();
+        let total = counter().count_messages(&messages);
+        let fitted = fit(ContextWindowPolicy::None, true, messages.clone(), 10).unwrap();
+        assert_eq!(contents(&fitted), contents(&messages));
+
+        let fitted = fit(ContextWindowPolicy::Stop, true, messages.clone(), total).unwrap();
+        assert_eq!(contents(&fitted), contents(&messages));
+        let error = fit(ContextWindowPolicy::Stop, true, messages.clone(), total - 1).unwrap_err();
+        assert_eq!(error.status, "context_length_exceeded");
+
+        // The completion tokens are part of the window
+        let error = counter()
+            .fit_messages(
+                messages,
+                &options(ContextWindowPolicy::Stop, true),
+                Some(1.0),
+                Some(total as i32)
+            )
+            .unwrap_err();
+        assert_eq!(error.status, "context_length_exceeded");
+    }
+
+    #[test]
+    fn test_fit_rolling() {
+        let messages = conversation();
+        let counter = counter();
+        let total = counter.count_messages(&messages);
+        let fitted = fit(ContextWindowPolicy::Rolling, true, messages.clone(), total).unwrap();
+        assert_eq!(fitted.len(), 4);
+
+        // The oldest messages are removed first, the system message is kept
+        let budget = counter.count_messages(&vec![messages[0].clone(), messages[3].clone()]);
+        let fitted = fit(ContextWindowPolicy::Rolling, true, messages.clone(), budget).unwrap();
+        assert_eq!(contents(&fitted), vec![&messages[0].content, &messages[3].content]);
+        let budget = counter.count_messages(&messages[2..].to_vec());
+        let fitted = fit(ContextWindowPolicy::Rolling, true, messages.clone(), budget).unwrap();
+        assert_eq!(contents(&fitted), vec![&messages[0].content, &messages[3].content]);
+
+        // Without keep_system the system message is the oldest one
+        let fitted = fit(ContextWindowPolicy::Rolling, false, messages.clone(), budget).unwrap();
+        assert_eq!(contents(&fitted), contents(&messages[2..]));
+    }
+
+    #[test]
+    fn test_fit_last() {
+        let mut messages = conversation();
+        messages.push(LlmMessage::new("assistant", "Rome."));
+        let fitted = fit(ContextWindowPolicy::Last, true, messages.clone(), 1000).unwrap();
+        assert_eq!(contents(&fitted), vec![&messages[0].content, &messages[3].content]);
+        let fitted = fit(ContextWindowPolicy::Last, false, messages.clone(), 1000).unwrap();
+        assert_eq!(contents(&fitted), vec![&messages[3].content]);
+    }
+
+    #[test]
+    fn test_fit_truncate_start() {
+        let counter = counter();
+        let long = (0..200).map(|i| format!("word{}", i)).collect::<Vec<_>>().join(" ");
+        let messages = vec![LlmMessage::new("system", "Be brief."), LlmMessage::new("user", &long)];
+        let budget = counter.count_messages(&messages) / 2;
+        for policy in [ContextWindowPolicy::Rolling, ContextWindowPolicy::Last] {
+            let fitted = fit(policy, true, messages.clone(), budget).unwrap();
+            assert_eq!(fitted.len(), 2);
+            assert_eq!(fitted[0].content, "Be brief.");
+            // The end of the message is kept, on a token boundary
+            assert!(long.ends_with(&fitted[1].content));
+            assert!(fitted[1].content.ends_with("word199"));
+            assert!(fitted[1].content.len() < long.len());
+            let count = counter.count_messages(&fitted);
+            assert!(count <= budget);
+            // Only the tokens needed are removed
+            let mut longer = fitted.clone();
+            let start = long.len() - fitted[1].content.len();
+            longer[1].content = long[long[..start].rfind("word").unwrap()..].to_string();
+            assert!(counter.count_messages(&longer) > budget);
+        }
+
+        // Without keep_system the system message is removed before truncating
+        let fitted = fit(ContextWindowPolicy::Rolling, false, messages.clone(), budget).unwrap();
+        assert_eq!(fitted.len(), 1);
+        assert!(long.ends_with(&fitted[0].content));
+    }
+
+    #[test]
+    fn test_fit_system_exceeding_budget() {
+        let system = "You are a helpful assistant. ".repeat(20);
+        let messages = vec![LlmMessage::new("system", &system), LlmMessage::new("user", "Hello")];
+        let budget = counter().count_messages(&vec![messages[0].clone()]) - 1;
+        for policy in [ContextWindowPolicy::Rolling, ContextWindowPolicy::Last] {
+            let error = fit(policy, true, messages.clone(), budget).unwrap_err();
+            assert_eq!(error.status, "context_length_exceeded");
+        }
+        // A last system message is never truncated
+        let error = fit(
+            ContextWindowPolicy::Rolling,
+            true,
+            vec![messages[0].clone()],
+            budget
+        ).unwrap_err();
+        assert_eq!(error.status, "context_length_exceeded");
+
+        let fitted = fit(ContextWindowPolicy::Rolling, false, messages.clone(), budget).unwrap();
+        assert_eq!(contents(&fitted), vec!["Hello"]);
+    }
+}
@@ -164,8 +164,11 @@ impl ModelStorage {
         let mut gguf = opla_core::gguf::GGUF::new(&model_path);
         match gguf.read(&model_path) {
             Ok(_) => {}
+            Err(opla_core::Error::NotFound(_)) => {
+                return Err(format!("Model file not found: {:?}", model_path));
+            }
             Err(err) => {
-                return Err(err);
+                return Err(format!("Invalid model file {:?}: {}", model_path, err));
             }
         }
 
@@ -178,14 +181,31 @@ impl ModelStorage {
         let mut gguf = GGUF::new(&model_path);
         match gguf.read(&model_path) {
             Ok(_) => {}
+            Err(opla_core::Error::NotFound(_)) => {
+                return Err(format!("Model file not found: {:?}", model_path));
+            }
             Err(err) => {
-                return Err(err);
+                return Err(format!("Invalid model file {:?}: {}", model_path, err));
             }
         }
 
         Ok(gguf)
     }
 
+    // Fill the model with the metadata of its GGUF file
+    pub fn update_model_from_file(&mut self, id_or_name: &str) -> Result<(), String> {
+        let gguf = self.get_model_file(id_or_name.to_string())?;
+        let mut model_entity = match self.get_model_entity(id_or_name) {
+            Some(model_entity) => model_entity,
+            None => {
+                return Err(format!("Model not found: {:?}", id_or_name));
+            }
+        };
+        model_entity.reference.set_from_gguf(&gguf);
+        self.update_model_entity(&model_entity);
+        Ok(())
+    }
+
     pub fn validate_model(&self, model: &Model) -> Result<(), String> {
         if model.id.is_none() {
             return Err("Model ID is required".to_string());
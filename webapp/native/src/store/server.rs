@@ -126,6 +126,13 @@ impl ServerConfiguration {
             .unwrap_or(default_value)
     }
 
+    pub fn get_parameter_bool(&self, key: &str, default_value: bool) -> bool {
+        self.parameters
+            .get(key)
+            .map(|s| s.to_bool(default_value))
+            .unwrap_or(default_value)
+    }
+
     pub fn contains_parameter(&self, key: &str) -> bool {
         self.parameters.contains_key(key)
     }
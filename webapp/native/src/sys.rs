@@ -81,6 +81,7 @@ impl Sys {
                 .with_cpu(sysinfo::CpuRefreshKind::everything())
         );
         self.infos.global_cpu_percentage = self.sys.global_cpu_usage() as f64;
+        self.infos.total_memory = self.sys.total_memory();
         self.infos.used_memory = self.sys.used_memory();
         self.infos.used_swap = self.sys.used_swap();
         self.infos.cpus = self.sys
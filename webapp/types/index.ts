@@ -392,6 +392,7 @@ export enum ServerStatus {
   ERROR = 'error',
   STDOUT = 'stdout',
   STDERR = 'stderr',
+  WARNING = 'warning',
 }
 
 export type Payload = {